@@ -1,6 +1,6 @@
 use super::*;
+use crate::encoding::*;
 use crate::transaction::Transaction;
-use bincode::serialize;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use merkle_cbt::merkle_tree::Merge;
@@ -9,11 +9,17 @@ use std::time::{SystemTime, UNIX_EPOCH, Duration};  // 合并所有 time 相关
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::fmt;  // 添加这行
+use failure::format_err;
 
-const TARGET_HEXS: usize = 5;
+#[cfg(not(test))]
+pub const TARGET_HEXS: usize = 5;
+/// tests mine with an easy target, a block takes a few dozen hashes
+#[cfg(test)]
+pub const TARGET_HEXS: usize = 1;
 /// Block keeps block headers
 #[derive(Serialize, Deserialize, Debug, Clone)] 
 pub struct Block {
+    pub version: i32,
     pub timestamp: u128,    // 添加 pub 使字段公开
     pub transactions: Vec<Transaction>,
     pub prev_block_hash: String,
@@ -22,6 +28,39 @@ pub struct Block {
     height: i32,
 }
 
+/// BlockHeader is a block without its transactions, all a pruned node keeps of old blocks
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockHeader {
+    pub version: i32,
+    pub prev_block_hash: String,
+    pub merkle_root: Vec<u8>,
+    pub timestamp: u128,
+    pub nonce: i32,
+    pub height: i32,
+    pub hash: String,
+}
+
+impl BlockHeader {
+    /// CheckProofOfWork recomputes the header hash and checks it against the stored hash and the target
+    pub fn check_proof_of_work(&self) -> Result<bool> {
+        let hash = hex::encode(sha256d(&encode(self)?));
+        Ok(hash == self.hash && hash[0..TARGET_HEXS].bytes().all(|c| c == b'0'))
+    }
+}
+
+impl Encodable for BlockHeader {
+    fn encode(&self, w: &mut Vec<u8>) -> Result<()> {
+        write_i32(w, self.version);
+        write_hash(w, &self.prev_block_hash)?;
+        w.extend_from_slice(&self.merkle_root);
+        write_i64(w, self.timestamp as i64);
+        write_i32(w, TARGET_HEXS as i32);
+        write_i32(w, self.nonce);
+        write_i32(w, self.height);
+        Ok(())
+    }
+}
+
 impl Block {
     pub fn get_hash(&self) -> String {
         self.hash.clone()
@@ -47,6 +86,7 @@ impl Block {
             .duration_since(SystemTime::UNIX_EPOCH)?
             .as_millis();
         let mut block = Block {
+            version: BLOCK_VERSION,
             timestamp,
             transactions,
             prev_block_hash,
@@ -54,6 +94,11 @@ impl Block {
             nonce: 0,
             height,
         };
+        // 见证承诺写进coinbase,coinbase自己的wtxid是全零,所以不会循环依赖
+        let witness_root = block.hash_witnesses()?;
+        if let Some(coinbase) = block.transactions.first_mut() {
+            coinbase.set_witness_commitment(&witness_root)?;
+        }
         block.run_proof_of_work()?;
         Ok(block)
     }
@@ -101,10 +146,7 @@ impl Block {
         }
         println!();
         
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        self.hash = hasher.result_str();
+        self.hash = self.compute_hash()?;
         
         println!("✅ 挖矿成功!");
         println!("   🎲 Nonce: {}", self.nonce);
@@ -113,35 +155,74 @@ impl Block {
         
         Ok(())
     }
+    /// hash_transactions returns the merkle root over the raw txids
     fn hash_transactions(&self) -> Result<Vec<u8>> {
         let mut transactions = Vec::new();
         for tx in &self.transactions {
-            transactions.push(tx.hash()?.as_bytes().to_owned());
+            transactions.push(hex::decode(tx.hash()?)?);
+        }
+        Ok(merkle_root(transactions))
+    }
+    /// hash_witnesses returns the merkle root over the raw wtxids
+    fn hash_witnesses(&self) -> Result<Vec<u8>> {
+        let mut witnesses = Vec::new();
+        for tx in &self.transactions {
+            witnesses.push(hex::decode(tx.witness_hash()?)?);
         }
-        let tree = CBMT::<Vec<u8>, MergeVu8>::build_merkle_tree(transactions);
+        Ok(merkle_root(witnesses))
+    }
 
-        Ok(tree.root())
+    /// CheckWitnessCommitment checks that the coinbase commits to the witnesses of the block
+    pub fn check_witness_commitment(&self) -> Result<bool> {
+        let coinbase = match self.transactions.first() {
+            Some(tx) if tx.is_coinbase() => tx,
+            _ => return Ok(false),
+        };
+        match coinbase.witness_commitment() {
+            Some(commitment) => Ok(commitment == &self.hash_witnesses()?[..]),
+            None => Ok(false),
+        }
+    }
+    /// Header returns the header of the block
+    pub fn header(&self) -> Result<BlockHeader> {
+        Ok(BlockHeader {
+            version: self.version,
+            prev_block_hash: self.prev_block_hash.clone(),
+            merkle_root: self.hash_transactions()?,
+            timestamp: self.timestamp,
+            nonce: self.nonce,
+            height: self.height,
+            hash: self.hash.clone(),
+        })
     }
+    /// prepare_hash_data returns the canonical header encoding, see the encoding module
     fn prepare_hash_data(&self) -> Result<Vec<u8>> {
-        let content = (
-            self.prev_block_hash.clone(),
-            self.hash_transactions()?,
-            self.timestamp,
-            TARGET_HEXS,
-            self.nonce,
-        );
-        let bytes = serialize(&content)?;
-        Ok(bytes)
+        encode(&self.header()?)
+    }
+
+    /// compute_hash returns the block hash over the header
+    fn compute_hash(&self) -> Result<String> {
+        Ok(hex::encode(sha256d(&self.prepare_hash_data()?)))
+    }
+
+    /// CheckProofOfWork recomputes the header hash and checks it against the stored hash and the target
+    pub fn check_proof_of_work(&self) -> Result<bool> {
+        Ok(self.compute_hash()? == self.hash && self.validate()?)
+    }
+
+    /// CheckMerkleRoots checks every txid against its transaction and the coinbase's witness commitment
+    pub fn check_merkle_roots(&self) -> Result<bool> {
+        for tx in &self.transactions {
+            if tx.id != tx.hash()? {
+                return Ok(false);
+            }
+        }
+        self.check_witness_commitment()
     }
 
     fn validate(&self) -> Result<bool> {
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        let mut vec1: Vec<u8> = Vec::new();
-        vec1.resize(TARGET_HEXS, '0' as u8);
-        Ok(&hasher.result_str()[0..TARGET_HEXS] == String::from_utf8(vec1)?)
-       
+        let hash = self.compute_hash()?;
+        Ok(hash[0..TARGET_HEXS].bytes().all(|c| c == b'0'))
     }
 
 
@@ -155,6 +236,71 @@ impl Block {
 
 
 
+impl Encodable for Block {
+    fn encode(&self, w: &mut Vec<u8>) -> Result<()> {
+        w.extend_from_slice(&self.prepare_hash_data()?);
+        write_varint(w, self.transactions.len() as u64);
+        for tx in &self.transactions {
+            tx.encode(w)?;
+        }
+        Ok(())
+    }
+}
+
+impl Decodable for BlockHeader {
+    fn decode(r: &mut Reader) -> Result<Self> {
+        let version = r.read_i32()?;
+        if version != BLOCK_VERSION {
+            return Err(format_err!("ERROR: Unknown block version {}", version));
+        }
+        let prev_block_hash = r.read_hash()?;
+        let merkle_root = r.read_slice(32)?.to_vec();
+        let timestamp = r.read_i64()? as u128;
+        let target = r.read_i32()?;
+        if target != TARGET_HEXS as i32 {
+            return Err(format_err!("ERROR: Unexpected block target {}", target));
+        }
+        let mut header = BlockHeader {
+            version,
+            prev_block_hash,
+            merkle_root,
+            timestamp,
+            nonce: r.read_i32()?,
+            height: r.read_i32()?,
+            hash: String::new(),
+        };
+        header.hash = hex::encode(sha256d(&encode(&header)?));
+        Ok(header)
+    }
+}
+
+impl Decodable for Block {
+    fn decode(r: &mut Reader) -> Result<Self> {
+        let header = BlockHeader::decode(r)?;
+        let mut transactions = Vec::new();
+        for _ in 0..r.read_varint()? {
+            transactions.push(Transaction::decode(r)?);
+        }
+
+        let block = Block {
+            version: header.version,
+            timestamp: header.timestamp,
+            transactions,
+            prev_block_hash: header.prev_block_hash,
+            hash: header.hash,
+            nonce: header.nonce,
+            height: header.height,
+        };
+        if block.hash_transactions()? != header.merkle_root {
+            return Err(format_err!("ERROR: Block merkle root mismatch"));
+        }
+        if !block.check_witness_commitment()? {
+            return Err(format_err!("ERROR: Block witness commitment mismatch"));
+        }
+        Ok(block)
+    }
+}
+
 impl fmt::Display for Block {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "╭─────────────────────────────────────────────────────────────╮")?;
@@ -197,6 +343,11 @@ impl fmt::Display for Block {
 }
 struct MergeVu8 {}
 
+/// MerkleRoot returns the root of the complete binary merkle tree over leaves, see the encoding module
+pub fn merkle_root(leaves: Vec<Vec<u8>>) -> Vec<u8> {
+    CBMT::<Vec<u8>, MergeVu8>::build_merkle_tree(leaves).root()
+}
+
 impl Merge for MergeVu8 {
     type Item = Vec<u8>;
     fn merge(left: &Self::Item, right: &Self::Item) -> Self::Item {
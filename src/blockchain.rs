@@ -1,32 +1,76 @@
 use super::*;
 use crate::block::*;
+use crate::chainparams::*;
+use crate::encoding::TX_VERSION;
+use crate::keys::*;
+use crate::schema::*;
+use crate::sigcheck::*;
+use crate::storage::*;
 use crate::transaction::*;
+use crate::utxoset::*;
 use failure::format_err;
 use bincode::{deserialize, serialize};
-use sled;
-use std::collections::HashMap;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 
 
 const GENESIS_COINBASE_DATA: &str =
     "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
 
+/// Key of the hash of the best block in the metadata tree
+pub const LAST_KEY: &str = "LAST";
+/// Key of the height of the best block in the metadata tree
+pub const HEIGHT_KEY: &str = "HEIGHT";
+/// Key of the lowest height whose block is still stored in full, in the metadata tree
+pub const PRUNE_HEIGHT_KEY: &str = "PRUNE_HEIGHT";
+/// Smallest prune depth, blocks this close to the tip may still be reorganized away
+pub const MIN_PRUNE_DEPTH: u32 = 10;
+/// Number of blocks a reindex handles per batch
+pub const REINDEX_BATCH: usize = 1000;
+/// Mark of a block invalidated with invalidateblock, in the invalid tree
+pub const INVALID_BLOCK: u8 = 1;
+/// Mark of a block that descends from an invalidated block, in the invalid tree
+pub const INVALID_CHILD: u8 = 2;
+
+/// TxLocation is where the txid index finds a transaction
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TxLocation {
+    pub block_hash: String,
+    pub position: u32,
+}
+
 /// Blockchain keeps a sequence of Blocks
 #[derive(Debug)]
-pub struct Blockchain {
+pub struct Blockchain<S: ChainStore = Storage> {
     pub tip: String,
-    pub db: sled::Db,
+    /// height of the tip, -1 for an empty chain
+    height: i32,
+    /// blocks buried deeper than this are pruned, None keeps every block
+    prune_depth: Option<u32>,
+    /// checkpoints and the assumed valid block new blocks are checked against
+    params: ChainParams,
+    /// hashes of the blocks the assumed valid block builds on, learned from a headers chain
+    assumed_valid_chain: HashSet<String>,
+    pub store: S,
+}
+
+/// height_key is the key of a height in the height index, big-endian so keys sort by height
+pub fn height_key(height: i32) -> [u8; 4] {
+    (height as u32).to_be_bytes()
 }
-pub struct BlockchainIterator<'a> {
+pub struct BlockchainIterator<'a, S: ChainStore> {
     current_hash: String,
-    bc: &'a Blockchain,
+    bc: &'a Blockchain<S>,
 }
-impl Blockchain {
-    /// NewBlockchain creates a new Blockchain with genesis Block
-    pub fn new() -> Result<Blockchain> {
+impl<S: ChainStore> Blockchain<S> {
+    /// NewBlockchain opens the Blockchain kept in store
+    ///
+    /// Data of an older schema version is upgraded first, data of a newer one is an error.
+    pub fn new(store: &S) -> Result<Blockchain<S>> {
         info!("open blockchain");
 
-        let db = sled::open("data/blocks")?;
-        let hash = match db.get("LAST")? {
+        let hash = match store.get(Tree::Metadata, LAST_KEY.as_bytes())? {
             Some(l) => l.to_vec(),
             None => Vec::new(),
         };
@@ -36,182 +80,859 @@ impl Blockchain {
         } else {
             String::from_utf8(hash.to_vec())?
         };
-        Ok(Blockchain { tip: lasthash, db })
+        let mut bc = Blockchain {
+            tip: lasthash,
+            height: -1,
+            prune_depth: None,
+            params: ChainParams::default(),
+            assumed_valid_chain: HashSet::new(),
+            store: store.clone(),
+        };
+        upgrade_chain(&bc)?;
+        bc.height = match store.get(Tree::Metadata, HEIGHT_KEY.as_bytes())? {
+            Some(h) => deserialize(&h)?,
+            None if bc.tip.is_empty() => -1,
+            None => bc.get_block(&bc.tip)?.get_height(),
+        };
+        // 建索引之前创建的链没有txid索引,裁剪过的链一定有
+        if !bc.tip.is_empty()
+            && bc.get_prune_height()? == 0
+            && store.scan_prefix(Tree::TxIndex, &[]).next().is_none()
+        {
+            warn!("the txid index is missing, rebuilding it");
+            bc.reindex_transactions()?;
+        }
+        Ok(bc)
     }
-    /// CreateBlockchain creates a new blockchain DB
-    pub fn create_blockchain(address: String) -> Result<Blockchain> {
+    /// CreateBlockchain replaces the chain in store with a new one holding only the genesis Block
+    ///
+    /// The UTXO set is cleared as well and has to be reindexed.
+    pub fn create_blockchain(address: String, store: &S) -> Result<Blockchain<S>> {
         info!("Creating new blockchain");
 
-        std::fs::remove_dir_all("data/blocks").ok();
-        let db = sled::open("data/blocks")?;
+        for tree in Tree::ALL {
+            store.clear(tree)?;
+        }
+        let mut batch = Batch::new();
+        set_chain_schema_version(&mut batch, CHAIN_SCHEMA_VERSION)?;
+        store.commit(batch)?;
         debug!("Creating new block database");
         let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
         let genesis: Block = Block::new_genesis_block(cbtx);
-        db.insert(genesis.get_hash(), serialize(&genesis)?)?;
-        db.insert("LAST", genesis.get_hash().as_bytes())?;
-        let bc = Blockchain {
-            tip: genesis.get_hash(),
-            db,
+        let mut bc = Blockchain {
+            tip: String::new(),
+            height: -1,
+            prune_depth: None,
+            params: ChainParams::default(),
+            assumed_valid_chain: HashSet::new(),
+            store: store.clone(),
         };
-        bc.db.flush()?;
+        bc.add_block(genesis)?;
         Ok(bc)
     }
-    /// MineBlock mines a new block with the provided transactions
-    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
+    /// PrepareBlock verifies transactions and mines a new block on top of the tip
+    ///
+    /// The block is not saved; UTXOSet::mine_block saves it together with the UTXO changes.
+    pub fn prepare_block(&self, transactions: Vec<Transaction>) -> Result<Block> {
         info!("mine a new block");
 
-        for tx in &transactions {
-            if !self.verify_transacton(tx)? {
-                return Err(format_err!("ERROR: Invalid transaction"));
-            }
+        if !self.verify_transactions(&transactions)? {
+            return Err(format_err!("ERROR: Invalid transaction"));
         }
 
-        let lasthash = self.db.get("LAST")?.unwrap();
-
-        let newblock = Block::new_block(
+        Block::new_block(
             transactions,
-            String::from_utf8(lasthash.to_vec())?,
+            self.tip.clone(),
             self.get_best_height()? + 1,
-        )?;
-        self.db.insert(newblock.get_hash(), serialize(&newblock)?)?;
-        self.db.insert("LAST", newblock.get_hash().as_bytes())?;
-        self.db.flush()?;
-
-        self.tip = newblock.get_hash();
-        Ok(newblock)
+        )
     }
     /// 定义这个类的迭代器,这个迭代器里面会方
-    pub fn iter(&self) -> BlockchainIterator {
+    pub fn iter(&self) -> BlockchainIterator<'_, S> {
         BlockchainIterator {
             current_hash: self.tip.clone(),
             bc: &self,
         }
     }
     /// FindUTXO finds and returns all unspent transaction outputs
-    pub fn find_UTXO(&self) -> HashMap<String, TXOutputs> {
-        let mut utxos: HashMap<String, TXOutputs> = HashMap::new();
-        let mut spend_txos: HashMap<String, Vec<i32>> = HashMap::new();
+    pub fn find_UTXO(&self) -> Result<HashMap<OutPoint, Coin>> {
+        let mut utxos: HashMap<OutPoint, Coin> = HashMap::new();
+        let mut spend_txos: HashSet<OutPoint> = HashSet::new();
 
+        // 从tip往回走,区块内也倒序,这样花费记录总是先于被花费的输出
         for block in self.iter() {
-            for tx in block.get_transaction() {
-                for index in 0..tx.vout.len() {
-                    if let Some(ids) = spend_txos.get(&tx.id) {
-                        if ids.contains(&(index as i32)) {
-                            continue;
-                        }
-                    }
-
-                    match utxos.get_mut(&tx.id) {
-                        Some(v) => {
-                            v.outputs.push(tx.vout[index].clone());
-                        }
-                        None => {
-                            utxos.insert(
-                                tx.id.clone(),
-                                TXOutputs {
-                                    outputs: vec![tx.vout[index].clone()],
-                                },
-                            );
-                        }
+            let block = block?;
+            for tx in block.get_transaction().iter().rev() {
+                for (index, out) in tx.vout.iter().enumerate() {
+                    let outpoint = OutPoint::new(&tx.id, index as i32);
+                    if spend_txos.contains(&outpoint) {
+                        continue;
                     }
+                    utxos.insert(
+                        outpoint,
+                        Coin {
+                            output: out.clone(),
+                            height: block.get_height(),
+                            is_coinbase: tx.is_coinbase(),
+                        },
+                    );
                 }
 
                 if !tx.is_coinbase() {
                     for i in &tx.vin {
-                        match spend_txos.get_mut(&i.txid) {
-                            Some(v) => {
-                                v.push(i.vout);
-                            }
-                            None => {
-                                spend_txos.insert(i.txid.clone(), vec![i.vout]);
-                            }
-                        }
+                        spend_txos.insert(OutPoint::new(&i.txid, i.vout));
                     }
                 }
             }
         }
 
-        utxos
+        Ok(utxos)
     }
-    /// FindTransaction finds a transaction by its ID
+    /// FindTransaction finds a transaction of the best chain by its ID
     pub fn find_transacton(&self, id: &str) -> Result<Transaction> {
-        for b in self.iter() {
-            for tx in b.get_transaction() {
-                if tx.id == id {
-                    return Ok(tx.clone());
+        match self.get_transaction_location(id)? {
+            Some(loc) => {
+                let block = self.get_block(&loc.block_hash)?;
+                match block.get_transaction().get(loc.position as usize) {
+                    Some(tx) if tx.id == id => Ok(tx.clone()),
+                    _ => Err(format_err!("ERROR: Transaction index is corrupted at {}", id)),
+                }
+            }
+            None => Err(format_err!("Transaction is not found")),
+        }
+    }
+
+    /// GetTransactionLocation looks a txid up in the txid index
+    pub fn get_transaction_location(&self, id: &str) -> Result<Option<TxLocation>> {
+        let key = match hex::decode(id) {
+            Ok(key) => key,
+            Err(_) => return Ok(None),
+        };
+        match self.store.get(Tree::TxIndex, &key)? {
+            Some(v) => Ok(Some(deserialize(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// GetBlockHash returns the hash of the best chain's block at height
+    pub fn get_block_hash(&self, height: i32) -> Result<Option<String>> {
+        if height < 0 {
+            return Ok(None);
+        }
+        match self.store.get(Tree::Heights, &height_key(height))? {
+            Some(h) => Ok(Some(String::from_utf8(h)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// IndexBestChain adds to batch the writes making block the new tip
+    ///
+    /// It walks back from block and rewrites the height and txid indexes until it
+    /// meets a block that is already indexed at its height, so extending the tip
+    /// costs one block and a reorganization costs the blocks that changed.
+    pub fn index_best_chain(&self, block: &Block, batch: &mut Batch) -> Result<()> {
+        let mut added: HashMap<Vec<u8>, TxLocation> = HashMap::new();
+        let mut removed: HashSet<Vec<u8>> = HashSet::new();
+        let mut current = block.clone();
+        loop {
+            let hash = current.get_hash();
+            let key = height_key(current.get_height());
+            let old = self.store.get(Tree::Heights, &key)?;
+            if old.as_deref() == Some(hash.as_bytes()) {
+                break;
+            }
+            // 被换掉的旧区块里的交易要从txid索引里删掉
+            if let Some(old) = old {
+                let old_block = self.get_block(&String::from_utf8(old)?)?;
+                for tx in old_block.get_transaction() {
+                    removed.insert(hex::decode(&tx.id)?);
                 }
             }
+            batch.insert(Tree::Heights, key, &hash);
+            for (pos, tx) in current.get_transaction().iter().enumerate() {
+                added.entry(hex::decode(&tx.id)?).or_insert(TxLocation {
+                    block_hash: hash.clone(),
+                    position: pos as u32,
+                });
+            }
+
+            let prev_hash = current.get_prev_hash();
+            if prev_hash.is_empty() || !self.has_block(&prev_hash)? {
+                break;
+            }
+            // 父区块已经在索引里就不用再读它,它可能已经被裁剪了
+            if self.get_block_hash(current.get_height() - 1)?.as_deref() == Some(prev_hash.as_str()) {
+                break;
+            }
+            current = self.get_block(&prev_hash)?;
+        }
+
+        for key in removed {
+            if !added.contains_key(&key) {
+                batch.remove(Tree::TxIndex, key);
+            }
         }
-        Err(format_err!("Transaction is not found"))
+        for (key, loc) in added {
+            batch.insert(Tree::TxIndex, key, serialize(&loc)?);
+        }
+        batch.insert(Tree::Metadata, LAST_KEY, block.get_hash());
+        batch.insert(Tree::Metadata, HEIGHT_KEY, serialize(&block.get_height())?);
+        Ok(())
+    }
+
+    /// SetTip records block as the tip once index_best_chain's batch is committed
+    pub fn set_tip(&mut self, block: &Block) {
+        self.tip = block.get_hash();
+        self.height = block.get_height();
     }
-    fn get_prev_TXs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
+
+    /// ReindexHeights rebuilds the height index walking back from the tip and clears the txid index
+    ///
+    /// Heights are written in batches; the txid index is refilled block by block
+    /// by UTXOSet::reindex with index_transactions.
+    pub fn reindex_heights(&self) -> Result<()> {
+        self.store.clear(Tree::Heights)?;
+        self.store.clear(Tree::TxIndex)?;
+        let mut batch = Batch::new();
+        let mut hash = self.tip.clone();
+        let mut count = 0;
+        while !hash.is_empty() {
+            let block = self.get_block(&hash)?;
+            batch.insert(Tree::Heights, height_key(block.get_height()), &hash);
+            count += 1;
+            if count % REINDEX_BATCH == 0 {
+                self.store.commit(std::mem::take(&mut batch))?;
+            }
+            hash = block.get_prev_hash();
+        }
+        self.store.commit(batch)?;
+        self.store.flush()
+    }
+
+    /// ReindexTransactions rebuilds the txid index from the best chain's blocks in height order
+    pub fn reindex_transactions(&self) -> Result<()> {
+        let mut batch = Batch::new();
+        for height in 0..=self.height {
+            let hash = match self.get_block_hash(height)? {
+                Some(hash) => hash,
+                None => return Err(format_err!("ERROR: No block at height {} to index", height)),
+            };
+            self.index_transactions(&self.get_block(&hash)?, &mut batch)?;
+            if ((height + 1) as usize).is_multiple_of(REINDEX_BATCH) {
+                self.store.commit(std::mem::take(&mut batch))?;
+            }
+        }
+        self.store.commit(batch)?;
+        self.store.flush()
+    }
+
+    /// IndexTransactions adds to batch the txid index entries of a best chain block
+    pub fn index_transactions(&self, block: &Block, batch: &mut Batch) -> Result<()> {
+        for (pos, tx) in block.get_transaction().iter().enumerate() {
+            let loc = TxLocation {
+                block_hash: block.get_hash(),
+                position: pos as u32,
+            };
+            batch.insert(Tree::TxIndex, hex::decode(&tx.id)?, serialize(&loc)?);
+        }
+        Ok(())
+    }
+
+    /// GetPrevTXs looks up the transactions spent by tx, preferring unconfirmed ones in `pending`
+    pub fn get_prev_TXs(
+        &self,
+        tx: &Transaction,
+        pending: &HashMap<String, Transaction>,
+    ) -> Result<HashMap<String, Transaction>> {
+        self.get_prev_TXs_with(tx, pending, &HashMap::new())
+    }
+
+    /// get_prev_TXs_with is get_prev_TXs that also knows the already `spent` coins
+    ///
+    /// A transaction of a pruned block is rebuilt from the coins tx spends, looked
+    /// up in `spent` and then in the UTXO set; the other outputs are left empty.
+    fn get_prev_TXs_with(
+        &self,
+        tx: &Transaction,
+        pending: &HashMap<String, Transaction>,
+        spent: &HashMap<OutPoint, Coin>,
+    ) -> Result<HashMap<String, Transaction>> {
         let mut prev_TXs = HashMap::new();
         for vin in &tx.vin {
-            let prev_TX = self.find_transacton(&vin.txid)?;
+            if prev_TXs.contains_key(&vin.txid) {
+                continue;
+            }
+            let prev_TX = match pending.get(&vin.txid) {
+                Some(prev_TX) => prev_TX.clone(),
+                None => match self.get_transaction_location(&vin.txid)? {
+                    Some(loc) if !self.is_pruned(&loc.block_hash)? => {
+                        self.find_transacton(&vin.txid)?
+                    }
+                    // 被裁剪的区块或者UTXO快照之前的交易,只能从币里还原
+                    Some(_) => self.coins_TX(tx, &vin.txid, spent)?,
+                    None if self.get_prune_height()? > 0 => self.coins_TX(tx, &vin.txid, spent)?,
+                    None => self.find_transacton(&vin.txid)?,
+                },
+            };
             prev_TXs.insert(prev_TX.id.clone(), prev_TX);
         }
         Ok(prev_TXs)
     }
+
+    /// coins_TX rebuilds the outputs tx spends from the pruned transaction txid
+    fn coins_TX(
+        &self,
+        tx: &Transaction,
+        txid: &str,
+        spent: &HashMap<OutPoint, Coin>,
+    ) -> Result<Transaction> {
+        let mut vout = Vec::new();
+        for vin in tx.vin.iter().filter(|vin| vin.txid == txid && vin.vout >= 0) {
+            let outpoint = OutPoint::new(txid, vin.vout);
+            let coin: Coin = match spent.get(&outpoint) {
+                Some(coin) => coin.clone(),
+                None => match self.store.get(Tree::Utxos, &outpoint.to_key()?)? {
+                    Some(v) => deserialize(&v)?,
+                    None => {
+                        return Err(format_err!(
+                            "ERROR: Transaction {} is pruned and output {} is not unspent",
+                            txid,
+                            vin.vout
+                        ));
+                    }
+                },
+            };
+            let index = vin.vout as usize;
+            if vout.len() <= index {
+                vout.resize(
+                    index + 1,
+                    TXOutput {
+                        value: 0,
+                        pub_key_hash: Vec::new(),
+                    },
+                );
+            }
+            vout[index] = coin.output;
+        }
+        Ok(Transaction {
+            version: TX_VERSION,
+            id: txid.to_string(),
+            vin: Vec::new(),
+            vout,
+            witness: Vec::new(),
+        })
+    }
     /// SignTransaction signs inputs of a Transaction
-    pub fn sign_transacton(&self, tx: &mut Transaction, private_key: &[u8]) -> Result<()> {
-        let prev_TXs = self.get_prev_TXs(tx)?;
-        tx.sign(private_key, prev_TXs)?;
+    pub fn sign_transacton(&self, tx: &mut Transaction, signer: &dyn Signer) -> Result<()> {
+        let prev_TXs = self.get_prev_TXs(tx, &HashMap::new())?;
+        tx.sign(signer, prev_TXs)?;
         Ok(())
     }
 
-    /// VerifyTransaction verifies transaction input signatures
-    pub fn verify_transacton(&self, tx: & Transaction) -> Result<bool> {
+    /// VerifyTransactionWith verifies tx, allowing its inputs to spend the unconfirmed `pending` transactions
+    pub fn verify_transacton_with(
+        &self,
+        tx: &Transaction,
+        pending: &HashMap<String, Transaction>,
+    ) -> Result<bool> {
         if tx.is_coinbase() {
             return Ok(true);
         }
-        let prev_TXs = self.get_prev_TXs(tx)?;
+        let prev_TXs = self.get_prev_TXs(tx, pending)?;
         tx.verify(prev_TXs)
     }
 
-    /// AddBlock saves the block into the blockchain
-    pub fn add_block(&mut self, block: Block) -> Result<()> {
-        let data = serialize(&block)?;
-        if let Some(_) = self.db.get(block.get_hash())? {
+    /// VerifyTransactions verifies the input signatures of a block's transactions in parallel
+    ///
+    /// A transaction may spend the outputs of transactions listed before it.
+    pub fn verify_transactions(&self, transactions: &[Transaction]) -> Result<bool> {
+        self.verify_transactions_with(transactions, &HashMap::new())
+    }
+
+    /// VerifyTransactionsWith verifies a stored block's transactions given the coins it `spent`
+    ///
+    /// Its inputs may be gone from the UTXO set, the block's undo data has them.
+    pub fn verify_transactions_with(
+        &self,
+        transactions: &[Transaction],
+        spent: &HashMap<OutPoint, Coin>,
+    ) -> Result<bool> {
+        let positions: HashMap<&str, usize> = transactions
+            .iter()
+            .enumerate()
+            .map(|(i, tx)| (tx.id.as_str(), i))
+            .collect();
+
+        let checks = transactions
+            .par_iter()
+            .enumerate()
+            .filter(|(_, tx)| !tx.is_coinbase())
+            .map(|(i, tx)| {
+                // 同一个区块里,后面的交易可以花费前面交易的输出
+                let mut pending = HashMap::new();
+                for vin in &tx.vin {
+                    if let Some(&pos) = positions.get(vin.txid.as_str())
+                        && pos < i
+                    {
+                        pending.insert(vin.txid.clone(), transactions[pos].clone());
+                    }
+                }
+                let prev_TXs = self.get_prev_TXs_with(tx, &pending, spent)?;
+                tx.signature_checks(&prev_TXs)
+            })
+            .collect::<Result<Vec<Vec<SigCheck>>>>()?;
+
+        Ok(verify_batch(&checks.concat()))
+    }
+
+    /// CheckBlock validates the proof of work, the witness commitment and the transactions of a block
+    pub fn check_block(&self, block: &Block) -> Result<()> {
+        if !block.check_proof_of_work()? {
+            return Err(format_err!("ERROR: Block {} has a bad proof of work", block.get_hash()));
+        }
+        // 默克尔根按重新算出的txid计算,存下的id也要核对,币、撤销数据和索引都按它记
+        if !block.check_merkle_roots()? {
+            return Err(format_err!(
+                "ERROR: Block {} has a bad transaction id or witness commitment",
+                block.get_hash()
+            ));
+        }
+        // 父区块不在就没法查前序交易,孤块直接拒绝,同步时按从老到新的顺序要区块
+        let prev_hash = block.get_prev_hash();
+        if !prev_hash.is_empty() && !self.has_block(&prev_hash)? {
+            return Err(format_err!(
+                "ERROR: Parent {} of block {} is unknown",
+                prev_hash,
+                block.get_hash()
+            ));
+        }
+        self.check_checkpoints(block)?;
+        if self.is_assumed_valid(block)? {
+            debug!("block {} is an ancestor of the assumed valid block, skip signature checks", block.get_hash());
+        } else if !self.verify_transactions(block.get_transaction())? {
+            return Err(format_err!("ERROR: Block {} has invalid transactions", block.get_hash()));
+        }
+        Ok(())
+    }
+
+    /// is_assumed_valid tells whether block is an ancestor of the assumed valid block
+    ///
+    /// The ancestors are known from a headers chain holding the assumed valid
+    /// block, or by walking back from it once it is stored. A block that is
+    /// only below its height is checked in full.
+    fn is_assumed_valid(&self, block: &Block) -> Result<bool> {
+        let av = match &self.params.assume_valid {
+            Some(av) if block.get_height() <= av.height => av,
+            _ => return Ok(false),
+        };
+        let hash = block.get_hash();
+        if self.assumed_valid_chain.contains(&hash) {
+            return Ok(true);
+        }
+        if !self.has_block(&av.block_hash)? {
+            return Ok(false);
+        }
+        let mut current = av.block_hash.clone();
+        for _ in block.get_height()..av.height {
+            current = self.get_header(&current)?.prev_block_hash;
+        }
+        Ok(current == hash)
+    }
+
+    /// AddHeadersChain remembers the ancestors of the assumed valid block in headers
+    ///
+    /// headers must be linked by prev_block_hash with a valid proof of work,
+    /// oldest first, as the server checks them.
+    pub fn add_headers_chain(&mut self, headers: &[BlockHeader]) {
+        let av = match &self.params.assume_valid {
+            Some(av) => av,
+            None => return,
+        };
+        if let Some(pos) = headers
+            .iter()
+            .position(|h| h.hash == av.block_hash && h.height == av.height)
+        {
+            self.assumed_valid_chain
+                .extend(headers[..=pos].iter().map(|h| h.hash.clone()));
+        }
+    }
+
+    /// check_checkpoints rejects a block that contradicts a checkpoint or forks the chain below the last one passed
+    fn check_checkpoints(&self, block: &Block) -> Result<()> {
+        let hash = block.get_hash();
+        if let Some(expected) = self.params.checkpoint_at(block.get_height())
+            && expected != hash
+        {
+            return Err(format_err!(
+                "ERROR: Block {} at height {} does not match the checkpoint {}",
+                hash,
+                block.get_height(),
+                expected
+            ));
+        }
+        if let Some(checkpoint) = self.last_checkpoint()?
+            && let Some(fork) = self.fork_height(block)?
+            && fork < checkpoint
+        {
+            return Err(format_err!(
+                "ERROR: Block {} forks the chain at height {}, below the checkpoint at height {}",
+                hash,
+                fork,
+                checkpoint
+            ));
+        }
+        Ok(())
+    }
+
+    /// last_checkpoint returns the height of the highest checkpoint the best chain holds
+    fn last_checkpoint(&self) -> Result<Option<i32>> {
+        let mut last = None;
+        for checkpoint in self.params.all_checkpoints() {
+            if checkpoint.height <= self.height
+                && last.is_none_or(|h| checkpoint.height > h)
+                && self.get_block_hash(checkpoint.height)?.as_deref()
+                    == Some(checkpoint.block_hash.as_str())
+            {
+                last = Some(checkpoint.height);
+            }
+        }
+        Ok(last)
+    }
+
+    /// fork_height returns the height of the last best chain block the block builds on
+    ///
+    /// -1 means a different genesis block, None that an ancestor is missing.
+    fn fork_height(&self, block: &Block) -> Result<Option<i32>> {
+        let mut hash = block.get_prev_hash();
+        let mut height = block.get_height() - 1;
+        while !hash.is_empty() {
+            if self.get_block_hash(height)?.as_deref() == Some(hash.as_str()) {
+                return Ok(Some(height));
+            }
+            if !self.has_block(&hash)? {
+                return Ok(None);
+            }
+            hash = self.get_header(&hash)?.prev_block_hash;
+            height -= 1;
+        }
+        Ok(Some(-1))
+    }
+
+    /// HasBlock reports whether the block is stored, in full or pruned to its header
+    pub fn has_block(&self, block_hash: &str) -> Result<bool> {
+        Ok(self.store.contains(Tree::Blocks, block_hash.as_bytes())?
+            || self.store.contains(Tree::Headers, block_hash.as_bytes())?)
+    }
+
+    /// IsPruned reports whether only the header of the block is left
+    pub fn is_pruned(&self, block_hash: &str) -> Result<bool> {
+        Ok(!self.store.contains(Tree::Blocks, block_hash.as_bytes())?
+            && self.store.contains(Tree::Headers, block_hash.as_bytes())?)
+    }
+
+    /// GetPruneHeight returns the lowest height whose block is still stored in full
+    pub fn get_prune_height(&self) -> Result<i32> {
+        match self.store.get(Tree::Metadata, PRUNE_HEIGHT_KEY.as_bytes())? {
+            Some(h) => Ok(deserialize(&h)?),
+            None => Ok(0),
+        }
+    }
+
+    /// SetPruneDepth turns pruning on, blocks buried deeper than depth lose their transactions
+    pub fn set_prune_depth(&mut self, depth: u32) -> Result<()> {
+        if depth < MIN_PRUNE_DEPTH {
+            return Err(format_err!(
+                "ERROR: Prune depth {} is below the minimum of {}",
+                depth,
+                MIN_PRUNE_DEPTH
+            ));
+        }
+        self.prune_depth = Some(depth);
+        Ok(())
+    }
+
+    /// SetChainParams sets the checkpoints and the assumed valid block new blocks are checked against
+    pub fn set_chain_params(&mut self, params: ChainParams) {
+        self.params = params;
+    }
+
+    /// StartFromSnapshot makes the block of a loaded UTXO snapshot the tip of an empty chain
+    ///
+    /// The block is stored as a pruned header together with the writes already
+    /// in batch, so the blocks below it are never looked for.
+    pub fn start_from_snapshot(&mut self, header: &BlockHeader, mut batch: Batch) -> Result<()> {
+        if !self.tip.is_empty() {
+            return Err(format_err!("ERROR: The chain is not empty"));
+        }
+        batch.insert(Tree::Headers, &header.hash, serialize(header)?);
+        batch.insert(Tree::Heights, height_key(header.height), &header.hash);
+        batch.insert(Tree::Metadata, LAST_KEY, &header.hash);
+        batch.insert(Tree::Metadata, HEIGHT_KEY, serialize(&header.height)?);
+        batch.insert(Tree::Metadata, PRUNE_HEIGHT_KEY, serialize(&(header.height + 1))?);
+        self.store.commit(batch)?;
+        self.store.flush()?;
+        self.tip = header.hash.clone();
+        self.height = header.height;
+        Ok(())
+    }
+
+    /// Prune drops the transactions of the best chain's blocks buried deeper than the prune depth
+    ///
+    /// Their headers move to the headers tree and their undo data is kept. The
+    /// caller makes sure the UTXO set is at the tip, it won't need those blocks again.
+    pub fn prune(&self) -> Result<()> {
+        let depth = match self.prune_depth {
+            Some(depth) => depth as i32,
+            None => return Ok(()),
+        };
+        let start = self.get_prune_height()?;
+        let end = self.height - depth;
+        if end <= start {
             return Ok(());
         }
-        self.db.insert(block.get_hash(), data)?;
 
-        let lastheight = self.get_best_height()?;
-        if block.get_height() > lastheight {
-            self.db.insert("LAST", block.get_hash().as_bytes())?;
-            self.tip = block.get_hash();
-            self.db.flush()?;
+        let mut batch = Batch::new();
+        for height in start..end {
+            let hash = match self.get_block_hash(height)? {
+                Some(hash) => hash,
+                None => return Err(format_err!("ERROR: No block at height {} to prune", height)),
+            };
+            let block = self.get_block(&hash)?;
+            batch.insert(Tree::Headers, &hash, serialize(&block.header()?)?);
+            batch.remove(Tree::Blocks, &hash);
         }
+        batch.insert(Tree::Metadata, PRUNE_HEIGHT_KEY, serialize(&end)?);
+        self.store.commit(batch)?;
+        self.store.flush()?;
+        info!("pruned blocks below height {}", end);
         Ok(())
     }
 
+    /// AddBlock saves the block into the blockchain and makes it the tip if it is higher
+    ///
+    /// The UTXO set is not touched, see UTXOSet::add_block.
+    pub fn add_block(&mut self, block: Block) -> Result<()> {
+        if self.store_block(&block)? {
+            self.set_best_chain(&block)?;
+        }
+        Ok(())
+    }
+
+    /// StoreBlock checks and saves a block without moving the tip
+    ///
+    /// It returns whether the block is valid so far and higher than the tip,
+    /// that is whether it should become the new tip.
+    pub fn store_block(&mut self, block: &Block) -> Result<bool> {
+        if self.has_block(&block.get_hash())? {
+            return Ok(false);
+        }
+        self.check_block(block)?;
+        let hash = block.get_hash();
+        let invalid_parent = self.is_invalid(&block.get_prev_hash())?;
+
+        let mut batch = Batch::new();
+        batch.insert(Tree::Blocks, &hash, serialize(block)?);
+        if invalid_parent {
+            warn!("block {} builds on an invalid block, not following it", hash);
+            batch.insert(Tree::Invalid, &hash, [INVALID_CHILD]);
+        }
+        self.store.commit(batch)?;
+        self.store.flush()?;
+        Ok(block.get_height() > self.get_best_height()? && !invalid_parent)
+    }
+
     // GetBlock finds a block by its hash and returns it
     pub fn get_block(&self, block_hash: &str) -> Result<Block> {
-        let data = self.db.get(block_hash)?.unwrap();
-        let block = deserialize(&data.to_vec())?;
+        let data = match self.store.get(Tree::Blocks, block_hash.as_bytes())? {
+            Some(data) => data,
+            None if self.is_pruned(block_hash)? => {
+                return Err(format_err!("ERROR: Block {} has been pruned", block_hash));
+            }
+            None => return Err(format_err!("ERROR: Block {} is not found", block_hash)),
+        };
+        let block = deserialize(&data)?;
         Ok(block)
     }
 
+    /// GetHeader returns the header of a stored block, pruned or not
+    pub fn get_header(&self, block_hash: &str) -> Result<BlockHeader> {
+        match self.store.get(Tree::Headers, block_hash.as_bytes())? {
+            Some(data) => Ok(deserialize(&data)?),
+            None => self.get_block(block_hash)?.header(),
+        }
+    }
+
     /// GetBestHeight returns the height of the latest block
     pub fn get_best_height(&self) -> Result<i32> {
-        let lasthash = if let Some(h) = self.db.get("LAST")? {
-            h
-        } else {
-            return Ok(-1);
-        };
-        let last_data = self.db.get(lasthash)?.unwrap();
-        let last_block: Block = deserialize(&last_data.to_vec())?;
-        Ok(last_block.get_height())
+        Ok(self.height)
     }
 
     /// GetBlockHashes returns a list of hashes of all the blocks in the chain
-    pub fn get_block_hashs(&self) -> Vec<String> {
+    pub fn get_block_hashs(&self) -> Result<Vec<String>> {
         let mut list = Vec::new();
         for b in self.iter() {
-            list.push(b.get_hash());
+            list.push(b?.get_hash());
+        }
+        Ok(list)
+    }
+    /// IsInvalid reports whether the block or one of its ancestors was invalidated
+    pub fn is_invalid(&self, block_hash: &str) -> Result<bool> {
+        self.store.contains(Tree::Invalid, block_hash.as_bytes())
+    }
+
+    /// InvalidateBlock marks a block and its descendants invalid and moves the tip off them
+    ///
+    /// If the block is on the best chain the tip moves to the highest valid
+    /// block. The marks are kept, so the node won't follow that branch again
+    /// until reconsider_block. The UTXO set is not touched, see UTXOSet::invalidate_block.
+    pub fn invalidate_block(&mut self, block_hash: &str) -> Result<()> {
+        let header = self.get_header(block_hash)?;
+        if header.prev_block_hash.is_empty() {
+            return Err(format_err!("ERROR: The genesis block can't be invalidated"));
+        }
+        if header.height <= self.get_prune_height()? && self.get_prune_height()? > 0 {
+            return Err(format_err!(
+                "ERROR: Block {} is too close to the pruned blocks to be invalidated",
+                block_hash
+            ));
+        }
+        if let Some(checkpoint) = self.last_checkpoint()?
+            && header.height <= checkpoint
+        {
+            return Err(format_err!(
+                "ERROR: Block {} is at or below the checkpoint at height {}",
+                block_hash,
+                checkpoint
+            ));
+        }
+
+        let mut batch = Batch::new();
+        batch.insert(Tree::Invalid, block_hash, [INVALID_BLOCK]);
+        for hash in self.descendants(block_hash)? {
+            batch.insert(Tree::Invalid, &hash, [INVALID_CHILD]);
+        }
+        self.store.commit(batch)?;
+
+        if self.get_block_hash(header.height)?.as_deref() == Some(block_hash) {
+            let best = match self.best_valid_block()? {
+                Some(best) => best,
+                None => return Err(format_err!("ERROR: No valid chain is left")),
+            };
+            info!("block {} invalidated, tip moves to {}", block_hash, best.get_hash());
+            self.set_best_chain(&best)?;
+        }
+        Ok(())
+    }
+
+    /// ReconsiderBlock clears the invalid marks of a block, its ancestors and its descendants
+    ///
+    /// The tip moves to the best valid chain if it is now higher than the current one.
+    pub fn reconsider_block(&mut self, block_hash: &str) -> Result<()> {
+        if !self.has_block(block_hash)? {
+            return Err(format_err!("ERROR: Block {} is not found", block_hash));
+        }
+        let mut batch = Batch::new();
+        for hash in self.descendants(block_hash)? {
+            batch.remove(Tree::Invalid, &hash);
+        }
+        let mut hash = block_hash.to_string();
+        while !hash.is_empty() && self.has_block(&hash)? {
+            batch.remove(Tree::Invalid, &hash);
+            hash = self.get_header(&hash)?.prev_block_hash;
+        }
+        self.store.commit(batch)?;
+
+        if let Some(best) = self.best_valid_block()?
+            && best.get_height() > self.height
+        {
+            info!("block {} reconsidered, tip moves to {}", block_hash, best.get_hash());
+            self.set_best_chain(&best)?;
+        }
+        Ok(())
+    }
+
+    /// descendants returns the hashes of the stored blocks that build on block_hash
+    fn descendants(&self, block_hash: &str) -> Result<Vec<String>> {
+        let mut children: HashMap<String, Vec<String>> = HashMap::new();
+        for kv in self.store.scan_prefix(Tree::Blocks, &[]) {
+            let (_, value) = kv?;
+            let block: Block = deserialize(&value)?;
+            children.entry(block.get_prev_hash()).or_default().push(block.get_hash());
+        }
+        let mut found = Vec::new();
+        let mut todo = vec![block_hash.to_string()];
+        while let Some(hash) = todo.pop() {
+            if let Some(list) = children.remove(&hash) {
+                found.extend(list.iter().cloned());
+                todo.extend(list);
+            }
+        }
+        Ok(found)
+    }
+
+    /// best_valid_block returns the highest valid block that connects to the best chain
+    ///
+    /// At equal heights the block already on the best chain wins.
+    fn best_valid_block(&self) -> Result<Option<Block>> {
+        let mut candidates = Vec::new();
+        for kv in self.store.scan_prefix(Tree::Blocks, &[]) {
+            let (key, value) = kv?;
+            if self.store.contains(Tree::Invalid, &key)? {
+                continue;
+            }
+            let block: Block = deserialize(&value)?;
+            let on_best = self.get_block_hash(block.get_height())?.as_deref()
+                == Some(block.get_hash().as_str());
+            candidates.push((block.get_height(), on_best, block));
+        }
+        candidates.sort_by_key(|c| std::cmp::Reverse((c.0, c.1)));
+
+        for (_, _, block) in candidates {
+            if self.connects(&block)? {
+                return Ok(Some(block));
+            }
         }
-        list
+        Ok(None)
+    }
+
+    /// connects reports whether every ancestor of block is stored and valid
+    fn connects(&self, block: &Block) -> Result<bool> {
+        let mut hash = block.get_prev_hash();
+        let mut height = block.get_height() - 1;
+        while !hash.is_empty() {
+            if !self.has_block(&hash)? || self.is_invalid(&hash)? {
+                return Ok(false);
+            }
+            // 到了主链上的有效区块,再往前都是有效的
+            if self.get_block_hash(height)?.as_deref() == Some(hash.as_str()) {
+                return Ok(true);
+            }
+            hash = self.get_header(&hash)?.prev_block_hash;
+            height -= 1;
+        }
+        Ok(true)
+    }
+
+    /// SetBestChain makes block the tip, rewriting the height and txid indexes
+    ///
+    /// Heights above the block that the old best chain used are dropped along
+    /// with the txid index entries of their blocks.
+    pub fn set_best_chain(&mut self, block: &Block) -> Result<()> {
+        let mut batch = Batch::new();
+        for height in block.get_height() + 1..=self.height {
+            if let Some(hash) = self.get_block_hash(height)? {
+                for tx in self.get_block(&hash)?.get_transaction() {
+                    batch.remove(Tree::TxIndex, hex::decode(&tx.id)?);
+                }
+                batch.remove(Tree::Heights, height_key(height));
+            }
+        }
+        self.index_best_chain(block, &mut batch)?;
+        self.store.commit(batch)?;
+        self.store.flush()?;
+        self.set_tip(block);
+        Ok(())
     }
+
     // /// FindUnspentTransactions returns a list of transactions containing unspent outputs
     // pub fn find_spendable_outputs(
     //     &self,
@@ -299,23 +1020,225 @@ impl Blockchain {
     // }
 }
 
-impl<'a>  Iterator for BlockchainIterator<'a>{
-    type Item = Block;
+impl<S: ChainStore> Iterator for BlockchainIterator<'_, S> {
+    type Item = Result<Block>;
 
+    /// Next walks back to the previous block; it ends after the genesis block
+    /// or at the first pruned block, and yields an error for a block that is
+    /// missing or can't be read
     fn next(&mut self) -> Option<Self::Item> {
-        if let Ok(encoded_block) = self.bc.db.get(&self.current_hash) {
-            return match encoded_block {
-                Some(b) => {
-                    if let Ok(block) = deserialize::<Block>(&b) {
-                        self.current_hash = block.get_prev_hash();
-                        Some(block)
-                    } else {
-                        None
-                    }
-                }
-                None => None,
-            };
+        if self.current_hash.is_empty() {
+            return None;
+        }
+        let hash = std::mem::take(&mut self.current_hash);
+        let encoded = match self.bc.store.get(Tree::Blocks, hash.as_bytes()) {
+            Ok(Some(b)) => b,
+            Ok(None) => {
+                return match self.bc.store.contains(Tree::Headers, hash.as_bytes()) {
+                    Ok(true) => None,
+                    Ok(false) => Some(Err(format_err!("ERROR: Block {} is missing", hash))),
+                    Err(e) => Some(Err(e)),
+                };
+            }
+            Err(e) => return Some(Err(e)),
+        };
+        match deserialize::<Block>(&encoded) {
+            Ok(block) => {
+                self.current_hash = block.get_prev_hash();
+                Some(Ok(block))
+            }
+            Err(e) => Some(Err(format_err!("ERROR: Block {} can't be read: {}", hash, e))),
         }
-        None
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn test_reject_orphan() {
+        let mut chain = TestChain::new();
+        let first = chain.mine(vec![]);
+        let second = chain.mine(vec![]);
+        let genesis = chain.utxo.blockchain.get_block(&first.get_prev_hash()).unwrap();
+
+        let mut bc = Blockchain::new(&MemoryStore::new()).unwrap();
+        bc.add_block(genesis).unwrap();
+        let err = bc.add_block(second.clone()).unwrap_err().to_string();
+        assert!(err.contains("is unknown"), "{}", err);
+        assert!(!bc.has_block(&second.get_hash()).unwrap());
+
+        bc.add_block(first).unwrap();
+        bc.add_block(second.clone()).unwrap();
+        assert_eq!(bc.tip, second.get_hash());
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let mut a = TestChain::new();
+        let b = TestChain::new();
+        let block = a.mine(vec![]);
+        assert_eq!(a.utxo.blockchain.get_best_height().unwrap(), 1);
+        assert_eq!(b.utxo.blockchain.get_best_height().unwrap(), 0);
+
+        // 同一个store再打开一次,看到的是同一条链
+        let bc = Blockchain::new(&a.utxo.blockchain.store).unwrap();
+        assert_eq!(bc.tip, block.get_hash());
+        assert_eq!(bc.get_best_height().unwrap(), 1);
+        assert_eq!(bc.iter().count(), 2);
+        assert!(!b.utxo.blockchain.has_block(&block.get_hash()).unwrap());
+    }
+
+    #[test]
+    fn test_indexes_follow_reorg() {
+        let mut chain = TestChain::new();
+        let alice = chain.address();
+        let miner = chain.miner.clone();
+        let tx = chain.send(&miner, &alice, 3);
+        let a1 = chain.mine(vec![tx.clone()]);
+        let bc = &chain.utxo.blockchain;
+        assert_eq!(bc.get_block_hash(1).unwrap(), Some(a1.get_hash()));
+        assert_eq!(bc.find_transacton(&tx.id).unwrap().id, tx.id);
+        assert_eq!(bc.get_transaction_location(&tx.id).unwrap().unwrap().position, 1);
+
+        // 从创世区块分出一条更长的链
+        let genesis = bc.get_block(&a1.get_prev_hash()).unwrap();
+        let b1 = chain.mine_on(&genesis);
+        let b2 = chain.mine_on(&b1);
+        chain.utxo.add_block(b1.clone()).unwrap();
+        chain.utxo.add_block(b2.clone()).unwrap();
+        let bc = &chain.utxo.blockchain;
+        assert_eq!(bc.tip, b2.get_hash());
+        assert_eq!(bc.get_block_hash(1).unwrap(), Some(b1.get_hash()));
+        assert_eq!(bc.get_block_hash(2).unwrap(), Some(b2.get_hash()));
+        assert!(bc.find_transacton(&tx.id).is_err());
+        assert!(bc.get_transaction_location(&a1.get_transaction()[0].id).unwrap().is_none());
+        let coinbase = &b1.get_transaction()[0];
+        assert_eq!(bc.find_transacton(&coinbase.id).unwrap().id, coinbase.id);
+
+        // 没有txid索引的链打开时会重建
+        bc.store.clear(Tree::TxIndex).unwrap();
+        let bc = Blockchain::new(&bc.store).unwrap();
+        assert_eq!(bc.find_transacton(&coinbase.id).unwrap().id, coinbase.id);
+        let coinbase = &b2.get_transaction()[0];
+        assert_eq!(bc.get_transaction_location(&coinbase.id).unwrap().unwrap().block_hash, b2.get_hash());
+    }
+
+    fn chain_with(params: &ChainParams, blocks: &[Block]) -> Blockchain<MemoryStore> {
+        let mut bc = Blockchain::new(&MemoryStore::new()).unwrap();
+        bc.set_chain_params(params.clone());
+        for block in blocks {
+            bc.add_block(block.clone()).unwrap();
+        }
+        bc
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let mut chain = TestChain::new();
+        let a1 = chain.mine(vec![]);
+        let a2 = chain.mine(vec![]);
+        let a3 = chain.mine(vec![]);
+        let genesis = chain.utxo.blockchain.get_block(&a1.get_prev_hash()).unwrap();
+        let mut params = ChainParams::default();
+        params.checkpoints.push(Checkpoint {
+            height: 2,
+            block_hash: a2.get_hash(),
+        });
+
+        // 检查点高度上的别的区块不要
+        let mut bc = chain_with(&params, &[genesis.clone(), a1.clone()]);
+        let err = bc.add_block(chain.mine_on(&a1)).unwrap_err().to_string();
+        assert!(err.contains("does not match the checkpoint"), "{}", err);
+        bc.add_block(a2.clone()).unwrap();
+        bc.add_block(a3.clone()).unwrap();
+        assert_eq!(bc.tip, a3.get_hash());
+
+        // 越过检查点之后,从它下面分叉的区块也不要
+        let err = bc.add_block(chain.mine_on(&genesis)).unwrap_err().to_string();
+        assert!(err.contains("below the checkpoint"), "{}", err);
+        bc.add_block(chain.mine_on(&a2)).unwrap();
+        assert_eq!(bc.tip, a3.get_hash());
+    }
+
+    #[test]
+    fn test_assume_valid() {
+        let mut chain = TestChain::new();
+        let alice = chain.address();
+        let miner = chain.miner.clone();
+        let genesis = chain.utxo.blockchain.get_block(&chain.utxo.blockchain.tip).unwrap();
+
+        // 签名坏了的交易,txid不变,见证承诺按坏签名算
+        let mut bad_tx = chain.send(&miner, &alice, 3);
+        bad_tx.witness[0].signature[0] ^= 1;
+        let coinbase = Transaction::new_coinbase(miner.clone(), String::new()).unwrap();
+        let bad1 = Block::new_block(vec![coinbase, bad_tx.clone()], genesis.get_hash(), 1).unwrap();
+        let good2 = chain.mine_on(&bad1);
+        let mut params = ChainParams::default();
+        params.assume_valid = Some(Checkpoint {
+            height: 2,
+            block_hash: good2.get_hash(),
+        });
+
+        // 只是高度够低还不够,不知道它是assumevalid区块的祖先就照样验签
+        let mut bc = chain_with(&params, std::slice::from_ref(&genesis));
+        let err = bc.add_block(bad1.clone()).unwrap_err().to_string();
+        assert!(err.contains("invalid transactions"), "{}", err);
+
+        // 区块头链说明了它是祖先,就不验签
+        let headers: Vec<BlockHeader> = [&genesis, &bad1, &good2]
+            .iter()
+            .map(|b| b.header().unwrap())
+            .collect();
+        bc.add_headers_chain(&headers);
+        bc.add_block(bad1.clone()).unwrap();
+
+        // 同样高度的另一个区块不是祖先
+        let coinbase = Transaction::new_coinbase(miner.clone(), String::new()).unwrap();
+        let other = Block::new_block(vec![coinbase, bad_tx], genesis.get_hash(), 1).unwrap();
+        let err = bc.check_block(&other).unwrap_err().to_string();
+        assert!(err.contains("invalid transactions"), "{}", err);
+
+        bc.add_block(good2.clone()).unwrap();
+        assert_eq!(bc.tip, good2.get_hash());
+
+        // assumevalid区块已经存下来了,从它往回走也能认出祖先
+        let mut reopened = Blockchain::new(&bc.store).unwrap();
+        reopened.set_chain_params(params);
+        assert!(reopened.is_assumed_valid(&bad1).unwrap());
+        assert!(!reopened.is_assumed_valid(&other).unwrap());
+        assert!(reopened.is_assumed_valid(&good2).unwrap());
+    }
+
+    #[test]
+    fn test_forged_txid() {
+        let mut chain = TestChain::new();
+        let alice = chain.address();
+        let miner = chain.miner.clone();
+        let genesis = chain.utxo.blockchain.get_block(&chain.utxo.blockchain.tip).unwrap();
+
+        // 默克尔根按重新算出的txid计算,改了存下的id工作量证明照样成立
+        let mut tx = chain.send(&miner, &alice, 3);
+        tx.id = "ab".repeat(32);
+        let coinbase = Transaction::new_coinbase(miner.clone(), String::new()).unwrap();
+        let forged = Block::new_block(vec![coinbase, tx], genesis.get_hash(), 1).unwrap();
+        assert!(forged.check_proof_of_work().unwrap());
+
+        // 在assumevalid区块下面也一样不要
+        let params = ChainParams {
+            assume_valid: Some(Checkpoint {
+                height: 1,
+                block_hash: forged.get_hash(),
+            }),
+            ..Default::default()
+        };
+        let mut bc = chain_with(&params, std::slice::from_ref(&genesis));
+        bc.add_headers_chain(&[genesis.header().unwrap(), forged.header().unwrap()]);
+        assert!(bc.is_assumed_valid(&forged).unwrap());
+        let err = bc.add_block(forged.clone()).unwrap_err().to_string();
+        assert!(err.contains("bad transaction id"), "{}", err);
+        assert!(!bc.has_block(&forged.get_hash()).unwrap());
+    }
+}
@@ -0,0 +1,293 @@
+//! bootstrap files of the exportchain and importchain commands
+//!
+//! A bootstrap file seeds a node with the best chain without P2P sync. It is
+//! an 8 byte file header followed by one record per block, in height order
+//! from the genesis block:
+//!
+//! ```text
+//! file header
+//!   4 bytes  magic "RBCB"
+//!   uint32   format version, little-endian (1)
+//! record
+//!   uint32   length of the block, little-endian
+//!   4 bytes  checksum, the first 4 bytes of SHA-256(SHA-256(block))
+//!   bytes    the block in the canonical encoding, see the encoding module
+//! ```
+//!
+//! Importing sends every block through UTXOSet::add_block, so it is validated
+//! like a block received from a peer. The offset of the next record is saved
+//! as blocks are imported; running the import again on the same file resumes
+//! from there.
+
+use super::*;
+use crate::block::*;
+use crate::blockchain::*;
+use crate::encoding::*;
+use crate::storage::*;
+use crate::utxoset::*;
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+
+/// Magic bytes at the start of a bootstrap file
+const FILE_MAGIC: [u8; 4] = *b"RBCB";
+/// Current bootstrap file format version
+const FORMAT_VERSION: u32 = 1;
+/// Length of the file header and of a record header
+const HEADER_LEN: u64 = 8;
+/// Largest block a record may hold
+const MAX_RECORD_LEN: usize = 32 * 1024 * 1024;
+/// Key of the progress of an interrupted import in the metadata tree
+pub const IMPORT_PROGRESS_KEY: &str = "IMPORT_PROGRESS";
+
+/// ImportReport counts the blocks of an import
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    /// blocks the node already had
+    pub skipped: usize,
+    /// offset an interrupted import was resumed from
+    pub resumed_at: Option<u64>,
+}
+
+/// ExportChain writes the best chain to a bootstrap file and returns the number of blocks
+pub fn export_chain<S: ChainStore>(bc: &Blockchain<S>, path: &str) -> Result<usize> {
+    let prune_height = bc.get_prune_height()?;
+    if prune_height > 0 {
+        return Err(format_err!(
+            "ERROR: Blocks below height {} are pruned, the chain can't be exported",
+            prune_height
+        ));
+    }
+
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(&FILE_MAGIC)?;
+    w.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    let mut count = 0;
+    for height in 0..=bc.get_best_height()? {
+        let hash = match bc.get_block_hash(height)? {
+            Some(hash) => hash,
+            None => return Err(format_err!("ERROR: No block at height {}", height)),
+        };
+        write_record(&mut w, &encode(&bc.get_block(&hash)?)?)?;
+        count += 1;
+    }
+    w.flush()?;
+    Ok(count)
+}
+
+/// ImportChain adds the blocks of a bootstrap file to the chain
+pub fn import_chain<S: ChainStore>(utxo: &mut UTXOSet<S>, path: &str) -> Result<ImportReport> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut head = [0; HEADER_LEN as usize];
+    if file.read_exact(&mut head).is_err() || head[..4] != FILE_MAGIC {
+        return Err(format_err!("ERROR: {} is not a bootstrap file", path));
+    }
+    let version = u32::from_le_bytes([head[4], head[5], head[6], head[7]]);
+    if version != FORMAT_VERSION {
+        return Err(format_err!("ERROR: Unknown bootstrap format version {}", version));
+    }
+
+    let mut report = ImportReport::default();
+    let mut offset = HEADER_LEN;
+    if let Some(saved) = load_progress(&utxo.blockchain.store, file_len)? {
+        offset = saved;
+        report.resumed_at = Some(saved);
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let mut r = BufReader::new(file);
+
+    loop {
+        let payload = match read_record(&mut r) {
+            Ok(Some(payload)) => payload,
+            Ok(None) => break,
+            Err(e) => return Err(format_err!("{} at offset {}", e, offset)),
+        };
+        let block: Block = decode(&payload)?;
+        let hash = block.get_hash();
+        if block.get_height() == 0
+            && let Some(genesis) = utxo.blockchain.get_block_hash(0)?
+            && genesis != hash
+        {
+            return Err(format_err!(
+                "ERROR: The file starts from genesis block {}, the chain from {}",
+                hash,
+                genesis
+            ));
+        }
+
+        if utxo.blockchain.has_block(&hash)? {
+            report.skipped += 1;
+        } else {
+            utxo.add_block(block)?;
+            report.imported += 1;
+        }
+        offset += HEADER_LEN + payload.len() as u64;
+        save_progress(&utxo.blockchain.store, file_len, offset)?;
+        if (report.imported + report.skipped) % 1000 == 0 {
+            info!("imported {} blocks, {}/{} bytes", report.imported, offset, file_len);
+        }
+    }
+
+    let mut batch = Batch::new();
+    batch.remove(Tree::Metadata, IMPORT_PROGRESS_KEY);
+    utxo.blockchain.store.commit(batch)?;
+    utxo.flush()?;
+    Ok(report)
+}
+
+/// load_progress returns the saved offset of an interrupted import of a file of file_len bytes
+fn load_progress<S: ChainStore>(store: &S, file_len: u64) -> Result<Option<u64>> {
+    match store.get(Tree::Metadata, IMPORT_PROGRESS_KEY.as_bytes())? {
+        Some(v) => {
+            let (len, offset): (u64, u64) = deserialize(&v)?;
+            // 文件长度对不上说明换了文件,从头开始,已有的区块会被跳过
+            Ok(if len == file_len { Some(offset) } else { None })
+        }
+        None => Ok(None),
+    }
+}
+
+fn save_progress<S: ChainStore>(store: &S, file_len: u64, offset: u64) -> Result<()> {
+    let mut batch = Batch::new();
+    batch.insert(Tree::Metadata, IMPORT_PROGRESS_KEY, serialize(&(file_len, offset))?);
+    store.commit(batch)
+}
+
+/// WriteRecord writes one length-prefixed, checksummed record
+pub fn write_record<W: Write>(w: &mut W, payload: &[u8]) -> Result<()> {
+    w.write_all(&(payload.len() as u32).to_le_bytes())?;
+    w.write_all(&sha256d(payload)[..4])?;
+    w.write_all(payload)?;
+    Ok(())
+}
+
+/// ReadRecord reads the next record, None at the end of the file
+pub fn read_record<R: Read>(r: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut head = [0; HEADER_LEN as usize];
+    let mut n = 0;
+    while n < head.len() {
+        match r.read(&mut head[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if n == 0 {
+        return Ok(None);
+    }
+    if n < head.len() {
+        return Err(format_err!("ERROR: Truncated record header"));
+    }
+
+    let len = u32::from_le_bytes([head[0], head[1], head[2], head[3]]) as usize;
+    if len > MAX_RECORD_LEN {
+        return Err(format_err!("ERROR: Record of {} bytes is too large", len));
+    }
+    let mut payload = vec![0; len];
+    if r.read_exact(&mut payload).is_err() {
+        return Err(format_err!("ERROR: Truncated record"));
+    }
+    if sha256d(&payload)[..4] != head[4..] {
+        return Err(format_err!("ERROR: Bad record checksum"));
+    }
+    Ok(Some(payload))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+
+    fn empty_chain() -> UTXOSet<MemoryStore> {
+        UTXOSet::new(Blockchain::new(&MemoryStore::new()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_records() {
+        let mut data = Vec::new();
+        write_record(&mut data, b"first").unwrap();
+        write_record(&mut data, b"").unwrap();
+
+        let mut r = &data[..];
+        assert_eq!(read_record(&mut r).unwrap(), Some(b"first".to_vec()));
+        assert_eq!(read_record(&mut r).unwrap(), Some(Vec::new()));
+        assert_eq!(read_record(&mut r).unwrap(), None);
+
+        let mut r = &data[..10];
+        assert!(read_record(&mut r).is_err());
+
+        let mut corrupted = data.clone();
+        corrupted[9] ^= 1;
+        assert!(read_record(&mut &corrupted[..]).is_err());
+    }
+
+    #[test]
+    fn test_export_import() {
+        let mut chain = TestChain::new();
+        let alice = chain.address();
+        let miner = chain.miner.clone();
+        let tx = chain.send(&miner, &alice, 3);
+        chain.mine(vec![tx]);
+        for _ in 0..3 {
+            chain.mine(vec![]);
+        }
+        let bc = &chain.utxo.blockchain;
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("bootstrap-{}.dat", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert_eq!(export_chain(bc, path).unwrap(), 5);
+
+        let mut utxo = empty_chain();
+        let report = import_chain(&mut utxo, path).unwrap();
+        assert_eq!((report.imported, report.skipped, report.resumed_at), (5, 0, None));
+        assert_eq!(utxo.blockchain.tip, bc.tip);
+        for height in 0..5 {
+            assert_eq!(utxo.blockchain.get_block_hash(height).unwrap(), bc.get_block_hash(height).unwrap());
+        }
+        assert_eq!(utxo.find_UTXO(&pub_key_hash(&alice)).unwrap().len(), 1);
+        assert_utxo_set_matches(&utxo);
+        // 再导入一次,区块都已经有了
+        let report = import_chain(&mut utxo, path).unwrap();
+        assert_eq!((report.imported, report.skipped), (0, 5));
+
+        let data = std::fs::read(path).unwrap();
+        let mut offsets = vec![HEADER_LEN as usize];
+        let mut r = &data[HEADER_LEN as usize..];
+        while let Some(payload) = read_record(&mut r).unwrap() {
+            offsets.push(offsets.last().unwrap() + HEADER_LEN as usize + payload.len());
+        }
+        let part = dir.join(format!("bootstrap-{}.part", std::process::id()));
+        let part = part.to_str().unwrap();
+
+        // 文件截断在第三个区块中间:导入前两个区块后报错,换成完整的文件再导入会跳过它们
+        std::fs::write(part, &data[..offsets[2] + 10]).unwrap();
+        let mut utxo = empty_chain();
+        assert!(import_chain(&mut utxo, part).is_err());
+        assert_eq!(utxo.blockchain.get_best_height().unwrap(), 1);
+        let report = import_chain(&mut utxo, path).unwrap();
+        assert_eq!((report.imported, report.skipped, report.resumed_at), (3, 2, None));
+        assert_eq!(utxo.blockchain.tip, bc.tip);
+
+        // 同一个文件的第三个区块坏了,修好之后从断开的地方接着导入
+        let mut corrupted = data.clone();
+        corrupted[offsets[2] + 10] ^= 1;
+        std::fs::write(part, &corrupted).unwrap();
+        let mut utxo = empty_chain();
+        assert!(import_chain(&mut utxo, part).is_err());
+        std::fs::write(part, &data).unwrap();
+        let report = import_chain(&mut utxo, part).unwrap();
+        assert_eq!(report.resumed_at, Some(offsets[2] as u64));
+        assert_eq!((report.imported, report.skipped), (3, 0));
+        assert_eq!(utxo.blockchain.tip, bc.tip);
+        assert_utxo_set_matches(&utxo);
+        let store = &utxo.blockchain.store;
+        assert!(store.get(Tree::Metadata, IMPORT_PROGRESS_KEY.as_bytes()).unwrap().is_none());
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(part).unwrap();
+    }
+}
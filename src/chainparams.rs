@@ -0,0 +1,156 @@
+//! parameters of the chain a node trusts
+//!
+//! The built-in values can be extended with a text file in the data
+//! directory, one entry per line, `#` starts a comment:
+//!
+//! ```text
+//! assumeutxo <height> <block hash> <UTXO set hash>
+//! checkpoint <height> <block hash>
+//! assumevalid <height> <block hash>
+//! ```
+//!
+//! A checkpoint pins the block at its height: a block contradicting it is
+//! rejected, and once the best chain passes it no fork starting below it is
+//! accepted. The assumed valid block is a checkpoint too, and the signatures
+//! of its ancestors are not checked; their proof of work and the coins they
+//! spend still are.
+
+use super::*;
+use failure::format_err;
+use std::fs;
+use std::path::Path;
+
+/// File with the configured chain parameters
+pub const CHAIN_PARAMS_FILE: &str = "data/chainparams.txt";
+
+/// AssumeUtxo is a trusted UTXO snapshot a node may start from instead of replaying the chain
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssumeUtxo {
+    pub height: i32,
+    pub block_hash: String,
+    /// merkle root of the UTXO set at the block, see the snapshot module
+    pub utxo_hash: String,
+}
+
+/// Built-in checkpoints, none since every chain starts from its own genesis block
+const CHECKPOINTS: &[(i32, &str)] = &[];
+
+/// Checkpoint is a block the best chain must hold at its height
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub height: i32,
+    pub block_hash: String,
+}
+
+/// ChainParams are the values a node trusts about its chain
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    pub assume_utxo: Vec<AssumeUtxo>,
+    pub checkpoints: Vec<Checkpoint>,
+    /// block whose ancestors' signatures are not checked
+    pub assume_valid: Option<Checkpoint>,
+}
+
+impl Default for ChainParams {
+    fn default() -> ChainParams {
+        ChainParams {
+            assume_utxo: Vec::new(),
+            checkpoints: CHECKPOINTS
+                .iter()
+                .map(|&(height, hash)| Checkpoint {
+                    height,
+                    block_hash: hash.to_string(),
+                })
+                .collect(),
+            assume_valid: None,
+        }
+    }
+}
+
+impl ChainParams {
+    /// Load returns the built-in parameters extended with the file at path, if it exists
+    pub fn load(path: &str) -> Result<ChainParams> {
+        let mut params = ChainParams::default();
+        if Path::new(path).exists() {
+            params.parse(&fs::read_to_string(path)?)?;
+        }
+        Ok(params)
+    }
+
+    /// LoadDefault loads the parameters from CHAIN_PARAMS_FILE
+    pub fn load_default() -> Result<ChainParams> {
+        ChainParams::load(CHAIN_PARAMS_FILE)
+    }
+
+    fn parse(&mut self, text: &str) -> Result<()> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                [] => {}
+                ["assumeutxo", height, block_hash, utxo_hash] => {
+                    self.assume_utxo.push(AssumeUtxo {
+                        height: height.parse()?,
+                        block_hash: block_hash.to_string(),
+                        utxo_hash: utxo_hash.to_string(),
+                    })
+                }
+                ["checkpoint", height, block_hash] => self.checkpoints.push(Checkpoint {
+                    height: height.parse()?,
+                    block_hash: block_hash.to_string(),
+                }),
+                ["assumevalid", height, block_hash] => {
+                    self.assume_valid = Some(Checkpoint {
+                        height: height.parse()?,
+                        block_hash: block_hash.to_string(),
+                    })
+                }
+                _ => return Err(format_err!("ERROR: Bad chain params line {}: {}", i + 1, line)),
+            }
+        }
+        Ok(())
+    }
+
+    /// AssumeUtxoAt returns the trusted snapshot of the block, if there is one
+    pub fn assume_utxo_at(&self, block_hash: &str) -> Option<&AssumeUtxo> {
+        self.assume_utxo.iter().find(|a| a.block_hash == block_hash)
+    }
+
+    /// AllCheckpoints returns the checkpoints together with the assumed valid block
+    pub fn all_checkpoints(&self) -> impl Iterator<Item = &Checkpoint> {
+        self.checkpoints.iter().chain(self.assume_valid.iter())
+    }
+
+    /// CheckpointAt returns the hash the block at height must have, if it is pinned
+    pub fn checkpoint_at(&self, height: i32) -> Option<&str> {
+        self.all_checkpoints()
+            .find(|c| c.height == height)
+            .map(|c| c.block_hash.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let mut params = ChainParams::default();
+        params
+            .parse("# trusted snapshots\n\nassumeutxo 12 00aa 11bb  # comment\n")
+            .unwrap();
+        assert_eq!(params.assume_utxo.len(), 1);
+        assert_eq!(params.assume_utxo_at("00aa").unwrap().height, 12);
+        assert!(params.assume_utxo_at("11bb").is_none());
+        assert!(params.parse("assumeutxo 12 00aa").is_err());
+
+        params
+            .parse("checkpoint 3 00cc\nassumevalid 8 00dd\n")
+            .unwrap();
+        assert_eq!(params.checkpoint_at(3), Some("00cc"));
+        assert_eq!(params.checkpoint_at(8), Some("00dd"));
+        assert_eq!(params.checkpoint_at(4), None);
+        assert_eq!(params.assume_valid.as_ref().unwrap().height, 8);
+        assert!(params.parse("checkpoint x 00cc").is_err());
+    }
+}
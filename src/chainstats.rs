@@ -0,0 +1,304 @@
+//! statistics of the chain and the UTXO set for the chainstats command
+
+use super::*;
+use crate::block::*;
+use crate::storage::*;
+use crate::utxoset::*;
+use crate::wallets::*;
+use bincode::deserialize;
+use failure::format_err;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Number of windows the difficulty history of a range is split into
+const DIFFICULTY_WINDOWS: usize = 10;
+
+/// ChainStats describes the blocks of a height range and the current UTXO set
+#[derive(Serialize, Debug, Clone)]
+pub struct ChainStats {
+    pub from_height: i32,
+    pub to_height: i32,
+    pub blocks: usize,
+    /// blocks whose transactions were pruned, they only count in the block statistics
+    pub pruned_blocks: usize,
+    /// average time between two blocks of the range in milliseconds
+    pub avg_block_interval_ms: Option<f64>,
+    pub difficulty: Vec<DifficultyWindow>,
+    pub transactions: usize,
+    pub inputs: usize,
+    pub outputs: usize,
+    pub utxo: UtxoSummary,
+    pub address_distribution: Vec<BalanceBucket>,
+    pub top_holders: Vec<Holder>,
+}
+
+/// DifficultyWindow is the difficulty of consecutive blocks of the range
+#[derive(Serialize, Debug, Clone)]
+pub struct DifficultyWindow {
+    pub from_height: i32,
+    pub to_height: i32,
+    /// leading zero bits the target requires
+    pub target_bits: u32,
+    /// average leading zero bits of the block hashes
+    pub avg_hash_zero_bits: f64,
+    pub avg_block_interval_ms: Option<f64>,
+}
+
+/// UtxoSummary sums up the UTXO set at the chain tip
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct UtxoSummary {
+    pub coins: usize,
+    pub total_value: i64,
+    pub addresses: usize,
+}
+
+/// BalanceBucket counts the addresses whose balance is in [min, max)
+#[derive(Serialize, Debug, Clone)]
+pub struct BalanceBucket {
+    pub min: i64,
+    /// None for the last, open bucket
+    pub max: Option<i64>,
+    pub addresses: usize,
+    pub value: i64,
+}
+
+/// Holder is an address and its unspent coins
+#[derive(Serialize, Debug, Clone)]
+pub struct Holder {
+    pub address: String,
+    pub balance: i64,
+    pub coins: usize,
+}
+
+/// BlockSample is what the statistics need from one block
+struct BlockSample {
+    height: i32,
+    timestamp: u128,
+    zero_bits: u32,
+}
+
+/// GetChainStats computes the statistics of the best chain between from and to, inclusive
+///
+/// from defaults to the genesis block and to to the tip. top is the number of
+/// largest holders to list.
+pub fn get_chain_stats<S: ChainStore>(
+    utxo: &UTXOSet<S>,
+    from: Option<i32>,
+    to: Option<i32>,
+    top: usize,
+) -> Result<ChainStats> {
+    let bc = &utxo.blockchain;
+    let best = bc.get_best_height()?;
+    let from = from.unwrap_or(0).max(0);
+    let to = to.unwrap_or(best).min(best);
+    if from > to && best >= 0 {
+        return Err(format_err!(
+            "ERROR: Bad height range {}..{}, the best height is {}",
+            from,
+            to,
+            best
+        ));
+    }
+
+    let mut stats = ChainStats {
+        from_height: from,
+        to_height: to,
+        blocks: 0,
+        pruned_blocks: 0,
+        avg_block_interval_ms: None,
+        difficulty: Vec::new(),
+        transactions: 0,
+        inputs: 0,
+        outputs: 0,
+        utxo: UtxoSummary::default(),
+        address_distribution: Vec::new(),
+        top_holders: Vec::new(),
+    };
+    let mut samples = Vec::new();
+    for height in from..=to {
+        // 从快照启动的链在快照之前没有区块
+        let hash = match bc.get_block_hash(height)? {
+            Some(hash) => hash,
+            None => continue,
+        };
+        let header = bc.get_header(&hash)?;
+        samples.push(BlockSample {
+            height,
+            timestamp: header.timestamp,
+            zero_bits: zero_bits(&hash),
+        });
+        if bc.is_pruned(&hash)? {
+            stats.pruned_blocks += 1;
+            continue;
+        }
+        for tx in bc.get_block(&hash)?.get_transaction() {
+            stats.transactions += 1;
+            if !tx.is_coinbase() {
+                stats.inputs += tx.vin.len();
+            }
+            stats.outputs += tx.vout.len();
+        }
+    }
+    stats.blocks = samples.len();
+    stats.avg_block_interval_ms = avg_interval(&samples);
+    let window = samples.len().div_ceil(DIFFICULTY_WINDOWS).max(1);
+    for (i, chunk) in samples.chunks(window).enumerate() {
+        // 窗口的出块间隔从上一个窗口的最后一个区块算起
+        let start = i * window;
+        let intervals = &samples[start.saturating_sub(1)..start + chunk.len()];
+        stats.difficulty.push(DifficultyWindow {
+            from_height: chunk[0].height,
+            to_height: chunk[chunk.len() - 1].height,
+            target_bits: TARGET_HEXS as u32 * 4,
+            avg_hash_zero_bits: chunk.iter().map(|b| b.zero_bits as f64).sum::<f64>()
+                / chunk.len() as f64,
+            avg_block_interval_ms: avg_interval(intervals),
+        });
+    }
+
+    utxo.flush()?;
+    let mut holders: HashMap<Vec<u8>, (i64, usize)> = HashMap::new();
+    for kv in bc.store.scan_prefix(Tree::Utxos, &[]) {
+        let (_, value) = kv?;
+        let coin: Coin = deserialize(&value)?;
+        stats.utxo.coins += 1;
+        stats.utxo.total_value += coin.output.value as i64;
+        let holder = holders.entry(coin.output.pub_key_hash).or_default();
+        holder.0 += coin.output.value as i64;
+        holder.1 += 1;
+    }
+    stats.utxo.addresses = holders.len();
+    stats.address_distribution = distribution(holders.values().map(|h| h.0));
+
+    let mut ranked: Vec<(Vec<u8>, (i64, usize))> = holders.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.0.cmp(&a.1.0).then_with(|| a.0.cmp(&b.0)));
+    for (pub_key_hash, (balance, coins)) in ranked.into_iter().take(top) {
+        stats.top_holders.push(Holder {
+            address: address_from_pub_key_hash(&pub_key_hash)?,
+            balance,
+            coins,
+        });
+    }
+    Ok(stats)
+}
+
+/// zero_bits counts the leading zero bits of a hex hash
+fn zero_bits(hash: &str) -> u32 {
+    let mut bits = 0;
+    for c in hash.chars() {
+        match c.to_digit(16) {
+            Some(0) => bits += 4,
+            Some(d) => return bits + d.leading_zeros() - 28,
+            None => break,
+        }
+    }
+    bits
+}
+
+fn avg_interval(samples: &[BlockSample]) -> Option<f64> {
+    if samples.len() < 2 {
+        return None;
+    }
+    let first = samples[0].timestamp as f64;
+    let last = samples[samples.len() - 1].timestamp as f64;
+    Some((last - first) / (samples.len() - 1) as f64)
+}
+
+/// distribution buckets balances by powers of ten: [0, 10), [10, 100), ...
+fn distribution(balances: impl Iterator<Item = i64>) -> Vec<BalanceBucket> {
+    let mut buckets: Vec<BalanceBucket> = Vec::new();
+    for balance in balances {
+        let mut index = 0;
+        let mut max = 10;
+        while balance >= max {
+            index += 1;
+            max *= 10;
+        }
+        while buckets.len() <= index {
+            let min = if buckets.is_empty() { 0 } else { 10i64.pow(buckets.len() as u32) };
+            buckets.push(BalanceBucket {
+                min,
+                max: Some(10i64.pow(buckets.len() as u32 + 1)),
+                addresses: 0,
+                value: 0,
+            });
+        }
+        buckets[index].addresses += 1;
+        buckets[index].value += balance;
+    }
+    if let Some(last) = buckets.last_mut() {
+        last.max = None;
+    }
+    buckets
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::blockchain::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn test_helpers() {
+        assert_eq!(zero_bits("00000fab"), 20);
+        assert_eq!(zero_bits("00007fab"), 17);
+        assert_eq!(zero_bits("1"), 3);
+
+        let buckets = distribution(vec![3, 9, 10, 250].into_iter());
+        let counts: Vec<(i64, Option<i64>, usize, i64)> = buckets
+            .iter()
+            .map(|b| (b.min, b.max, b.addresses, b.value))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![(0, Some(10), 2, 12), (10, Some(100), 1, 10), (100, None, 1, 250)]
+        );
+        assert!(distribution(Vec::new().into_iter()).is_empty());
+    }
+
+    #[test]
+    fn test_chain_stats() {
+        let mut chain = TestChain::new();
+        let alice = chain.address();
+        let miner = chain.miner.clone();
+        let tx = chain.send(&miner, &alice, 3);
+        chain.mine(vec![tx.clone()]);
+        for _ in 0..11 {
+            chain.mine(vec![]);
+        }
+
+        let stats = get_chain_stats(&chain.utxo, Some(-5), Some(100), 2).unwrap();
+        assert_eq!((stats.from_height, stats.to_height, stats.blocks), (0, 12, 13));
+        assert_eq!(stats.transactions, 14);
+        assert_eq!(stats.inputs, tx.vin.len());
+        assert_eq!(stats.outputs, 13 + tx.vout.len());
+        assert!(get_chain_stats(&chain.utxo, Some(20), None, 2).is_err());
+
+        assert_eq!((stats.utxo.coins, stats.utxo.total_value, stats.utxo.addresses), (14, 130, 3));
+        let top: Vec<(&str, i64, usize)> = stats
+            .top_holders
+            .iter()
+            .map(|h| (h.address.as_str(), h.balance, h.coins))
+            .collect();
+        let change = address_from_pub_key_hash(&tx.vout[1].pub_key_hash).unwrap();
+        assert_eq!(top, vec![(miner.as_str(), 120, 12), (change.as_str(), 7, 1)]);
+
+        // 裁剪过的区块只算进区块数
+        chain.utxo.blockchain.set_prune_depth(MIN_PRUNE_DEPTH).unwrap();
+        chain.utxo.blockchain.prune().unwrap();
+        let stats = get_chain_stats(&chain.utxo, None, None, 2).unwrap();
+        assert_eq!((stats.blocks, stats.pruned_blocks, stats.transactions), (13, 2, 11));
+        assert_eq!(stats.inputs, 0);
+
+        // 从快照启动的链在快照区块之前是空的
+        let bc = &chain.utxo.blockchain;
+        let header = bc.get_header(&bc.get_block_hash(5).unwrap().unwrap()).unwrap();
+        let mut snapshot = Blockchain::new(&MemoryStore::new()).unwrap();
+        snapshot.start_from_snapshot(&header, Batch::new()).unwrap();
+        let utxo = UTXOSet::unrepaired(snapshot, 1);
+        let stats = get_chain_stats(&utxo, None, None, 2).unwrap();
+        assert_eq!((stats.from_height, stats.to_height), (0, 5));
+        assert_eq!((stats.blocks, stats.pruned_blocks, stats.transactions), (1, 1, 0));
+        assert!(stats.avg_block_interval_ms.is_none());
+    }
+}
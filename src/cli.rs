@@ -2,13 +2,26 @@
 
 use super::*;
 use crate::blockchain::*;
+use crate::bootstrap::*;
+use crate::chainparams::*;
+use crate::chainstats::*;
+use crate::coinscache::*;
+use crate::encoding::*;
+use crate::hdkey::*;
+use crate::keys::*;
 use crate::server::*;
+use crate::snapshot::*;
+use crate::storage::*;
 use crate::transaction::*;
 
 use crate::utxoset::*;
+use crate::verify::*;
 use crate::wallets::*;
 use bitcoincash_addr::Address;
 use clap::{App, Arg};
+use failure::format_err;
+use std::collections::HashSet;
+use std::io::{self, IsTerminal, Write};
 use std::process::exit;
 
 pub struct Cli {}
@@ -25,19 +38,78 @@ impl Cli {
             .author("小面包. 1852611363@qq.com")
             .about("用rust语言实现区块链比特币示例")
             .subcommand(App::new("printchain").about("打印整个区块链"))
-            .subcommand(App::new("createwallet").about("创建一个新钱包"))
-            .subcommand(App::new("listaddresses").about("列出所有钱包地址"))
-            .subcommand(App::new("reindex").about("重建UTXO集合"))
+            .subcommand(
+                App::new("createwallet").about("创建一个新钱包").arg(
+                    Arg::from_usage("-s --scheme=[scheme] '签名方案'")
+                        .possible_values(&["ed25519", "ecdsa", "schnorr"])
+                        .default_value("ed25519"),
+                ),
+            )
+            .subcommand(
+                App::new("listaddresses")
+                    .about("列出所有钱包地址")
+                    .arg(Arg::from_usage("-c --change '同时列出找零地址'")),
+            )
+            .subcommand(
+                App::new("restorewallet")
+                    .about("用助记词恢复钱包, 重新派生用过的所有地址, 助记词从终端输入"),
+            )
+            .subcommand(
+                App::new("importaddress")
+                    .about("导入一个只读地址, 只跟踪它的币, 不能花")
+                    .arg(Arg::from_usage("<address> '要跟踪的地址'"))
+                    .arg(Arg::from_usage("-r --rescan '导入后重新扫描区块链'")),
+            )
+            .subcommand(
+                App::new("importpubkey")
+                    .about("导入一个公钥的地址作为只读地址")
+                    .arg(Arg::from_usage("<pubkey> '十六进制公钥'"))
+                    .arg(Arg::from_usage("-r --rescan '导入后重新扫描区块链'")),
+            )
+            .subcommand(
+                App::new("listtransactions")
+                    .about("列出钱包的交易记录, 最新的在前")
+                    .arg(Arg::from_usage("-a --address=[address] '只列出和这个地址有关的交易'"))
+                    .arg(Arg::from_usage("-n --count=[count] '最多列出多少笔'").default_value("10"))
+                    .arg(Arg::from_usage("--skip=[skip] '跳过最新的多少笔'").default_value("0")),
+            )
+            .subcommand(
+                App::new("gettransaction")
+                    .about("查看一笔钱包交易的详情")
+                    .arg(Arg::from_usage("<txid> '交易ID'")),
+            )
+            .subcommand(App::new("encryptwallet").about("用密码加密钱包里的私钥"))
+            .subcommand(
+                App::new("walletpassphrase")
+                    .about("输入密码, 在运行中的节点上解锁钱包")
+                    .arg(Arg::from_usage("<timeout> '解锁多少秒后自动锁定'"))
+                    .arg(Arg::from_usage("--node=[port] '本机节点的端口'").default_value("3000")),
+            )
+            .subcommand(
+                App::new("walletlock")
+                    .about("立即锁定运行中的节点上的钱包")
+                    .arg(Arg::from_usage("--node=[port] '本机节点的端口'").default_value("3000")),
+            )
+            .subcommand(App::new("changepassphrase").about("修改钱包密码"))
+            .subcommand(
+                App::new("reindex")
+                    .about("重建UTXO集合")
+                    .arg(Arg::from_usage("--dbcache=[MiB] 'UTXO缓存大小'")),
+            )
             .subcommand(
                 App::new("startnode")
                     .about("启动节点服务器")
-                    .arg(Arg::from_usage("<port> '服务器本地绑定的端口'")),
+                    .arg(Arg::from_usage("<port> '服务器本地绑定的端口'"))
+                    .arg(Arg::from_usage("--prune=[depth] '只保留最近depth个区块的交易, 更早的只留区块头'"))
+                    .arg(Arg::from_usage("--dbcache=[MiB] 'UTXO缓存大小'")),
             )
             .subcommand(
                 App::new("startminer")
                     .about("启动挖矿节点服务器")
                     .arg(Arg::from_usage("<port> '服务器本地绑定的端口'"))
-                    .arg(Arg::from_usage("<address> '挖矿奖励接收地址'")),
+                    .arg(Arg::from_usage("<address> '挖矿奖励接收地址'"))
+                    .arg(Arg::from_usage("--prune=[depth] '只保留最近depth个区块的交易, 更早的只留区块头'"))
+                    .arg(Arg::from_usage("--dbcache=[MiB] 'UTXO缓存大小'")),
             )
             .subcommand(
                 App::new("getbalance")
@@ -49,6 +121,69 @@ impl Cli {
             .subcommand(App::new("createblockchain").about("创建新的区块链").arg(
                 Arg::from_usage("<address> '创世区块奖励接收地址'"),
             ))
+            .subcommand(
+                App::new("getblockhash")
+                    .about("获取主链上指定高度的区块哈希")
+                    .arg(Arg::from_usage("<height> '区块高度'")),
+            )
+            .subcommand(
+                App::new("getrawtransaction")
+                    .about("按交易ID获取已上链的原始交易")
+                    .arg(Arg::from_usage("<txid> '交易ID'")),
+            )
+            .subcommand(
+                App::new("invalidateblock")
+                    .about("把区块和它的后代标记为无效, 链顶退回到最好的有效链")
+                    .arg(Arg::from_usage("<hash> '区块哈希'")),
+            )
+            .subcommand(
+                App::new("reconsiderblock")
+                    .about("撤销区块的无效标记, 重新选择最好的链")
+                    .arg(Arg::from_usage("<hash> '区块哈希'")),
+            )
+            .subcommand(
+                App::new("exportchain")
+                    .about("把主链导出到引导文件")
+                    .arg(Arg::from_usage("<file> '引导文件路径'")),
+            )
+            .subcommand(
+                App::new("importchain")
+                    .about("从引导文件导入区块, 中断后再次运行会接着导入")
+                    .arg(Arg::from_usage("<file> '引导文件路径'"))
+                    .arg(Arg::from_usage("--dbcache=[MiB] 'UTXO缓存大小'")),
+            )
+            .subcommand(
+                App::new("dumputxoset")
+                    .about("把链顶的UTXO集合导出成快照")
+                    .arg(Arg::from_usage("<file> '快照文件路径'")),
+            )
+            .subcommand(
+                App::new("loadutxoset")
+                    .about("从UTXO快照启动一条空链")
+                    .arg(Arg::from_usage("<file> '快照文件路径'"))
+                    .arg(Arg::from_usage("-f --force '链参数里没有这个快照的哈希也加载'")),
+            )
+            .subcommand(
+                App::new("verifychain")
+                    .about("校验区块链数据库的完整性")
+                    .arg(
+                        Arg::from_usage("-l --level=[level] '检查级别: 0工作量证明 1默克尔根 2链接和索引 3签名和撤销数据 4UTXO集合'")
+                            .possible_values(&["0", "1", "2", "3", "4"])
+                            .default_value("3"),
+                    )
+                    .arg(
+                        Arg::from_usage("-d --depth=[depth] '检查最近多少个区块, 0表示全部'")
+                            .default_value("0"),
+                    ),
+            )
+            .subcommand(
+                App::new("chainstats")
+                    .about("统计区块链和UTXO集合的数据")
+                    .arg(Arg::from_usage("--from=[height] '起始高度, 默认从创世区块开始'"))
+                    .arg(Arg::from_usage("--to=[height] '结束高度, 默认到链顶'"))
+                    .arg(Arg::from_usage("--top=[n] '列出余额最多的前n个地址'").default_value("10"))
+                    .arg(Arg::from_usage("--json '以JSON格式输出'")),
+            )
             .subcommand(
                 App::new("send")
                     .about("发送交易")
@@ -57,7 +192,11 @@ impl Cli {
                     .arg(Arg::from_usage("<amount> '发送金额'"))
                     .arg(Arg::from_usage(
                         "-m --mine '立即由发送方挖矿'",
-                    )),
+                    ))
+                    .arg(
+                        Arg::from_usage("--node=[port] '由本机这个端口的节点用它解锁的钱包签名并广播'")
+                            .conflicts_with("mine"),
+                    ),
             )
             .get_matches();
 
@@ -66,19 +205,99 @@ impl Cli {
                 let balance = cmd_get_balance(address)?;
                // println!("Balance: {}\n", balance);
             }
-        } else if let Some(_) = matches.subcommand_matches("createwallet") {
-            println!("address: {}", cmd_create_wallet()?);
+        } else if let Some(ref matches) = matches.subcommand_matches("createwallet") {
+            let scheme: SignatureScheme = matches.value_of("scheme").unwrap_or("ed25519").parse()?;
+            println!("address: {}", cmd_create_wallet(scheme)?);
         } else if let Some(_) = matches.subcommand_matches("printchain") {
             cmd_print_chain()?;
-        } else if let Some(_) = matches.subcommand_matches("reindex") {
-            let count = cmd_reindex()?;
+        } else if let Some(ref matches) = matches.subcommand_matches("reindex") {
+            let count = cmd_reindex(dbcache(matches)?)?;
            // println!("Done! There are {} transactions in the UTXO set.", count);
-        } else if let Some(_) = matches.subcommand_matches("listaddresses") {
-            cmd_list_address()?;
+        } else if let Some(ref matches) = matches.subcommand_matches("listaddresses") {
+            cmd_list_address(matches.is_present("change"))?;
+        } else if let Some(_) = matches.subcommand_matches("restorewallet") {
+            cmd_restore_wallet()?;
+        } else if let Some(ref matches) = matches.subcommand_matches("importaddress") {
+            if let Some(address) = matches.value_of("address") {
+                cmd_import_address(address, matches.is_present("rescan"))?;
+            }
+        } else if let Some(ref matches) = matches.subcommand_matches("importpubkey") {
+            if let Some(pubkey) = matches.value_of("pubkey") {
+                cmd_import_pub_key(pubkey, matches.is_present("rescan"))?;
+            }
+        } else if let Some(ref matches) = matches.subcommand_matches("listtransactions") {
+            let count: usize = matches.value_of("count").unwrap_or("10").parse()?;
+            let skip: usize = matches.value_of("skip").unwrap_or("0").parse()?;
+            cmd_list_transactions(matches.value_of("address"), count, skip)?;
+        } else if let Some(ref matches) = matches.subcommand_matches("gettransaction") {
+            if let Some(txid) = matches.value_of("txid") {
+                cmd_get_wallet_transaction(txid)?;
+            }
+        } else if let Some(_) = matches.subcommand_matches("encryptwallet") {
+            cmd_encrypt_wallet()?;
+        } else if let Some(matches) = matches.subcommand_matches("walletpassphrase") {
+            if let (Some(timeout), Some(port)) = (matches.value_of("timeout"), matches.value_of("node")) {
+                cmd_wallet_passphrase(timeout.parse()?, port)?;
+            }
+        } else if let Some(matches) = matches.subcommand_matches("walletlock") {
+            if let Some(port) = matches.value_of("node") {
+                cmd_wallet_lock(port)?;
+            }
+        } else if let Some(_) = matches.subcommand_matches("changepassphrase") {
+            cmd_change_passphrase()?;
         } else if let Some(ref matches) = matches.subcommand_matches("createblockchain") {
             if let Some(address) = matches.value_of("address") {
                 cmd_create_blockchain(address)?;
             }
+        } else if let Some(ref matches) = matches.subcommand_matches("getblockhash") {
+            if let Some(height) = matches.value_of("height") {
+                cmd_get_block_hash(height.parse()?)?;
+            }
+        } else if let Some(ref matches) = matches.subcommand_matches("getrawtransaction") {
+            if let Some(txid) = matches.value_of("txid") {
+                cmd_get_raw_transaction(txid)?;
+            }
+        } else if let Some(ref matches) = matches.subcommand_matches("invalidateblock") {
+            if let Some(hash) = matches.value_of("hash") {
+                cmd_invalidate_block(hash)?;
+            }
+        } else if let Some(ref matches) = matches.subcommand_matches("reconsiderblock") {
+            if let Some(hash) = matches.value_of("hash") {
+                cmd_reconsider_block(hash)?;
+            }
+        } else if let Some(ref matches) = matches.subcommand_matches("exportchain") {
+            if let Some(file) = matches.value_of("file") {
+                cmd_export_chain(file)?;
+            }
+        } else if let Some(ref matches) = matches.subcommand_matches("importchain") {
+            if let Some(file) = matches.value_of("file") {
+                cmd_import_chain(file, dbcache(matches)?)?;
+            }
+        } else if let Some(ref matches) = matches.subcommand_matches("dumputxoset") {
+            if let Some(file) = matches.value_of("file") {
+                cmd_dump_utxo_set(file)?;
+            }
+        } else if let Some(ref matches) = matches.subcommand_matches("loadutxoset") {
+            if let Some(file) = matches.value_of("file") {
+                cmd_load_utxo_set(file, matches.is_present("force"))?;
+            }
+        } else if let Some(ref matches) = matches.subcommand_matches("verifychain") {
+            let level: u32 = matches.value_of("level").unwrap_or("3").parse()?;
+            let depth: usize = matches.value_of("depth").unwrap_or("0").parse()?;
+            if !cmd_verify_chain(level, depth)? {
+                exit(1)
+            }
+        } else if let Some(ref matches) = matches.subcommand_matches("chainstats") {
+            let from = match matches.value_of("from") {
+                Some(h) => Some(h.parse()?),
+                None => None,
+            };
+            let to = match matches.value_of("to") {
+                Some(h) => Some(h.parse()?),
+                None => None,
+            };
+            let top: usize = matches.value_of("top").unwrap_or("10").parse()?;
+            cmd_chain_stats(from, to, top, matches.is_present("json"))?;
         } else if let Some(ref matches) = matches.subcommand_matches("send") {
             let from = if let Some(address) = matches.value_of("from") {
                 address
@@ -98,7 +317,9 @@ impl Cli {
                 println!("amount in send not supply!: usage\n{}", matches.usage());
                 exit(1)
             };
-            if matches.is_present("mine") {
+            if let Some(port) = matches.value_of("node") {
+                cmd_send_from_node(port, from, to, amount)?;
+            } else if matches.is_present("mine") {
                 cmd_send(from, to, amount, true)?;
             } else {
                 cmd_send(from, to, amount, false)?;
@@ -106,9 +327,9 @@ impl Cli {
         } else if let Some(ref matches) = matches.subcommand_matches("startnode") {
             if let Some(port) = matches.value_of("port") {
                 println!("Start node...");
-                let bc = Blockchain::new()?;
-                let utxo_set = UTXOSet { blockchain: bc };
+                let (utxo_set, wallets) = open_node(matches.value_of("prune"), dbcache(matches)?)?;
                 let server = Server::new(port, "", utxo_set)?;
+                server.set_wallets(wallets);
                 server.start_server()?;
             }
         } else if let Some(ref matches) = matches.subcommand_matches("startminer") {
@@ -125,9 +346,9 @@ impl Cli {
                 exit(1)
             };
             println!("Start miner node...");
-            let bc = Blockchain::new()?;
-            let utxo_set = UTXOSet { blockchain: bc };
+            let (utxo_set, wallets) = open_node(matches.value_of("prune"), dbcache(matches)?)?;
             let server = Server::new(port, address, utxo_set)?;
+            server.set_wallets(wallets);
             server.start_server()?;
         }
 
@@ -135,23 +356,80 @@ impl Cli {
     }
 }
 
+/// dbcache returns the UTXO cache size in MiB given with `--dbcache`
+fn dbcache(matches: &clap::ArgMatches) -> Result<usize> {
+    match matches.value_of("dbcache") {
+        Some(mb) => Ok(mb.parse()?),
+        None => Ok(DEFAULT_DBCACHE_MB),
+    }
+}
+
+/// print_cache_stats prints how the UTXO cache was used
+fn print_cache_stats(stats: &CacheStats) {
+    println!(
+        "📊 UTXO缓存: 命中 {} 次, 未命中 {} 次, 落盘 {} 次",
+        stats.hits, stats.misses, stats.flushes
+    );
+}
+
+/// open_blockchain opens the chain in store, checking new blocks against the configured chain params
+fn open_blockchain(store: &Storage) -> Result<Blockchain> {
+    let mut bc = Blockchain::new(store)?;
+    bc.set_chain_params(ChainParams::load_default()?);
+    Ok(bc)
+}
+
+/// open_node opens the UTXO set a node serves and the wallets whose ledger it keeps, pruning old blocks if prune is given
+fn open_node(prune: Option<&str>, dbcache: usize) -> Result<(UTXOSet, Wallets)> {
+    let store = Storage::open_default()?;
+    let mut bc = open_blockchain(&store)?;
+    if let Some(depth) = prune {
+        bc.set_prune_depth(depth.parse()?)?;
+        println!("✂️ 裁剪模式: 只保留最近 {} 个区块的交易", depth);
+    }
+    let utxo_set = UTXOSet::with_dbcache(bc, dbcache)?;
+    // 先把钱包账本追到链顶再裁剪,免得账本还要用的区块被裁掉
+    let wallets = Wallets::new(&store)?;
+    wallets.sync(&utxo_set.blockchain)?;
+    utxo_set.blockchain.prune()?;
+    Ok((utxo_set, wallets))
+}
+
 fn cmd_send(from: &str, to: &str, amount: i32, mine_now: bool) -> Result<()> {
     println!("🚀 开始发送交易...");
     println!("📤 发送方: {}", from);
     println!("📥 接收方: {}", to);
     println!("💎 金额: {} 币", amount);
     
-    let bc = Blockchain::new()?;
-    let mut utxo_set = UTXOSet { blockchain: bc };
-    let wallets = Wallets::new()?;
-    let wallet = wallets.get_wallet(from).unwrap();
-    let tx = Transaction::new_UTXO(&wallet, to, amount, &utxo_set)?;
+    let store = Storage::open_default()?;
+    let bc = open_blockchain(&store)?;
+    let mut utxo_set = UTXOSet::new(bc)?;
+    let mut wallets = Wallets::new(&store)?;
+    // 加密钱包只在运行中的节点内存里解锁,这个进程里总是锁定的
+    if wallets.is_locked() {
+        return Err(format_err!(
+            "ERROR: The wallet is locked, unlock it on a running node with walletpassphrase <timeout> and send with --node <port>"
+        ));
+    }
+    let wallet = wallets.get_wallet(from)?;
+    // 找零打到新派生的找零地址,不回到发送方地址;HD之前的旧钱包要先创建种子
+    if !wallets.has_seed() {
+        return Err(format_err!(
+            "ERROR: The wallet has no HD seed to derive a change address from, run createwallet once to create one and back up its mnemonic"
+        ));
+    }
+    let change = wallets.create_change_address(wallet.scheme)?;
+    let tx = Transaction::new_UTXO(&wallet, to, amount, &change, &utxo_set)?;
+    if tx.vout.len() > 1 {
+        wallets.save_all()?;
+        println!("🔁 找零地址: {}", change);
+    }
     
     if mine_now {
         println!("⛏️  开始挖矿确认交易...");
         let cbtx = Transaction::new_coinbase(from.to_string(), String::from("奖励挖矿"))?;
-        let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
-        utxo_set.update(&new_block)?;
+        utxo_set.mine_block(vec![cbtx, tx])?;
+        wallets.sync(&utxo_set.blockchain)?;
         println!("✅ 交易已确认并添加到区块链!");
         println!("🏆 挖矿奖励: {} 币", crate::transaction::SUBSIDY);
     } else {
@@ -162,20 +440,221 @@ fn cmd_send(from: &str, to: &str, amount: i32, mine_now: bool) -> Result<()> {
     Ok(())
 }
 
-fn cmd_create_wallet() -> Result<String> {
-    let mut ws = Wallets::new()?;
-    let address = ws.create_wallet();
+/// cmd_send_from_node has the local node at port sign and relay the payment with its wallet
+fn cmd_send_from_node(port: &str, from: &str, to: &str, amount: i32) -> Result<()> {
+    println!("🚀 通过节点 {} 发送交易...", port);
+    println!("📤 发送方: {}", from);
+    println!("📥 接收方: {}", to);
+    println!("💎 金额: {} 币", amount);
+    let txid = send_from_node(port, from, to, amount)?;
+    println!("📨 交易已由节点签名并广播, 等待挖矿确认...");
+    println!("🧾 交易ID: {}", txid);
+    println!("🎉 交易发送成功!");
+    Ok(())
+}
+
+fn cmd_create_wallet(scheme: SignatureScheme) -> Result<String> {
+    let store = Storage::open_default()?;
+    let mut ws = Wallets::new(&store)?;
+    unlock_wallet(&mut ws)?;
+    let mnemonic = if ws.has_seed() { None } else { Some(ws.create_seed()?) };
+    let address = ws.create_wallet(scheme)?;
     ws.save_all()?;
+    // 种子保存成功后才显示助记词,免得抄下一个没存进钱包的种子
+    if let Some(mnemonic) = mnemonic {
+        println!("🌱 已生成新的HD种子, 之后的所有地址都由它派生");
+        println!("📝 助记词(只显示这一次, 请抄写下来妥善保管):");
+        println!();
+        println!("    {}", mnemonic);
+        println!();
+        println!("⚠️  丢失助记词就无法恢复钱包, 用 restorewallet 输入助记词可以在新节点上恢复所有地址");
+    }
     println!("🎉 成功创建新钱包!");
+    println!("🔑 签名方案: {}", scheme);
+    if let Some(path) = ws.derivation_path_of(&address) {
+        println!("🧭 派生路径: {}", path_string(&path));
+    }
     println!("💳 钱包地址: {}", address);
     Ok(address)
 }
 
-fn cmd_reindex() -> Result<()> {
+fn cmd_restore_wallet() -> Result<()> {
+    let store = Storage::open_default()?;
+    let mut ws = Wallets::new(&store)?;
+    if ws.has_seed() {
+        return Err(format_err!("ERROR: The wallet already has an HD seed"));
+    }
+    // 助记词不放在命令行参数里,免得留在shell历史和进程列表中
+    let words = read_passphrase("📝 请输入助记词: ")?;
+    let mnemonic = parse_mnemonic(&words.split_whitespace().collect::<Vec<&str>>().join(" "))?;
+    unlock_wallet(&mut ws)?;
+    // 没有区块链也可以恢复,只是找不到用过的地址
+    let used = match open_blockchain(&store) {
+        Ok(bc) if !bc.tip.is_empty() => UTXOSet::new(bc)?.used_pub_key_hashes()?,
+        _ => HashSet::new(),
+    };
+    let restored = ws.restore(&mnemonic, &used)?;
+    ws.save_all()?;
+    println!("♻️ 钱包已从助记词恢复");
+    for address in &restored {
+        if let Some(path) = ws.derivation_path_of(address) {
+            println!("📍 {}  {}", address, path_string(&path));
+        }
+    }
+    println!("📊 恢复了 {} 个用过的地址, 新地址从它们之后继续派生", restored.len());
+    Ok(())
+}
+
+fn cmd_import_address(address: &str, rescan: bool) -> Result<()> {
+    let store = Storage::open_default()?;
+    let mut ws = Wallets::new(&store)?;
+    let address = ws.import_address(address)?;
+    ws.save_all()?;
+    println!("👀 已导入只读地址: {}", address);
+    if rescan {
+        rescan_wallet(&store, &ws)?;
+    }
+    Ok(())
+}
+
+fn cmd_import_pub_key(pubkey: &str, rescan: bool) -> Result<()> {
+    let public_key = match hex::decode(pubkey) {
+        Ok(public_key) => public_key,
+        Err(_) => return Err(format_err!("ERROR: Bad public key {}", pubkey)),
+    };
+    let store = Storage::open_default()?;
+    let mut ws = Wallets::new(&store)?;
+    let address = ws.import_pub_key(&public_key)?;
+    ws.save_all()?;
+    println!("👀 已导入只读地址: {}", address);
+    if rescan {
+        rescan_wallet(&store, &ws)?;
+    }
+    Ok(())
+}
+
+/// rescan_wallet rebuilds the wallet transaction index from the block store
+fn rescan_wallet(store: &Storage, ws: &Wallets) -> Result<()> {
+    let bc = open_blockchain(store)?;
+    println!("🔍 正在重新扫描区块链...");
+    let found = ws.rescan(&bc)?;
+    let best_height = bc.get_best_height()?;
+    for wtx in ws.transactions()? {
+        print_wallet_tx(&wtx, best_height);
+    }
+    println!("✅ 扫描到高度 {}, 找到 {} 笔钱包交易", best_height, found);
+    Ok(())
+}
+
+/// open_ledger opens the wallets with their transaction ledger brought to the chain tip
+///
+/// It also returns the best height, -1 without a blockchain.
+fn open_ledger(store: &Storage) -> Result<(Wallets, i32)> {
+    let ws = Wallets::new(store)?;
+    match open_blockchain(store) {
+        Ok(bc) if !bc.tip.is_empty() => {
+            ws.sync(&bc)?;
+            let best_height = bc.get_best_height()?;
+            Ok((ws, best_height))
+        }
+        _ => Ok((ws, -1)),
+    }
+}
+
+fn direction_label(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Generated => "⛏️ 挖矿",
+        Direction::Received => "📥 收到",
+        Direction::Sent => "📤 发送",
+        Direction::SelfTransfer => "🔁 转给自己",
+    }
+}
+
+fn print_wallet_tx(wtx: &WalletTx, best_height: i32) {
+    let mut line = format!(
+        "{} {:+} 币  {}  {} 确认",
+        direction_label(wtx.direction()),
+        wtx.amount(),
+        wtx.get_readable_time(),
+        wtx.confirmations(best_height)
+    );
+    if let Some(fee) = wtx.fee
+        && wtx.direction() != Direction::Received
+    {
+        line += &format!("  手续费 {}", fee);
+    }
+    println!("{}", line);
+    let counterparties = wtx.counterparties();
+    if !counterparties.is_empty() {
+        println!("    👤 {}", counterparties.join(", "));
+    }
+    println!("    🆔 {}", wtx.txid);
+}
+
+fn cmd_list_transactions(address: Option<&str>, count: usize, skip: usize) -> Result<()> {
+    let store = Storage::open_default()?;
+    let (ws, best_height) = open_ledger(&store)?;
+    let mut txs = ws.transactions()?;
+    if let Some(address) = address {
+        txs.retain(|wtx| wtx.involves(address));
+    }
+    let total = txs.len();
+    println!("\n📜 =============== 钱包交易记录 =============== 📜");
+    for wtx in txs.iter().rev().skip(skip).take(count) {
+        print_wallet_tx(wtx, best_height);
+    }
+    println!("📊 共 {} 笔交易, 显示第 {} 到 {} 笔", total, (skip + 1).min(total), (skip + count).min(total));
+    println!();
+    Ok(())
+}
+
+fn cmd_get_wallet_transaction(txid: &str) -> Result<()> {
+    let store = Storage::open_default()?;
+    let (ws, best_height) = open_ledger(&store)?;
+    let wtx = match ws.get_transaction(txid)? {
+        Some(wtx) => wtx,
+        None => return Err(format_err!("ERROR: Transaction {} is not a wallet transaction", txid)),
+    };
+    println!("\n🧾 =============== 钱包交易 =============== 🧾");
+    println!("🆔 交易ID: {}", wtx.txid);
+    println!("🧭 方向: {}", direction_label(wtx.direction()));
+    println!("💰 金额: {:+} 币", wtx.amount());
+    match wtx.fee {
+        Some(fee) => println!("💸 手续费: {} 币", fee),
+        None if wtx.is_coinbase => {}
+        None => println!("💸 手续费: 未知(有不属于钱包的输入)"),
+    }
+    let counterparties = wtx.counterparties();
+    if !counterparties.is_empty() {
+        println!("👤 对方: {}", counterparties.join(", "));
+    }
+    println!("✅ 确认数: {}", wtx.confirmations(best_height));
+    println!("📦 区块: {} (高度 {})", wtx.block_hash, wtx.height);
+    println!("⏰ 时间: {}", wtx.get_readable_time());
+    for c in &wtx.spent {
+        println!("  ➖ {} 币 ← {} ({}:{})", c.value, c.address, c.outpoint.txid, c.outpoint.vout);
+    }
+    for c in &wtx.received {
+        println!("  ➕ {} 币 → {} (输出 {})", c.value, c.address, c.outpoint.vout);
+    }
+    for c in &wtx.paid {
+        println!("  📤 {} 币 → {} (输出 {})", c.value, c.address, c.outpoint.vout);
+    }
+    println!();
+    Ok(())
+}
+
+fn cmd_reindex(dbcache: usize) -> Result<()> {
     println!("🔄 正在重建UTXO索引...");
-    let bc = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain: bc };
-    let count = utxo_set.reindex()?;
+    let store = Storage::open_default()?;
+    let bc = open_blockchain(&store)?;
+    let utxo_set = UTXOSet::unrepaired(bc, dbcache);
+    let count = utxo_set.reindex_with(&mut |done, total| {
+        if done % 100 == 0 || done == total {
+            println!("⏳ 已重放 {}/{} 个区块", done, total);
+        }
+    })?;
+    print_cache_stats(&utxo_set.cache_stats());
     println!("✅ UTXO索引重建完成!");
     //println!("📊 处理了 {} 笔交易", count);
     Ok(count)
@@ -197,8 +676,9 @@ fn cmd_create_blockchain(address: &str) -> Result<()> {
     println!("💳 创世奖励接收地址: {}", address);
     println!();
     
-    let bc = Blockchain::create_blockchain(address.to_string())?;
-    let utxo_set = UTXOSet { blockchain: bc };
+    let store = Storage::open_default()?;
+    let bc = Blockchain::create_blockchain(address.to_string(), &store)?;
+    let utxo_set = UTXOSet::unrepaired(bc, DEFAULT_DBCACHE_MB);
     utxo_set.reindex()?;
     
     println!("✅ 创世区块链创建成功!");
@@ -210,13 +690,12 @@ fn cmd_create_blockchain(address: &str) -> Result<()> {
 }
 fn cmd_get_balance(address: &str) -> Result<i32> {
     let pub_key_hash = Address::decode(address).unwrap().body;
-    let utxo_set = UTXOSet {
-        blockchain: Blockchain::new()?,
-    };
+    let store = Storage::open_default()?;
+    let utxo_set = UTXOSet::new(open_blockchain(&store)?)?;
     let utxos = utxo_set.find_UTXO(&pub_key_hash)?;
     let mut balance = 0;
-    for out in utxos.outputs {
-        balance += out.value;
+    for (_, coin) in utxos {
+        balance += coin.output.value;
     }
     println!("💰 地址 {} 的余额: {} 币 💎", address, balance);
     // Ok(balance)
@@ -224,13 +703,14 @@ fn cmd_get_balance(address: &str) -> Result<i32> {
 }
 
 fn cmd_print_chain() -> Result<()> {
-    let bc = Blockchain::new()?;
+    let store = Storage::open_default()?;
+    let bc = open_blockchain(&store)?;
     println!("\n🔗 =============== 区块链信息 =============== 🔗\n");
     
     let mut block_count = 0;
     for b in bc.iter() {
         block_count += 1;
-        println!("{}", b);
+        println!("{}", b?);
         println!();
     }
     
@@ -238,14 +718,296 @@ fn cmd_print_chain() -> Result<()> {
     Ok(())
 }
 
-fn cmd_list_address() -> Result<()> {
-    let ws = Wallets::new()?;
-    let addresses = ws.get_all_addresses();
+fn cmd_get_block_hash(height: i32) -> Result<String> {
+    let store = Storage::open_default()?;
+    let bc = open_blockchain(&store)?;
+    match bc.get_block_hash(height)? {
+        Some(hash) => {
+            println!("🔗 高度 {} 的区块哈希: {}", height, hash);
+            Ok(hash)
+        }
+        None => Err(format_err!(
+            "ERROR: No block at height {}, best height is {}",
+            height,
+            bc.get_best_height()?
+        )),
+    }
+}
+
+fn cmd_get_raw_transaction(txid: &str) -> Result<String> {
+    let store = Storage::open_default()?;
+    let bc = open_blockchain(&store)?;
+    let loc = match bc.get_transaction_location(txid)? {
+        Some(loc) => loc,
+        None => return Err(format_err!("ERROR: Transaction {} is not in the chain", txid)),
+    };
+    let tx = bc.find_transacton(txid)?;
+    let raw = hex::encode(encode(&tx)?);
+    println!("📦 所在区块: {} (第 {} 笔交易)", loc.block_hash, loc.position);
+    println!("{}", raw);
+    Ok(raw)
+}
+
+fn cmd_invalidate_block(hash: &str) -> Result<()> {
+    let store = Storage::open_default()?;
+    let mut utxo_set = UTXOSet::new(open_blockchain(&store)?)?;
+    utxo_set.invalidate_block(hash)?;
+    println!("🚫 区块 {} 及其后代已标记为无效", hash);
+    println!(
+        "🔗 当前链顶: {} (高度 {})",
+        utxo_set.blockchain.tip,
+        utxo_set.blockchain.get_best_height()?
+    );
+    Ok(())
+}
+
+fn cmd_reconsider_block(hash: &str) -> Result<()> {
+    let store = Storage::open_default()?;
+    let mut utxo_set = UTXOSet::new(open_blockchain(&store)?)?;
+    utxo_set.reconsider_block(hash)?;
+    println!("♻️ 区块 {} 的无效标记已撤销", hash);
+    println!(
+        "🔗 当前链顶: {} (高度 {})",
+        utxo_set.blockchain.tip,
+        utxo_set.blockchain.get_best_height()?
+    );
+    Ok(())
+}
+
+fn cmd_export_chain(file: &str) -> Result<()> {
+    let store = Storage::open_default()?;
+    let bc = open_blockchain(&store)?;
+    println!("📤 正在导出区块链到 {}...", file);
+    let count = export_chain(&bc, file)?;
+    println!("✅ 已导出 {} 个区块", count);
+    Ok(())
+}
+
+fn cmd_import_chain(file: &str, dbcache: usize) -> Result<()> {
+    let store = Storage::open_default()?;
+    let mut utxo_set = UTXOSet::with_dbcache(open_blockchain(&store)?, dbcache)?;
+    println!("📥 正在从 {} 导入区块...", file);
+    let report = import_chain(&mut utxo_set, file)?;
+    if let Some(offset) = report.resumed_at {
+        println!("⏩ 从上次中断的位置继续 (偏移 {})", offset);
+    }
+    println!("📊 新导入区块: {}", report.imported);
+    println!("📊 已有区块: {}", report.skipped);
+    print_cache_stats(&utxo_set.cache_stats());
+    println!("✅ 导入完成, 当前高度: {}", utxo_set.blockchain.get_best_height()?);
+    Ok(())
+}
+
+fn cmd_dump_utxo_set(file: &str) -> Result<()> {
+    let store = Storage::open_default()?;
+    let utxo_set = UTXOSet::new(open_blockchain(&store)?)?;
+    println!("📸 正在导出UTXO快照到 {}...", file);
+    let stats = dump_utxo_set(&utxo_set, file)?;
+    println!("🧱 区块: {} (高度 {})", stats.block_hash, stats.height);
+    println!("📊 UTXO数量: {}", stats.coins);
+    println!("🔐 UTXO集合哈希: {}", stats.hash);
+    println!("💡 把下面这行加到 {} 里, 别的节点就会信任这个快照:", CHAIN_PARAMS_FILE);
+    println!("assumeutxo {} {} {}", stats.height, stats.block_hash, stats.hash);
+    Ok(())
+}
+
+fn cmd_load_utxo_set(file: &str, force: bool) -> Result<()> {
+    let store = Storage::open_default()?;
+    let params = ChainParams::load_default()?;
+    let mut utxo_set = UTXOSet::new(open_blockchain(&store)?)?;
+    println!("📸 正在从 {} 加载UTXO快照...", file);
+    let stats = load_utxo_set(&mut utxo_set, file, &params, force)?;
+    println!("🧱 区块: {} (高度 {})", stats.block_hash, stats.height);
+    println!("📊 UTXO数量: {}", stats.coins);
+    println!("🔐 UTXO集合哈希: {}", stats.hash);
+    println!("✅ 快照加载完成, 链从这个区块开始");
+    Ok(())
+}
+
+fn cmd_chain_stats(from: Option<i32>, to: Option<i32>, top: usize, json: bool) -> Result<()> {
+    let store = Storage::open_default()?;
+    let utxo_set = UTXOSet::new(open_blockchain(&store)?)?;
+    let stats = get_chain_stats(&utxo_set, from, to, top)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+
+    println!("\n📈 =============== 区块链统计 (高度 {} - {}) =============== 📈\n", stats.from_height, stats.to_height);
+    println!("🧱 区块数量: {}", stats.blocks);
+    if stats.pruned_blocks > 0 {
+        println!("✂️ 已裁剪区块 (不计入交易统计): {}", stats.pruned_blocks);
+    }
+    match stats.avg_block_interval_ms {
+        Some(ms) => println!("⏱️ 平均出块间隔: {:.1} 秒", ms / 1000.0),
+        None => println!("⏱️ 平均出块间隔: -"),
+    }
+    println!("📊 交易: {}  输入: {}  输出: {}", stats.transactions, stats.inputs, stats.outputs);
+
+    println!("\n⛏️ 难度变化:");
+    for w in &stats.difficulty {
+        let interval = match w.avg_block_interval_ms {
+            Some(ms) => format!("{:.1} 秒", ms / 1000.0),
+            None => "-".to_string(),
+        };
+        println!(
+            "   高度 {:>6} - {:<6} 目标 {} 位  平均哈希前导零 {:.1} 位  出块间隔 {}",
+            w.from_height, w.to_height, w.target_bits, w.avg_hash_zero_bits, interval
+        );
+    }
+
+    println!("\n💰 UTXO集合: {} 个币, 总额 {} 币, {} 个地址", stats.utxo.coins, stats.utxo.total_value, stats.utxo.addresses);
+    println!("\n📊 地址余额分布:");
+    for b in &stats.address_distribution {
+        let range = match b.max {
+            Some(max) => format!("{} - {}", b.min, max - 1),
+            None => format!("{} 以上", b.min),
+        };
+        println!("   {:<16} {} 个地址, 共 {} 币", range, b.addresses, b.value);
+    }
+    println!("\n🏆 余额最多的地址:");
+    for (i, h) in stats.top_holders.iter().enumerate() {
+        println!("   {}. {} {} 币 ({} 个币)", i + 1, h.address, h.balance, h.coins);
+    }
+    println!();
+    Ok(())
+}
+
+fn cmd_verify_chain(level: u32, depth: usize) -> Result<bool> {
+    let store = Storage::open_default()?;
+    // 这里不能用UTXOSet::new,它会先把不一致的地方修好
+    let utxo_set = UTXOSet::unrepaired(open_blockchain(&store)?, DEFAULT_DBCACHE_MB);
+    if depth == 0 {
+        println!("🔍 开始校验区块链 (级别 {}, 全部区块)...", level);
+    } else {
+        println!("🔍 开始校验区块链 (级别 {}, 最近 {} 个区块)...", level, depth);
+    }
+
+    let report = verify_chain(&utxo_set, level, depth)?;
+    println!("📊 已检查区块: {}", report.blocks);
+    println!("📊 已检查交易: {}", report.transactions);
+    if report.pruned > 0 {
+        println!("✂️ 已裁剪区块 (只检查区块头): {}", report.pruned);
+    }
+    if level >= 4 {
+        if utxo_set.blockchain.get_prune_height()? > 0 {
+            println!("⚠️ 区块链已裁剪, 无法从区块重建UTXO集合, 跳过比对");
+        } else {
+            println!("📊 已比对UTXO: {}", report.utxos);
+        }
+    }
+    match report.failure {
+        Some(failure) => {
+            println!("❌ 校验失败: {}", failure);
+            Ok(false)
+        }
+        None => {
+            println!("✅ 区块链校验通过!");
+            Ok(true)
+        }
+    }
+}
+
+fn cmd_list_address(show_change: bool) -> Result<()> {
+    let store = Storage::open_default()?;
+    let ws = Wallets::new(&store)?;
+    let addresses = ws.get_addresses(show_change);
+    let change = ws.get_all_addresses().len() - ws.get_addresses(false).len();
     println!("\n👛 =============== 钱包地址列表 =============== 👛");
     for (i, address) in addresses.iter().enumerate() {
-        println!("{}. 📍 {}", i + 1, address);
+        if ws.is_change(address) {
+            println!("{}. 🔁 {} (找零)", i + 1, address);
+        } else {
+            println!("{}. 📍 {}", i + 1, address);
+        }
+    }
+    if !show_change && change > 0 {
+        println!("🙈 另有 {} 个找零地址, 用 --change 显示", change);
+    }
+    let watch_only = ws.get_watch_only_addresses();
+    for (i, address) in watch_only.iter().enumerate() {
+        println!("{}. 👀 {} (只读)", addresses.len() + i + 1, address);
+    }
+    println!("📊 总计: {} 个钱包地址", addresses.len() + watch_only.len());
+    if ws.is_encrypted() {
+        println!("🔒 钱包已加密, 创建地址时要输入密码, 发送交易要先在节点上用 walletpassphrase 解锁");
+    }
+    println!();
+    Ok(())
+}
+
+/// read_passphrase asks for a passphrase, hiding it when stdin is a terminal
+fn read_passphrase(prompt: &str) -> Result<String> {
+    if io::stdin().is_terminal() {
+        return Ok(rpassword::prompt_password(prompt)?);
+    }
+    // 脚本里从管道读一行
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    println!();
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// unlock_wallet asks for the passphrase of an encrypted wallet
+///
+/// The master key only lives in this process, nothing is written to the data directory.
+fn unlock_wallet(ws: &mut Wallets) -> Result<()> {
+    if ws.is_encrypted() {
+        let passphrase = read_passphrase("🔑 请输入钱包密码: ")?;
+        ws.unlock(&passphrase)?;
+    }
+    Ok(())
+}
+
+/// read_new_passphrase asks for a new passphrase twice
+fn read_new_passphrase() -> Result<String> {
+    let passphrase = read_passphrase("🔑 请输入新密码: ")?;
+    if read_passphrase("🔑 请再输入一次: ")? != passphrase {
+        return Err(format_err!("ERROR: The passphrases do not match"));
+    }
+    Ok(passphrase)
+}
+
+fn cmd_encrypt_wallet() -> Result<()> {
+    let store = Storage::open_default()?;
+    let mut ws = Wallets::new(&store)?;
+    if ws.is_encrypted() {
+        return Err(format_err!("ERROR: The wallet is already encrypted, use changepassphrase"));
+    }
+    let passphrase = read_new_passphrase()?;
+    ws.encrypt(&passphrase)?;
+    println!("🔒 钱包已加密, {} 个私钥已用密码保护", ws.get_all_addresses().len());
+    println!("⚠️  之后创建地址时要输入这个密码, 发送交易前先在运行中的节点上用 walletpassphrase <秒数> 解锁");
+    println!("⚠️  加密前备份的data目录里仍然有明文私钥, 请一并删除");
+    Ok(())
+}
+
+fn cmd_wallet_passphrase(timeout: u64, port: &str) -> Result<()> {
+    let passphrase = read_passphrase("🔑 请输入钱包密码: ")?;
+    wallet_passphrase(port, &passphrase, timeout)?;
+    println!("🔓 节点 {} 的钱包已解锁, {} 秒后自动锁定", port, timeout);
+    println!("💡 解锁期间用 send --node {} 发送交易, 密钥只留在节点的内存里", port);
+    Ok(())
+}
+
+fn cmd_wallet_lock(port: &str) -> Result<()> {
+    wallet_lock(port)?;
+    println!("🔒 节点 {} 的钱包已锁定", port);
+    Ok(())
+}
+
+fn cmd_change_passphrase() -> Result<()> {
+    let store = Storage::open_default()?;
+    let mut ws = Wallets::new(&store)?;
+    if !ws.is_encrypted() {
+        return Err(format_err!("ERROR: The wallet is not encrypted, use encryptwallet"));
     }
-    println!("📊 总计: {} 个钱包地址\n", addresses.len());
+    let old = read_passphrase("🔑 请输入旧密码: ")?;
+    let new = read_new_passphrase()?;
+    ws.change_passphrase(&old, &new)?;
+    println!("✅ 钱包密码已修改");
     Ok(())
 }
 
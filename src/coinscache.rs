@@ -0,0 +1,299 @@
+//! in-memory cache in front of the UTXO trees
+//!
+//! Every coin a block looks up or changes goes through the cache. Coins read
+//! from the store stay cached until the cache is flushed for lack of room;
+//! coins a block creates or spends are kept as dirty entries and only written,
+//! together with the `UTXO_BEST` marker, when the cache is flushed. Flushes
+//! only happen between blocks, so the marker always names the block the coins
+//! on disk are up to date with and a node that dies before a flush replays the
+//! missing blocks on the next start, see UTXOSet::repair.
+
+use super::*;
+use crate::storage::*;
+use crate::utxoset::*;
+use bincode::deserialize;
+use failure::format_err;
+use std::collections::HashMap;
+
+/// Default size of the UTXO cache in MiB, see the `--dbcache` option
+pub const DEFAULT_DBCACHE_MB: usize = 64;
+/// Estimated memory use of a cache entry besides its key and public key hash
+const ENTRY_OVERHEAD: usize = 96;
+
+/// CacheStats counts how the UTXO cache was used since it was created
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheStats {
+    /// lookups answered from memory
+    pub hits: u64,
+    /// lookups that had to read the store
+    pub misses: u64,
+    pub flushes: u64,
+    pub entries: usize,
+    /// entries not written to the store yet
+    pub dirty: usize,
+    /// estimated memory use in bytes
+    pub usage: usize,
+    pub limit: usize,
+}
+
+/// CacheEntry is a cached coin and what happened to it since the last flush
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    coin: Coin,
+    spent: bool,
+    /// changed since the last flush
+    dirty: bool,
+    /// created since the last flush, so not in the store
+    fresh: bool,
+}
+
+impl CacheEntry {
+    fn usage(key: &[u8], coin: &Coin) -> usize {
+        key.len() + coin.output.pub_key_hash.len() + ENTRY_OVERHEAD
+    }
+}
+
+/// CoinsCache caches the coins of the UTXO set, keyed by outpoint key
+#[derive(Debug)]
+pub struct CoinsCache {
+    entries: HashMap<Vec<u8>, CacheEntry>,
+    /// block the cached changes bring the UTXO set to, None if nothing is pending
+    best: Option<String>,
+    usage: usize,
+    limit: usize,
+    hits: u64,
+    misses: u64,
+    flushes: u64,
+}
+
+impl CoinsCache {
+    /// NewCoinsCache creates an empty cache that may use about limit bytes
+    pub fn new(limit: usize) -> CoinsCache {
+        CoinsCache {
+            entries: HashMap::new(),
+            best: None,
+            usage: 0,
+            limit,
+            hits: 0,
+            misses: 0,
+            flushes: 0,
+        }
+    }
+
+    /// Get returns the unspent coin at key, reading it from store on a miss
+    pub fn get<S: ChainStore>(&mut self, store: &S, key: &[u8]) -> Result<Option<Coin>> {
+        if let Some(entry) = self.entries.get(key) {
+            self.hits += 1;
+            return Ok(if entry.spent {
+                None
+            } else {
+                Some(entry.coin.clone())
+            });
+        }
+        self.misses += 1;
+        let coin: Coin = match store.get(Tree::Utxos, key)? {
+            Some(v) => deserialize(&v)?,
+            None => return Ok(None),
+        };
+        self.insert(
+            key.to_vec(),
+            CacheEntry {
+                coin: coin.clone(),
+                spent: false,
+                dirty: false,
+                fresh: false,
+            },
+        );
+        Ok(Some(coin))
+    }
+
+    /// Add caches a coin created by a block
+    pub fn add(&mut self, key: Vec<u8>, coin: Coin) {
+        // 之前被花掉但还没落盘的同一个输出,盘上的记录仍然要删
+        let fresh = match self.entries.get(&key) {
+            Some(entry) => entry.fresh,
+            None => true,
+        };
+        self.insert(
+            key,
+            CacheEntry {
+                coin,
+                spent: false,
+                dirty: true,
+                fresh,
+            },
+        );
+    }
+
+    /// Spend marks the coin at key as spent, it must have been looked up with get
+    pub fn spend(&mut self, key: &[u8]) -> Result<()> {
+        let entry = match self.entries.get_mut(key) {
+            Some(entry) if !entry.spent => entry,
+            _ => {
+                return Err(format_err!(
+                    "ERROR: Spending coin {:?} which is not in the UTXO cache",
+                    OutPoint::from_key(key)?
+                ));
+            }
+        };
+        if entry.fresh {
+            // 没落过盘的币直接丢掉就行
+            let coin = self.entries.remove(key).unwrap().coin;
+            self.usage -= CacheEntry::usage(key, &coin);
+        } else {
+            entry.spent = true;
+            entry.dirty = true;
+        }
+        Ok(())
+    }
+
+    /// SetBest records the block the cached changes bring the UTXO set to
+    pub fn set_best(&mut self, block_hash: &str) {
+        self.best = Some(block_hash.to_string());
+    }
+
+    /// Best returns the block of the pending changes, None if there are none
+    pub fn best(&self) -> Option<&str> {
+        self.best.as_deref()
+    }
+
+    /// IsFull tells whether the cache uses more memory than its limit
+    pub fn is_full(&self) -> bool {
+        self.usage > self.limit
+    }
+
+    /// Flush writes the dirty entries and the best block marker to store in one batch
+    ///
+    /// extra is committed in the same batch. Spent entries are dropped; the
+    /// other entries stay cached unless the cache is full, then it is emptied.
+    pub fn flush<S: ChainStore>(&mut self, store: &S, mut extra: Batch) -> Result<()> {
+        let mut written = 0;
+        for (key, entry) in &self.entries {
+            if !entry.dirty {
+                continue;
+            }
+            if entry.spent {
+                remove_coin(&mut extra, key, &entry.coin);
+            } else {
+                insert_coin(&mut extra, key, &entry.coin)?;
+            }
+            written += 1;
+        }
+        if let Some(best) = &self.best {
+            extra.insert(Tree::Metadata, UTXO_BEST_KEY, best);
+        }
+        store.commit(extra)?;
+        store.flush()?;
+        self.flushes += 1;
+        debug!("flushed {} coins of the UTXO cache", written);
+
+        self.best = None;
+        if self.is_full() {
+            self.clear();
+            return Ok(());
+        }
+        let mut usage = 0;
+        self.entries.retain(|key, entry| {
+            entry.dirty = false;
+            entry.fresh = false;
+            if !entry.spent {
+                usage += CacheEntry::usage(key, &entry.coin);
+            }
+            !entry.spent
+        });
+        self.usage = usage;
+        Ok(())
+    }
+
+    /// Clear drops every entry without writing it, for when the store has been changed directly
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.best = None;
+        self.usage = 0;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            flushes: self.flushes,
+            entries: self.entries.len(),
+            dirty: self.entries.values().filter(|e| e.dirty).count(),
+            usage: self.usage,
+            limit: self.limit,
+        }
+    }
+
+    fn insert(&mut self, key: Vec<u8>, entry: CacheEntry) {
+        self.usage += CacheEntry::usage(&key, &entry.coin);
+        if let Some(old) = self.entries.get(&key) {
+            self.usage -= CacheEntry::usage(&key, &old.coin);
+        }
+        self.entries.insert(key, entry);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transaction::*;
+
+    fn coin(value: i32) -> Coin {
+        Coin {
+            output: TXOutput {
+                value,
+                pub_key_hash: vec![7; 20],
+            },
+            height: 1,
+            is_coinbase: false,
+        }
+    }
+
+    #[test]
+    fn test_coins_cache() {
+        let store = MemoryStore::new();
+        let on_disk = OutPoint::new(&"aa".repeat(32), 0).to_key().unwrap();
+        let mut batch = Batch::new();
+        insert_coin(&mut batch, &on_disk, &coin(5)).unwrap();
+        store.commit(batch).unwrap();
+
+        let mut cache = CoinsCache::new(1 << 20);
+        assert_eq!(
+            cache.get(&store, &on_disk).unwrap().unwrap().output.value,
+            5
+        );
+        assert_eq!(
+            cache.get(&store, &on_disk).unwrap().unwrap().output.value,
+            5
+        );
+        cache.spend(&on_disk).unwrap();
+        assert!(cache.get(&store, &on_disk).unwrap().is_none());
+        assert!(cache.spend(&on_disk).is_err());
+
+        // 同一批里创建又花掉的币不会写到盘上
+        let created = OutPoint::new(&"bb".repeat(32), 1).to_key().unwrap();
+        let kept = OutPoint::new(&"cc".repeat(32), 0).to_key().unwrap();
+        cache.add(created.clone(), coin(3));
+        cache.spend(&created).unwrap();
+        cache.add(kept.clone(), coin(4));
+        cache.set_best("block");
+        assert!(store.contains(Tree::Utxos, &on_disk).unwrap());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+        assert_eq!(stats.dirty, 2);
+
+        cache.flush(&store, Batch::new()).unwrap();
+        assert!(!store.contains(Tree::Utxos, &on_disk).unwrap());
+        assert!(!store.contains(Tree::Utxos, &created).unwrap());
+        assert!(store.contains(Tree::Utxos, &kept).unwrap());
+        assert_eq!(
+            store.get(Tree::Metadata, UTXO_BEST_KEY.as_bytes()).unwrap(),
+            Some(b"block".to_vec())
+        );
+        assert_eq!(store.scan_prefix(Tree::Index, &[]).count(), 1);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.dirty, stats.flushes), (1, 0, 1));
+        assert_eq!(stats.usage, CacheEntry::usage(&kept, &coin(4)));
+    }
+}
@@ -0,0 +1,160 @@
+//! passphrase encryption of the wallet secret keys
+//!
+//! Secret keys are encrypted with ChaCha20-Poly1305 under a random master
+//! key. The master key is stored encrypted as well, under a key stretched from
+//! the passphrase with Argon2id, so changing the passphrase only rewrites the
+//! master key and a wrong passphrase is caught by the authentication tag.
+
+use super::*;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+/// Length of the master key and of the keys stretched from passphrases
+pub const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+
+/// KdfParams are the Argon2id costs a passphrase is stretched with
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct KdfParams {
+    /// memory in KiB
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> KdfParams {
+        KdfParams {
+            m_cost: 64 * 1024,
+            t_cost: 3,
+            p_cost: 1,
+        }
+    }
+}
+
+/// Sealed is data encrypted with ChaCha20-Poly1305 together with its nonce
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Sealed {
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+/// MasterKey is the wallet master key encrypted with a passphrase
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MasterKey {
+    salt: Vec<u8>,
+    kdf: KdfParams,
+    sealed: Sealed,
+}
+
+impl MasterKey {
+    /// NewMasterKey creates a random master key and returns it with its encryption under passphrase
+    pub fn new(passphrase: &str, kdf: KdfParams) -> Result<(MasterKey, Vec<u8>)> {
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng).to_vec();
+        Ok((MasterKey::encrypt(&key, passphrase, kdf)?, key))
+    }
+
+    /// Unlock decrypts the master key, a wrong passphrase is an error
+    pub fn unlock(&self, passphrase: &str) -> Result<Vec<u8>> {
+        let stretched = stretch(passphrase, &self.salt, self.kdf)?;
+        match open(&stretched, &self.sealed, &self.salt) {
+            Ok(key) => Ok(key),
+            Err(_) => Err(format_err!("ERROR: The wallet passphrase entered was incorrect")),
+        }
+    }
+
+    /// Kdf returns the parameters the passphrase is stretched with
+    pub fn kdf(&self) -> KdfParams {
+        self.kdf
+    }
+
+    fn encrypt(key: &[u8], passphrase: &str, kdf: KdfParams) -> Result<MasterKey> {
+        let mut salt = vec![0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let stretched = stretch(passphrase, &salt, kdf)?;
+        let sealed = seal(&stretched, key, &salt)?;
+        Ok(MasterKey { salt, kdf, sealed })
+    }
+}
+
+/// Seal encrypts plaintext under key, aad is authenticated but not encrypted
+pub fn seal(key: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Sealed> {
+    let cipher = cipher(key)?;
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let payload = Payload { msg: plaintext, aad };
+    match cipher.encrypt(&nonce, payload) {
+        Ok(ciphertext) => Ok(Sealed {
+            nonce: nonce.to_vec(),
+            ciphertext,
+        }),
+        Err(_) => Err(format_err!("ERROR: Encryption failed")),
+    }
+}
+
+/// Open decrypts sealed data, failing if the key or aad is wrong or the data was changed
+pub fn open(key: &[u8], sealed: &Sealed, aad: &[u8]) -> Result<Vec<u8>> {
+    let cipher = cipher(key)?;
+    if sealed.nonce.len() != 12 {
+        return Err(format_err!("ERROR: Bad nonce length {}", sealed.nonce.len()));
+    }
+    let payload = Payload {
+        msg: &sealed.ciphertext,
+        aad,
+    };
+    match cipher.decrypt(Nonce::from_slice(&sealed.nonce), payload) {
+        Ok(plaintext) => Ok(plaintext),
+        Err(_) => Err(format_err!("ERROR: Decryption failed, wrong key or corrupted data")),
+    }
+}
+
+fn cipher(key: &[u8]) -> Result<ChaCha20Poly1305> {
+    if key.len() != KEY_LEN {
+        return Err(format_err!("ERROR: Bad key length {}", key.len()));
+    }
+    Ok(ChaCha20Poly1305::new(Key::from_slice(key)))
+}
+
+/// stretch derives an encryption key from a passphrase with Argon2id
+fn stretch(passphrase: &str, salt: &[u8], kdf: KdfParams) -> Result<Vec<u8>> {
+    let params = match Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(KEY_LEN)) {
+        Ok(params) => params,
+        Err(e) => return Err(format_err!("ERROR: Bad key derivation parameters: {}", e)),
+    };
+    let mut key = vec![0u8; KEY_LEN];
+    if let Err(e) = Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+    {
+        return Err(format_err!("ERROR: Key derivation failed: {}", e));
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// cheap parameters so the tests don't spend seconds in the KDF
+    const TEST_KDF: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn test_master_key() {
+        let (master, key) = MasterKey::new("correct horse", TEST_KDF).unwrap();
+        assert_eq!(master.unlock("correct horse").unwrap(), key);
+        assert!(master.unlock("wrong horse").is_err());
+
+
+        let mut sealed = seal(&key, b"secret key", b"public key").unwrap();
+        assert_eq!(open(&key, &sealed, b"public key").unwrap(), b"secret key");
+        assert!(open(&key, &sealed, b"other public key").is_err());
+        sealed.ciphertext[0] ^= 1;
+        assert!(open(&key, &sealed, b"public key").is_err());
+    }
+}
@@ -0,0 +1,312 @@
+//! canonical binary encoding of transactions and blocks
+//!
+//! The txid and the block hash are computed over this encoding, so any tool that
+//! implements it can reproduce them byte for byte. It does not depend on bincode
+//! or on the layout of the Rust structs.
+//!
+//! Primitives:
+//! - `int32` / `int64`: little-endian two's complement
+//! - `varint`: Bitcoin CompactSize. `n < 0xfd` is one byte; otherwise a prefix
+//!   `0xfd`, `0xfe` or `0xff` followed by `n` as little-endian u16, u32 or u64.
+//!   Non-minimal encodings are rejected when decoding.
+//! - `bytes`: `varint` length followed by the raw bytes
+//! - `hash`: 32 raw bytes, the hex id decoded. An empty id (the coinbase input's
+//!   previous txid, the genesis block's previous hash) is 32 zero bytes.
+//!
+//! Transaction, version 2:
+//! ```text
+//! int32    version
+//! varint   input count
+//!   hash     previous txid
+//!   int32    previous output index (-1 for coinbase)
+//!   uint8    signature scheme (0 ed25519, 1 ecdsa secp256k1, 2 schnorr secp256k1)
+//!   bytes    data (coinbase message, empty for other inputs)
+//! varint   output count
+//!   int64    value
+//!   bytes    public key hash
+//! varint   witness count (0 for coinbase, one per input otherwise)
+//!   bytes    signature
+//!   bytes    public key
+//! ```
+//! txid = hex(SHA-256(SHA-256(transaction up to the witness count))), hex in
+//! digest byte order. wtxid is the same over the whole encoding; the coinbase
+//! wtxid is 32 zero bytes. Version 1 kept the signature and public key inside
+//! each input, which made txids malleable; it is no longer accepted.
+//!
+//! Input `i` signs SHA-256(SHA-256(transaction up to the witness count ||
+//! int32 i || bytes spent public key hash || int64 spent value)).
+//!
+//! Block header, version 1:
+//! ```text
+//! int32    version
+//! hash     previous block hash
+//! hash     merkle root
+//! int64    timestamp, milliseconds since the unix epoch
+//! int32    target, number of leading zero hex digits of the block hash
+//! int32    nonce
+//! int32    height
+//! ```
+//! block hash = hex(SHA-256(SHA-256(header))). The merkle root is the root of a
+//! complete binary merkle tree (merkle-cbt) over the raw txids, where a parent
+//! is SHA-256(left || right). A full block is the header followed by a `varint`
+//! transaction count and the transactions.
+//!
+//! The first transaction of a block is its coinbase. Its data ends with
+//! `aa21a9ed` followed by the witness commitment: the root of the same kind of
+//! merkle tree over the raw wtxids.
+
+use super::*;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use failure::format_err;
+
+/// Current transaction format version
+pub const TX_VERSION: i32 = 2;
+/// Current block format version
+pub const BLOCK_VERSION: i32 = 1;
+
+/// Encodable types write themselves in the canonical encoding
+pub trait Encodable {
+    fn encode(&self, w: &mut Vec<u8>) -> Result<()>;
+}
+
+/// Decodable types read themselves from the canonical encoding
+pub trait Decodable: Sized {
+    fn decode(r: &mut Reader) -> Result<Self>;
+}
+
+/// Reader walks an encoded buffer
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    pub fn read_slice(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            return Err(format_err!("ERROR: Unexpected end of encoded data"));
+        }
+        let s = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(s)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_slice(1)?[0])
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.read_slice(4)?);
+        Ok(i32::from_le_bytes(b))
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.read_slice(8)?);
+        Ok(i64::from_le_bytes(b))
+    }
+
+    pub fn read_varint(&mut self) -> Result<u64> {
+        let (n, min) = match self.read_u8()? {
+            0xfd => {
+                let mut b = [0; 2];
+                b.copy_from_slice(self.read_slice(2)?);
+                (u16::from_le_bytes(b) as u64, 0xfd)
+            }
+            0xfe => {
+                let mut b = [0; 4];
+                b.copy_from_slice(self.read_slice(4)?);
+                (u32::from_le_bytes(b) as u64, 0x1_0000)
+            }
+            0xff => {
+                let mut b = [0; 8];
+                b.copy_from_slice(self.read_slice(8)?);
+                (u64::from_le_bytes(b), 0x1_0000_0000)
+            }
+            n => return Ok(n as u64),
+        };
+        if n < min {
+            return Err(format_err!("ERROR: Non-canonical varint"));
+        }
+        Ok(n)
+    }
+
+    pub fn read_bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.read_varint()? as usize;
+        Ok(self.read_slice(len)?.to_vec())
+    }
+
+    /// ReadHash reads 32 raw bytes back into a hex id, all zeros meaning an empty id
+    pub fn read_hash(&mut self) -> Result<String> {
+        let b = self.read_slice(32)?;
+        if b.iter().all(|x| *x == 0) {
+            Ok(String::new())
+        } else {
+            Ok(hex::encode(b))
+        }
+    }
+}
+
+pub fn write_i32(w: &mut Vec<u8>, n: i32) {
+    w.extend_from_slice(&n.to_le_bytes());
+}
+
+pub fn write_i64(w: &mut Vec<u8>, n: i64) {
+    w.extend_from_slice(&n.to_le_bytes());
+}
+
+pub fn write_varint(w: &mut Vec<u8>, n: u64) {
+    if n < 0xfd {
+        w.push(n as u8);
+    } else if n <= 0xffff {
+        w.push(0xfd);
+        w.extend_from_slice(&(n as u16).to_le_bytes());
+    } else if n <= 0xffff_ffff {
+        w.push(0xfe);
+        w.extend_from_slice(&(n as u32).to_le_bytes());
+    } else {
+        w.push(0xff);
+        w.extend_from_slice(&n.to_le_bytes());
+    }
+}
+
+pub fn write_bytes(w: &mut Vec<u8>, b: &[u8]) {
+    write_varint(w, b.len() as u64);
+    w.extend_from_slice(b);
+}
+
+/// WriteHash writes a hex id as 32 raw bytes
+pub fn write_hash(w: &mut Vec<u8>, id: &str) -> Result<()> {
+    if id.is_empty() {
+        w.extend_from_slice(&[0; 32]);
+        return Ok(());
+    }
+    let b = hex::decode(id)?;
+    if b.len() != 32 {
+        return Err(format_err!("ERROR: Hash {} is not 32 bytes", id));
+    }
+    w.extend_from_slice(&b);
+    Ok(())
+}
+
+/// Encode returns the canonical encoding of a value
+pub fn encode<T: Encodable>(t: &T) -> Result<Vec<u8>> {
+    let mut w = Vec::new();
+    t.encode(&mut w)?;
+    Ok(w)
+}
+
+/// Decode parses a value and rejects trailing bytes
+pub fn decode<T: Decodable>(data: &[u8]) -> Result<T> {
+    let mut r = Reader::new(data);
+    let t = T::decode(&mut r)?;
+    if !r.is_empty() {
+        return Err(format_err!("ERROR: Trailing bytes after encoded data"));
+    }
+    Ok(t)
+}
+
+/// Sha256d returns SHA-256(SHA-256(data))
+pub fn sha256d(data: &[u8]) -> [u8; 32] {
+    let mut re: [u8; 32] = [0; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    hasher.result(&mut re);
+    let mut hasher = Sha256::new();
+    hasher.input(&re);
+    hasher.result(&mut re);
+    re
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::keys::*;
+    use crate::transaction::*;
+
+    #[test]
+    fn test_varint_vectors() {
+        let vectors: &[(u64, &str)] = &[
+            (0, "00"),
+            (0xfc, "fc"),
+            (0xfd, "fdfd00"),
+            (0xffff, "fdffff"),
+            (0x1_0000, "fe00000100"),
+            (0x1_0000_0000, "ff0000000001000000"),
+        ];
+        for (n, expected) in vectors {
+            let mut w = Vec::new();
+            write_varint(&mut w, *n);
+            assert_eq!(hex::encode(&w), *expected);
+            assert_eq!(Reader::new(&w).read_varint().unwrap(), *n);
+        }
+        assert!(Reader::new(&hex::decode("fd0100").unwrap()).read_varint().is_err());
+    }
+
+    #[test]
+    fn test_transaction_vector() {
+        let mut tx = Transaction {
+            version: TX_VERSION,
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: "11".repeat(32),
+                vout: 1,
+                scheme: SignatureScheme::SchnorrSecp256k1,
+                data: Vec::new(),
+            }],
+            vout: vec![TXOutput {
+                value: 10,
+                pub_key_hash: vec![0xcc; 20],
+            }],
+            witness: Vec::new(),
+        };
+        tx.id = tx.hash().unwrap();
+        let unsigned_id = tx.id.clone();
+        tx.witness = vec![TXWitness {
+            signature: vec![0xaa; 2],
+            pub_key: vec![0xbb; 3],
+        }];
+
+        let data = encode(&tx).unwrap();
+        assert_eq!(
+            hex::encode(&data),
+            concat!(
+                "02000000",
+                "01",
+                "1111111111111111111111111111111111111111111111111111111111111111",
+                "01000000",
+                "02",
+                "00",
+                "01",
+                "0a00000000000000",
+                "14cccccccccccccccccccccccccccccccccccccccc",
+                "01",
+                "02aaaa",
+                "03bbbbbb",
+            )
+        );
+        // 签名不影响txid
+        assert_eq!(tx.hash().unwrap(), unsigned_id);
+        assert_eq!(
+            tx.id,
+            "c335b72d559565df46176fded10451a838d9678fed62af1eb2c2935f1fad2688"
+        );
+        assert_eq!(
+            tx.witness_hash().unwrap(),
+            "10d81fb2e8445df49edf7b30056ade84b1a5498e6bad836919978e6d3d0d870f"
+        );
+
+        let decoded: Transaction = decode(&data).unwrap();
+        assert_eq!(decoded.id, tx.id);
+        assert_eq!(encode(&decoded).unwrap(), data);
+    }
+}
@@ -0,0 +1,170 @@
+//! hierarchical deterministic keys derived from a BIP39 mnemonic
+//!
+//! Every wallet key comes from one seed along the hardened path
+//!
+//! ```text
+//! m/44'/1'/<scheme>'/<chain>'/<index>'
+//! ```
+//!
+//! where scheme is the tag of the signature scheme and chain is
+//! RECEIVE_CHAIN or CHANGE_CHAIN. secp256k1 keys are derived as in BIP32,
+//! Ed25519 keys as in SLIP-0010, which only knows hardened children; the
+//! derived 32 bytes are the key material SignatureScheme::keypair_from_seed
+//! turns into a key pair.
+
+use super::*;
+use crate::keys::*;
+use bip39::Mnemonic;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha512;
+use failure::format_err;
+use rand::Rng;
+use secp256k1::{Scalar, SecretKey};
+
+/// Chain of the addresses handed out to receive coins
+pub const RECEIVE_CHAIN: u32 = 0;
+/// Chain of the addresses change goes back to
+pub const CHANGE_CHAIN: u32 = 1;
+/// Unused addresses in a row after which a restore stops looking on a chain
+pub const GAP_LIMIT: u32 = 20;
+
+const HARDENED: u32 = 0x8000_0000;
+const PURPOSE: u32 = 44;
+const COIN_TYPE: u32 = 1;
+/// Bytes of entropy of a new mnemonic, 12 words
+const MNEMONIC_ENTROPY: usize = 16;
+
+/// NewMnemonic draws a new 12 word mnemonic
+pub fn new_mnemonic() -> Result<Mnemonic> {
+    let mut entropy = [0u8; MNEMONIC_ENTROPY];
+    rand::OsRng::new()?.fill_bytes(&mut entropy);
+    Ok(Mnemonic::from_entropy(&entropy)?)
+}
+
+/// ParseMnemonic checks the words and the checksum of a mnemonic
+pub fn parse_mnemonic(words: &str) -> Result<Mnemonic> {
+    match Mnemonic::parse(words) {
+        Ok(mnemonic) => Ok(mnemonic),
+        Err(e) => Err(format_err!("ERROR: Bad mnemonic: {}", e)),
+    }
+}
+
+/// DerivationPath returns the path of a wallet key
+pub fn derivation_path(scheme: SignatureScheme, chain: u32, index: u32) -> [u32; 5] {
+    [PURPOSE, COIN_TYPE, scheme.to_byte() as u32, chain, index]
+}
+
+/// PathString formats a path the usual way, every step is hardened
+pub fn path_string(path: &[u32]) -> String {
+    let mut s = String::from("m");
+    for step in path {
+        s += &format!("/{}'", step);
+    }
+    s
+}
+
+/// DeriveKeypair returns the (secret_key, public_key) pair at index of chain
+pub fn derive_keypair(
+    seed: &[u8],
+    scheme: SignatureScheme,
+    chain: u32,
+    index: u32,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let key = derive_secret(seed, scheme, &derivation_path(scheme, chain, index))?;
+    scheme.keypair_from_seed(&key)
+}
+
+/// derive_secret walks path from the master key of seed, every step hardened
+fn derive_secret(seed: &[u8], scheme: SignatureScheme, path: &[u32]) -> Result<Vec<u8>> {
+    let curve_key: &[u8] = match scheme {
+        SignatureScheme::Ed25519 => b"ed25519 seed",
+        SignatureScheme::EcdsaSecp256k1 | SignatureScheme::SchnorrSecp256k1 => b"Bitcoin seed",
+    };
+    let (mut key, mut chain_code) = hmac_sha512(curve_key, seed);
+    if scheme != SignatureScheme::Ed25519 {
+        SecretKey::from_slice(&key)?;
+    }
+
+    for &step in path {
+        if step >= HARDENED {
+            return Err(format_err!("ERROR: Bad derivation index {}", step));
+        }
+        let mut data = vec![0u8];
+        data.extend_from_slice(&key);
+        data.extend_from_slice(&(step | HARDENED).to_be_bytes());
+        let (child, child_chain_code) = hmac_sha512(&chain_code, &data);
+        key = match scheme {
+            SignatureScheme::Ed25519 => child,
+            // BIP32: 子私钥 = IL + 父私钥 (mod n)
+            SignatureScheme::EcdsaSecp256k1 | SignatureScheme::SchnorrSecp256k1 => {
+                let tweak = Scalar::from_be_bytes(child.as_slice().try_into()?)?;
+                SecretKey::from_slice(&key)?
+                    .add_tweak(&tweak)?
+                    .secret_bytes()
+                    .to_vec()
+            }
+        };
+        chain_code = child_chain_code;
+    }
+    Ok(key)
+}
+
+/// hmac_sha512 returns the two halves of HMAC-SHA512(key, data)
+fn hmac_sha512(key: &[u8], data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut mac = Hmac::new(Sha512::new(), key);
+    mac.input(data);
+    let out = mac.result();
+    let (left, right) = out.code().split_at(32);
+    (left.to_vec(), right.to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_derive() {
+        // BIP32 和 SLIP-0010 的测试向量1
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let key = derive_secret(&seed, SignatureScheme::EcdsaSecp256k1, &[]).unwrap();
+        assert_eq!(
+            hex::encode(key),
+            "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35"
+        );
+        let key = derive_secret(&seed, SignatureScheme::EcdsaSecp256k1, &[0]).unwrap();
+        assert_eq!(
+            hex::encode(key),
+            "edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea"
+        );
+        let key = derive_secret(&seed, SignatureScheme::Ed25519, &[]).unwrap();
+        assert_eq!(
+            hex::encode(key),
+            "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7"
+        );
+        let key = derive_secret(&seed, SignatureScheme::Ed25519, &[0]).unwrap();
+        assert_eq!(
+            hex::encode(key),
+            "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3"
+        );
+
+        let mnemonic = new_mnemonic().unwrap();
+        assert_eq!(mnemonic.word_count(), 12);
+        let parsed = parse_mnemonic(&mnemonic.to_string()).unwrap();
+        let seed = parsed.to_seed("");
+        assert_eq!(seed, mnemonic.to_seed(""));
+        assert!(parse_mnemonic("abandon abandon abandon").is_err());
+
+        let receive = derive_keypair(&seed, SignatureScheme::SchnorrSecp256k1, RECEIVE_CHAIN, 0).unwrap();
+        let change = derive_keypair(&seed, SignatureScheme::SchnorrSecp256k1, CHANGE_CHAIN, 0).unwrap();
+        assert_ne!(receive, change);
+        assert_eq!(
+            receive,
+            derive_keypair(&seed, SignatureScheme::SchnorrSecp256k1, RECEIVE_CHAIN, 0).unwrap()
+        );
+        assert_eq!(
+            path_string(&derivation_path(SignatureScheme::EcdsaSecp256k1, CHANGE_CHAIN, 7)),
+            "m/44'/1'/1'/1'/7'"
+        );
+    }
+}
@@ -0,0 +1,238 @@
+//! signature schemes supported by wallets and transaction inputs
+
+use super::*;
+use crypto::digest::Digest;
+use crypto::ed25519;
+use crypto::sha2::Sha256;
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::scalar::Scalar;
+use failure::format_err;
+use secp256k1::{
+    Keypair, Message, PublicKey, Secp256k1, SecretKey, XOnlyPublicKey, ecdsa, schnorr,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// SignatureScheme names the key type and signature algorithm of a wallet or input
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum SignatureScheme {
+    #[default]
+    Ed25519,
+    /// ECDSA over secp256k1 with compact (r, s) signatures and compressed public keys
+    EcdsaSecp256k1,
+    /// BIP340 Schnorr over secp256k1 with x-only public keys
+    SchnorrSecp256k1,
+}
+
+/// Signer produces signatures with a secret key it holds
+pub trait Signer {
+    fn scheme(&self) -> SignatureScheme;
+    fn public_key(&self) -> Vec<u8>;
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Verifier checks a signature against a public key
+pub trait Verifier {
+    fn verify(&self, message: &[u8], public_key: &[u8], signature: &[u8]) -> bool;
+}
+
+impl SignatureScheme {
+    pub const ALL: [SignatureScheme; 3] = [
+        SignatureScheme::Ed25519,
+        SignatureScheme::EcdsaSecp256k1,
+        SignatureScheme::SchnorrSecp256k1,
+    ];
+
+    /// ToByte returns the tag of this scheme in the canonical encoding
+    pub fn to_byte(self) -> u8 {
+        match self {
+            SignatureScheme::Ed25519 => 0,
+            SignatureScheme::EcdsaSecp256k1 => 1,
+            SignatureScheme::SchnorrSecp256k1 => 2,
+        }
+    }
+
+    pub fn from_byte(b: u8) -> Result<SignatureScheme> {
+        match b {
+            0 => Ok(SignatureScheme::Ed25519),
+            1 => Ok(SignatureScheme::EcdsaSecp256k1),
+            2 => Ok(SignatureScheme::SchnorrSecp256k1),
+            _ => Err(format_err!("ERROR: Unknown signature scheme tag {}", b)),
+        }
+    }
+
+    /// GenerateKeypair returns a fresh (secret_key, public_key) pair for this scheme
+    ///
+    /// Wallets derive their keys from the HD seed, see the hdkey module.
+    #[cfg(test)]
+    pub fn generate_keypair(&self) -> (Vec<u8>, Vec<u8>) {
+        use rand::Rng;
+        let mut key: [u8; 32] = [0; 32];
+        let mut rand = rand::OsRng::new().unwrap();
+        loop {
+            rand.fill_bytes(&mut key);
+            if let Ok(pair) = self.keypair_from_seed(&key) {
+                return pair;
+            }
+        }
+    }
+
+    /// KeypairFromSeed derives a (secret_key, public_key) pair from 32 bytes of key material
+    pub fn keypair_from_seed(&self, seed: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        match self {
+            SignatureScheme::Ed25519 => {
+                let (secret_key, public_key) = ed25519::keypair(seed);
+                Ok((secret_key.to_vec(), public_key.to_vec()))
+            }
+            SignatureScheme::EcdsaSecp256k1 => {
+                let secp = Secp256k1::signing_only();
+                let sk = SecretKey::from_slice(seed)?;
+                let pk = PublicKey::from_secret_key(&secp, &sk);
+                Ok((sk.secret_bytes().to_vec(), pk.serialize().to_vec()))
+            }
+            SignatureScheme::SchnorrSecp256k1 => {
+                let secp = Secp256k1::signing_only();
+                let sk = SecretKey::from_slice(seed)?;
+                let (pk, _) = XOnlyPublicKey::from_keypair(&Keypair::from_secret_key(&secp, &sk));
+                Ok((sk.secret_bytes().to_vec(), pk.serialize().to_vec()))
+            }
+        }
+    }
+
+    /// SignWith signs message with a secret key of this scheme
+    pub fn sign_with(&self, secret_key: &[u8], message: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            SignatureScheme::Ed25519 => {
+                if secret_key.len() != 64 {
+                    return Err(format_err!("ERROR: Invalid ed25519 secret key"));
+                }
+                Ok(ed25519::signature(message, secret_key).to_vec())
+            }
+            SignatureScheme::EcdsaSecp256k1 => {
+                let secp = Secp256k1::signing_only();
+                let sk = SecretKey::from_slice(secret_key)?;
+                let sig = secp.sign_ecdsa(&digest(message), &sk);
+                Ok(sig.serialize_compact().to_vec())
+            }
+            SignatureScheme::SchnorrSecp256k1 => {
+                let secp = Secp256k1::signing_only();
+                let keypair = Keypair::from_seckey_slice(&secp, secret_key)?;
+                let sig = secp.sign_schnorr_no_aux_rand(&digest(message), &keypair);
+                Ok(sig.serialize().to_vec())
+            }
+        }
+    }
+}
+
+impl Verifier for SignatureScheme {
+    fn verify(&self, message: &[u8], public_key: &[u8], signature: &[u8]) -> bool {
+        match self {
+            SignatureScheme::Ed25519 => match ed25519_parse(public_key, signature) {
+                Some((pk, sig)) => pk.verify_strict(message, &sig).is_ok(),
+                None => false,
+            },
+            SignatureScheme::EcdsaSecp256k1 => {
+                let secp = Secp256k1::verification_only();
+                match (
+                    PublicKey::from_slice(public_key),
+                    ecdsa::Signature::from_compact(signature),
+                ) {
+                    (Ok(pk), Ok(sig)) => secp.verify_ecdsa(&digest(message), &sig, &pk).is_ok(),
+                    _ => false,
+                }
+            }
+            SignatureScheme::SchnorrSecp256k1 => {
+                let secp = Secp256k1::verification_only();
+                match (
+                    XOnlyPublicKey::from_slice(public_key),
+                    schnorr::Signature::from_slice(signature),
+                ) {
+                    (Ok(pk), Ok(sig)) => secp.verify_schnorr(&sig, &digest(message), &pk).is_ok(),
+                    _ => false,
+                }
+            }
+        }
+    }
+}
+
+/// ed25519_parse decodes an ed25519 public key and signature for verification
+///
+/// Only a canonical S and an R and A of the prime order subgroup are taken,
+/// so a signature is valid or not the same way whether it is verified alone
+/// or in a batch, see sigcheck::verify_batch.
+pub fn ed25519_parse(
+    public_key: &[u8],
+    signature: &[u8],
+) -> Option<(ed25519_dalek::PublicKey, ed25519_dalek::Signature)> {
+    if public_key.len() != 32 || signature.len() != 64 {
+        return None;
+    }
+    let mut s = [0u8; 32];
+    s.copy_from_slice(&signature[32..]);
+    Scalar::from_canonical_bytes(s)?;
+    for point in [&signature[..32], public_key] {
+        let point = CompressedEdwardsY::from_slice(point).decompress()?;
+        // 小阶点和带挠部分的点在单个验证和批量验证里结果可能不一样
+        if point.is_small_order() || !point.is_torsion_free() {
+            return None;
+        }
+    }
+    let pk = ed25519_dalek::PublicKey::from_bytes(public_key).ok()?;
+    let sig = ed25519_dalek::Signature::from_bytes(signature).ok()?;
+    Some((pk, sig))
+}
+
+/// digest hashes an arbitrary message down to the 32 bytes secp256k1 signs
+fn digest(message: &[u8]) -> Message {
+    let mut hasher = Sha256::new();
+    hasher.input(message);
+    let mut re: [u8; 32] = [0; 32];
+    hasher.result(&mut re);
+    Message::from_digest(re)
+}
+
+impl FromStr for SignatureScheme {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ed25519" => Ok(SignatureScheme::Ed25519),
+            "ecdsa" => Ok(SignatureScheme::EcdsaSecp256k1),
+            "schnorr" => Ok(SignatureScheme::SchnorrSecp256k1),
+            _ => Err(format_err!(
+                "Unknown signature scheme '{}', expected ed25519, ecdsa or schnorr",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SignatureScheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureScheme::Ed25519 => write!(f, "ed25519"),
+            SignatureScheme::EcdsaSecp256k1 => write!(f, "ecdsa"),
+            SignatureScheme::SchnorrSecp256k1 => write!(f, "schnorr"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sign_verify_all_schemes() {
+        for scheme in &[
+            SignatureScheme::Ed25519,
+            SignatureScheme::EcdsaSecp256k1,
+            SignatureScheme::SchnorrSecp256k1,
+        ] {
+            let (secret_key, public_key) = scheme.generate_keypair();
+            let sig = scheme.sign_with(&secret_key, b"message").unwrap();
+            assert!(scheme.verify(b"message", &public_key, &sig));
+            assert!(!scheme.verify(b"other message", &public_key, &sig));
+        }
+    }
+}
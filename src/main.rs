@@ -1,10 +1,26 @@
 #![allow(non_snake_case)]
 mod block;
 mod blockchain;
+mod bootstrap;
+mod chainparams;
+mod chainstats;
 mod cli;
+mod coinscache;
+mod crypter;
+mod encoding;
+mod hdkey;
+mod keys;
+mod mempool;
+mod schema;
 mod transaction;
 mod utxoset;
+mod verify;
 mod server;
+mod sigcheck;
+mod snapshot;
+mod storage;
+#[cfg(test)]
+mod testutil;
 mod wallets;
 
 #[macro_use]
@@ -0,0 +1,447 @@
+//! mempool of unconfirmed transactions
+
+use super::*;
+use crate::block::*;
+use crate::storage::*;
+use crate::transaction::*;
+use crate::utxoset::*;
+use bincode::serialized_size;
+use failure::format_err;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+/// Maximum number of unconfirmed ancestors a mempool transaction may have
+pub const MAX_ANCESTORS: usize = 25;
+
+/// MempoolEntry keeps an unconfirmed transaction with its fee and dependencies
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    pub fee: i32,
+    pub size: u64,
+    parents: HashSet<String>,
+    children: HashSet<String>,
+}
+
+/// Mempool keeps unconfirmed transactions and the parent/child links between them
+#[derive(Debug, Default)]
+pub struct Mempool {
+    entries: HashMap<String, MempoolEntry>,
+}
+
+impl Mempool {
+    pub fn new() -> Mempool {
+        Mempool {
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, txid: &str) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &str) -> Option<&Transaction> {
+        self.entries.get(txid).map(|e| &e.tx)
+    }
+
+    /// Transactions returns all mempool transactions keyed by txid
+    pub fn transactions(&self) -> HashMap<String, Transaction> {
+        self.entries
+            .iter()
+            .map(|(id, e)| (id.clone(), e.tx.clone()))
+            .collect()
+    }
+
+    /// Add verifies a transaction against the UTXO set and the mempool and stores it
+    ///
+    /// Every input must spend an unspent coin or an output of another mempool
+    /// transaction; those transactions become its parents.
+    pub fn add<S: ChainStore>(&mut self, tx: Transaction, utxo: &UTXOSet<S>) -> Result<()> {
+        if tx.is_coinbase() {
+            return Err(format_err!(
+                "ERROR: Coinbase transaction can't enter the mempool"
+            ));
+        }
+        if tx.id != tx.hash()? {
+            return Err(format_err!("ERROR: Transaction id {} doesn't match the transaction", tx.id));
+        }
+        if self.contains(&tx.id) {
+            return Ok(());
+        }
+        let conflicts = self.find_conflicts(&tx);
+        if !conflicts.is_empty() {
+            return Err(format_err!(
+                "ERROR: Transaction {} double spends mempool transaction {}",
+                tx.id,
+                conflicts.join(", ")
+            ));
+        }
+
+        let pending = self.transactions();
+        // 交易索引里找得到的输出可能早就花掉了,要到UTXO集合里查
+        let mut input_value: i32 = 0;
+        for vin in &tx.vin {
+            let value = match pending.get(&vin.txid) {
+                Some(parent) => parent.vout.get(vin.vout as usize).map(|out| out.value),
+                None => utxo
+                    .get_coin(&OutPoint::new(&vin.txid, vin.vout))?
+                    .map(|coin| coin.output.value),
+            };
+            let value = match value {
+                Some(value) => value,
+                None => {
+                    return Err(format_err!(
+                        "ERROR: Transaction {} spends missing or spent output {}:{}",
+                        tx.id,
+                        vin.txid,
+                        vin.vout
+                    ));
+                }
+            };
+            input_value = match input_value.checked_add(value) {
+                Some(sum) => sum,
+                None => return Err(format_err!("ERROR: Transaction {} input value overflows", tx.id)),
+            };
+        }
+        let mut output_value: i32 = 0;
+        for out in &tx.vout {
+            output_value = match output_value.checked_add(out.value) {
+                Some(sum) if out.value >= 0 => sum,
+                _ => {
+                    return Err(format_err!(
+                        "ERROR: Transaction {} has a negative or overflowing output value",
+                        tx.id
+                    ));
+                }
+            };
+        }
+
+        if !utxo.blockchain.verify_transacton_with(&tx, &pending)? {
+            return Err(format_err!("ERROR: Invalid transaction"));
+        }
+        if output_value > input_value {
+            return Err(format_err!(
+                "ERROR: Transaction {} spends {} but only has {}",
+                tx.id,
+                output_value,
+                input_value
+            ));
+        }
+
+        self.add_entry(tx, input_value - output_value)
+    }
+
+    /// add_entry links an already verified transaction into the mempool
+    fn add_entry(&mut self, tx: Transaction, fee: i32) -> Result<()> {
+        let parents: HashSet<String> = tx
+            .vin
+            .iter()
+            .filter(|vin| self.entries.contains_key(&vin.txid))
+            .map(|vin| vin.txid.clone())
+            .collect();
+
+        let mut ancestors = HashSet::new();
+        for parent in &parents {
+            ancestors.insert(parent.clone());
+            ancestors.extend(self.ancestors(parent));
+        }
+        if ancestors.len() > MAX_ANCESTORS {
+            return Err(format_err!(
+                "ERROR: Transaction {} has too many unconfirmed ancestors ({} > {})",
+                tx.id,
+                ancestors.len(),
+                MAX_ANCESTORS
+            ));
+        }
+
+        for parent in &parents {
+            if let Some(e) = self.entries.get_mut(parent) {
+                e.children.insert(tx.id.clone());
+            }
+        }
+        let size = serialized_size(&tx)?;
+        info!(
+            "mempool accept tx: {} fee: {} size: {} parents: {}",
+            tx.id,
+            fee,
+            size,
+            parents.len()
+        );
+        self.entries.insert(
+            tx.id.clone(),
+            MempoolEntry {
+                tx,
+                fee,
+                size,
+                parents,
+                children: HashSet::new(),
+            },
+        );
+        Ok(())
+    }
+
+    /// find_conflicts returns every mempool transaction already spending one of tx's inputs
+    fn find_conflicts(&self, tx: &Transaction) -> Vec<String> {
+        let mut conflicts: Vec<String> = self
+            .entries
+            .values()
+            .filter(|e| {
+                e.tx.vin.iter().any(|vin| {
+                    tx.vin
+                        .iter()
+                        .any(|other| other.txid == vin.txid && other.vout == vin.vout)
+                })
+            })
+            .map(|e| e.tx.id.clone())
+            .collect();
+        conflicts.sort();
+        conflicts
+    }
+
+    /// Ancestors returns all unconfirmed ancestors of a mempool transaction
+    pub fn ancestors(&self, txid: &str) -> HashSet<String> {
+        self.walk(txid, |e| &e.parents)
+    }
+
+    /// Descendants returns all mempool transactions that depend on txid
+    pub fn descendants(&self, txid: &str) -> HashSet<String> {
+        self.walk(txid, |e| &e.children)
+    }
+
+    fn walk<F>(&self, txid: &str, next: F) -> HashSet<String>
+    where
+        F: Fn(&MempoolEntry) -> &HashSet<String>,
+    {
+        let mut found = HashSet::new();
+        let mut stack = vec![txid.to_string()];
+        while let Some(id) = stack.pop() {
+            if let Some(e) = self.entries.get(&id) {
+                for n in next(e) {
+                    if found.insert(n.clone()) {
+                        stack.push(n.clone());
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    /// package sums the fee and size of txid and its ancestors, skipping those in `exclude`
+    fn package(&self, txid: &str, exclude: &HashSet<String>) -> (i64, u64) {
+        let mut fee = 0;
+        let mut size = 0;
+        let mut members = self.ancestors(txid);
+        members.insert(txid.to_string());
+        for id in members.difference(exclude) {
+            if let Some(e) = self.entries.get(id) {
+                fee += e.fee as i64;
+                size += e.size;
+            }
+        }
+        (fee, size)
+    }
+
+    /// BlockTemplate orders mempool transactions for the next block
+    ///
+    /// Transactions are picked by ancestor fee rate, so a high-fee child pulls its
+    /// low-fee parents in with it (child-pays-for-parent). Parents always come before
+    /// their children in the returned list.
+    pub fn block_template(&self) -> Vec<Transaction> {
+        let mut selected: HashSet<String> = HashSet::new();
+        let mut template = Vec::new();
+
+        while selected.len() < self.entries.len() {
+            let best = self
+                .entries
+                .keys()
+                .filter(|id| !selected.contains(*id))
+                .map(|id| (id, self.package(id, &selected)))
+                .max_by(|(a_id, a), (b_id, b)| {
+                    compare_fee_rate(*a, *b).then_with(|| b_id.cmp(a_id))
+                });
+            let best = match best {
+                Some((id, _)) => id.clone(),
+                None => break,
+            };
+
+            let mut package: Vec<String> = self
+                .ancestors(&best)
+                .into_iter()
+                .filter(|id| !selected.contains(id))
+                .collect();
+            package.push(best);
+            for id in self.sort_topological(package) {
+                template.push(self.entries[&id].tx.clone());
+                selected.insert(id);
+            }
+        }
+        template
+    }
+
+    /// sort_topological orders a set of mempool txids so parents come first
+    fn sort_topological(&self, mut ids: Vec<String>) -> Vec<String> {
+        ids.sort_by_key(|id| (self.ancestors(id).len(), id.clone()));
+        ids
+    }
+
+    /// RemoveForBlock drops transactions confirmed by block, and any that conflict with it
+    pub fn remove_for_block(&mut self, block: &Block) {
+        let mut to_remove = HashSet::new();
+        for tx in block.get_transaction() {
+            if self.entries.contains_key(&tx.id) {
+                to_remove.insert(tx.id.clone());
+            } else {
+                // 一笔交易的多个输入可能和不同的内存池交易冲突
+                for conflict in self.find_conflicts(tx) {
+                    to_remove.extend(self.descendants(&conflict));
+                    to_remove.insert(conflict);
+                }
+            }
+        }
+        for id in to_remove {
+            self.remove(&id);
+        }
+    }
+
+    /// Evict drops a transaction and every mempool transaction that depends on it
+    pub fn evict(&mut self, txid: &str) {
+        for id in self.descendants(txid) {
+            self.remove(&id);
+        }
+        self.remove(txid);
+    }
+
+    fn remove(&mut self, txid: &str) {
+        if let Some(e) = self.entries.remove(txid) {
+            for parent in &e.parents {
+                if let Some(p) = self.entries.get_mut(parent) {
+                    p.children.remove(txid);
+                }
+            }
+            for child in &e.children {
+                if let Some(c) = self.entries.get_mut(child) {
+                    c.parents.remove(txid);
+                }
+            }
+        }
+    }
+}
+
+/// compare_fee_rate compares two (fee, size) packages by fee per byte
+fn compare_fee_rate(a: (i64, u64), b: (i64, u64)) -> Ordering {
+    (a.0 as i128 * b.1 as i128).cmp(&(b.0 as i128 * a.1 as i128))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encoding::*;
+    use crate::keys::*;
+    use crate::testutil::*;
+
+    fn spend(txid: &str, vout: i32, value: i32) -> Transaction {
+        let mut tx = Transaction {
+            version: TX_VERSION,
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: txid.to_string(),
+                vout,
+                scheme: SignatureScheme::default(),
+                data: Vec::new(),
+            }],
+            vout: vec![TXOutput {
+                value,
+                pub_key_hash: vec![0; 20],
+            }],
+            witness: Vec::new(),
+        };
+        tx.id = tx.hash().unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_child_pays_for_parent() {
+        let mut pool = Mempool::new();
+        let parent = spend(&"aa".repeat(32), 0, 10);
+        let child = spend(&parent.id, 0, 10);
+        let other = spend(&"bb".repeat(32), 0, 10);
+        pool.add_entry(parent.clone(), 0).unwrap();
+        pool.add_entry(child.clone(), 50).unwrap();
+        pool.add_entry(other.clone(), 1).unwrap();
+
+        assert!(pool.ancestors(&child.id).contains(&parent.id));
+        assert!(pool.descendants(&parent.id).contains(&child.id));
+
+        let ids: Vec<String> = pool.block_template().into_iter().map(|tx| tx.id).collect();
+        assert_eq!(
+            ids,
+            vec![parent.id.clone(), child.id.clone(), other.id.clone()]
+        );
+
+        let block_tx = spend(&"aa".repeat(32), 0, 9);
+        assert_eq!(pool.find_conflicts(&block_tx), vec![parent.id.clone()]);
+
+        // 花掉两个输入的交易和两笔内存池交易都冲突
+        let mut both = spend(&"aa".repeat(32), 0, 9);
+        both.vin.push(TXInput {
+            txid: "bb".repeat(32),
+            vout: 0,
+            scheme: SignatureScheme::default(),
+            data: Vec::new(),
+        });
+        let mut expected = vec![parent.id.clone(), other.id.clone()];
+        expected.sort();
+        assert_eq!(pool.find_conflicts(&both), expected);
+
+        // 挖矿失败时把交易和依赖它的交易一起移出
+        pool.evict(&parent.id);
+        assert!(!pool.contains(&parent.id) && !pool.contains(&child.id));
+        assert!(pool.contains(&other.id));
+    }
+
+    #[test]
+    fn test_add_checks_coins() {
+        let mut chain = TestChain::new();
+        let miner = chain.miner.clone();
+        let to = chain.address();
+        let wallet = chain.wallets.get_wallet(&miner).unwrap();
+        let paid = chain.send(&miner, &to, 3);
+        let double = chain.send(&miner, &to, 4);
+        let mut pool = Mempool::new();
+        pool.add(paid.clone(), &chain.utxo).unwrap();
+        let err = pool.add(double.clone(), &chain.utxo).unwrap_err().to_string();
+        assert!(err.contains("double spends"), "{}", err);
+
+        // 链上已经花掉的币,交易索引里还找得到
+        chain.mine(vec![paid.clone()]);
+        let mut pool = Mempool::new();
+        let err = pool.add(double, &chain.utxo).unwrap_err().to_string();
+        assert!(err.contains("missing or spent"), "{}", err);
+
+        // 负数输出不能把另一个输出抬高
+        let mut forge = |values: [i32; 2]| {
+            let mut tx = chain.send(&miner, &to, 3);
+            tx.vout[0].value = values[0];
+            tx.vout[1].value = values[1];
+            tx.id = tx.hash().unwrap();
+            chain.utxo.blockchain.sign_transacton(&mut tx, &wallet).unwrap();
+            tx
+        };
+        let negative = forge([-5, SUBSIDY + 5]);
+        let overflow = forge([i32::MAX, 1]);
+        let err = pool.add(negative, &chain.utxo).unwrap_err().to_string();
+        assert!(err.contains("negative"), "{}", err);
+        let err = pool.add(overflow, &chain.utxo).unwrap_err().to_string();
+        assert!(err.contains("overflowing"), "{}", err);
+        assert!(pool.is_empty());
+        let mut forged = chain.send(&miner, &to, 3);
+        forged.id = "ab".repeat(32);
+        let err = pool.add(forged, &chain.utxo).unwrap_err().to_string();
+        assert!(err.contains("doesn't match"), "{}", err);
+        pool.add(chain.send(&miner, &to, 3), &chain.utxo).unwrap();
+    }
+}
@@ -0,0 +1,480 @@
+//! schema versions of the stored data and the migrations between them
+//!
+//! Blocks, coins and wallets are stored as bincode dumps of their structs, so
+//! changing one of those structs changes the layout on disk. Every store keeps
+//! the version of the layout it was written with:
+//!
+//! - the chain trees: `SCHEMA_VERSION` in the metadata tree
+//! - the wallets: `$schema` in the wallets tree, `$` is not in the base58
+//!   alphabet so the key never clashes with an address
+//!
+//! Opening a store upgrades an older layout one version at a time and refuses
+//! a layout newer than the binary knows. A store without the key was written
+//! before versioning and is version 0.
+//!
+//! Before DATA_DIR the wallets and the blocks lived in databases of their own,
+//! `data/wallets` and `data/blocks`. Storage::open_default imports them once
+//! and renames the old directory to `*.imported`.
+//!
+//! ```text
+//! chain    0  unversioned, the height, txid and undo indexes may be missing
+//!          1  blocks with segregated witnesses, coins keyed by outpoint,
+//!             height, txid and undo indexes
+//! wallets  0  unversioned, may hold Ed25519 wallets without a scheme
+//!          1  Wallet with its signature scheme
+//!          2  StoredWallet, the secret key may be encrypted under the
+//!             master key kept in `$master`
+//!          3  wallets derived from the HD seed kept in `$seed`
+//!          4  watch-only addresses in `$watch`, the transaction ledger in
+//!             `$tx:<txid>` up to date with the block in `$txbest`
+//!          5  the master key of an unlocked wallet is no longer kept in
+//!             `$unlock`
+//! ```
+
+use super::*;
+use crate::block::*;
+use crate::blockchain::*;
+use crate::keys::*;
+use crate::storage::*;
+use crate::utxoset::*;
+use crate::wallets::*;
+use bincode::{deserialize, serialize};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Schema version of the chain trees this binary writes
+pub const CHAIN_SCHEMA_VERSION: u32 = 1;
+/// Schema version of the wallets this binary writes
+pub const WALLET_SCHEMA_VERSION: u32 = 5;
+/// Key of the chain schema version in the metadata tree
+pub const SCHEMA_VERSION_KEY: &str = "SCHEMA_VERSION";
+/// Key of the wallet schema version in the wallets tree
+pub const WALLET_SCHEMA_KEY: &str = "$schema";
+/// Database the wallets were kept in before DATA_DIR
+pub const LEGACY_WALLETS_DIR: &str = "data/wallets";
+/// Database the blocks were kept in before DATA_DIR
+pub const LEGACY_BLOCKS_DIR: &str = "data/blocks";
+/// Key walletpassphrase kept the plain master key of an unlocked wallet under before version 5
+const LEGACY_UNLOCK_KEY: &str = "$unlock";
+/// Scratch tree the legacy wallets are migrated in before they are merged
+const WALLET_IMPORT_TREE: &str = "wallets_import";
+
+/// LegacyWallet is a wallet of the unversioned layout, always an Ed25519 key pair
+#[derive(Serialize, Deserialize)]
+struct LegacyWallet {
+    public_key: Vec<u8>,
+    secret_key: Vec<u8>,
+}
+
+/// ChainSchemaVersion returns the schema version of the chain trees, None for an empty store
+pub fn chain_schema_version<S: ChainStore>(store: &S) -> Result<Option<u32>> {
+    if let Some(v) = store.get(Tree::Metadata, SCHEMA_VERSION_KEY.as_bytes())? {
+        return Ok(Some(deserialize(&v)?));
+    }
+    let empty = !store.contains(Tree::Metadata, LAST_KEY.as_bytes())?
+        && store.scan_prefix(Tree::Blocks, &[]).next().is_none();
+    Ok(if empty { None } else { Some(0) })
+}
+
+/// SetChainSchemaVersion adds the write of the chain schema version to batch
+pub fn set_chain_schema_version(batch: &mut Batch, version: u32) -> Result<()> {
+    batch.insert(Tree::Metadata, SCHEMA_VERSION_KEY, serialize(&version)?);
+    Ok(())
+}
+
+/// UpgradeChain brings the chain trees of bc to CHAIN_SCHEMA_VERSION
+pub fn upgrade_chain<S: ChainStore>(bc: &Blockchain<S>) -> Result<()> {
+    let store = &bc.store;
+    let mut version = match chain_schema_version(store)? {
+        Some(v) => v,
+        None => {
+            let mut batch = Batch::new();
+            set_chain_schema_version(&mut batch, CHAIN_SCHEMA_VERSION)?;
+            return store.commit(batch);
+        }
+    };
+    check_supported("chain", version, CHAIN_SCHEMA_VERSION)?;
+
+    while version < CHAIN_SCHEMA_VERSION {
+        warn!("upgrading the chain data from schema version {}", version);
+        match version {
+            0 => migrate_chain_v0(bc)?,
+            _ => unreachable!(),
+        }
+        version += 1;
+        let mut batch = Batch::new();
+        set_chain_schema_version(&mut batch, version)?;
+        store.commit(batch)?;
+    }
+    store.flush()
+}
+
+/// migrate_chain_v0 checks that the blocks are still readable and rebuilds the indexes
+///
+/// The unversioned layout may predate the height, txid and undo indexes, so
+/// the height index is rebuilt and the UTXO set is marked stale; the next
+/// UTXOSet::new reindexes it together with the txid index and undo data. A
+/// pruned chain already has all of them.
+fn migrate_chain_v0<S: ChainStore>(bc: &Blockchain<S>) -> Result<()> {
+    let store = &bc.store;
+    for kv in store.scan_prefix(Tree::Blocks, &[]) {
+        let (key, value) = kv?;
+        if let Err(e) = deserialize::<Block>(&value) {
+            return Err(format_err!(
+                "ERROR: Block {} was written in an older layout this binary can't read ({}), \
+                 delete the data directory and sync or import the chain again",
+                String::from_utf8_lossy(&key),
+                e
+            ));
+        }
+    }
+    for kv in store.scan_prefix(Tree::Headers, &[]) {
+        let (key, value) = kv?;
+        if let Err(e) = deserialize::<BlockHeader>(&value) {
+            return Err(format_err!(
+                "ERROR: Header {} can't be read ({})",
+                String::from_utf8_lossy(&key),
+                e
+            ));
+        }
+    }
+
+    if bc.get_prune_height()? == 0 && !bc.tip.is_empty() {
+        bc.reindex_heights()?;
+        let mut batch = Batch::new();
+        batch.remove(Tree::Metadata, UTXO_BEST_KEY);
+        store.commit(batch)?;
+    }
+    Ok(())
+}
+
+/// UpgradeWallets brings the wallets tree to WALLET_SCHEMA_VERSION
+pub fn upgrade_wallets(tree: &sled::Tree) -> Result<()> {
+    let mut version = match tree.get(WALLET_SCHEMA_KEY)? {
+        Some(v) => deserialize(&v)?,
+        None if tree.is_empty() => WALLET_SCHEMA_VERSION,
+        None => 0,
+    };
+    check_supported("wallet", version, WALLET_SCHEMA_VERSION)?;
+
+    while version < WALLET_SCHEMA_VERSION {
+        warn!("upgrading the wallets from schema version {}", version);
+        // 每一步的改动和新的版本号放在同一个batch里,中途崩溃不会留下一半新一半旧的钱包
+        let mut batch = match version {
+            0 => migrate_wallets_v0(tree)?,
+            1 => migrate_wallets_v1(tree)?,
+            // 只是多了派生钱包和$seed,已有的数据不用改
+            2 => sled::Batch::default(),
+            3 => migrate_wallets_v3(tree)?,
+            4 => migrate_wallets_v4(),
+            _ => unreachable!(),
+        };
+        version += 1;
+        batch.insert(WALLET_SCHEMA_KEY, serialize(&version)?);
+        tree.apply_batch(batch)?;
+    }
+    if !tree.contains_key(WALLET_SCHEMA_KEY)? {
+        tree.insert(WALLET_SCHEMA_KEY, serialize(&version)?)?;
+    }
+    tree.flush()?;
+    Ok(())
+}
+
+/// migrate_wallets_v0 rewrites the wallets saved without a signature scheme as Ed25519 wallets
+fn migrate_wallets_v0(tree: &sled::Tree) -> Result<sled::Batch> {
+    let mut batch = sled::Batch::default();
+    for item in tree.iter() {
+        let (key, value) = item?;
+        if deserialize::<Wallet>(&value).is_ok() {
+            continue;
+        }
+        let address = String::from_utf8_lossy(&key).to_string();
+        let legacy: LegacyWallet = match deserialize(&value) {
+            Ok(w) => w,
+            Err(e) => {
+                return Err(format_err!("ERROR: Wallet {} can't be read ({})", address, e));
+            }
+        };
+        let wallet = Wallet {
+            public_key: legacy.public_key,
+            secret_key: legacy.secret_key,
+            scheme: SignatureScheme::Ed25519,
+        };
+        batch.insert(key, serialize(&wallet)?);
+        info!("migrated wallet {}", address);
+    }
+    Ok(batch)
+}
+
+/// migrate_wallets_v1 wraps the plain wallets into StoredWallet
+fn migrate_wallets_v1(tree: &sled::Tree) -> Result<sled::Batch> {
+    let mut batch = sled::Batch::default();
+    for item in tree.iter() {
+        let (key, value) = item?;
+        if key.starts_with(b"$") {
+            continue;
+        }
+        let wallet: Wallet = deserialize(&value)?;
+        let stored = StoredWallet {
+            public_key: wallet.public_key,
+            scheme: wallet.scheme,
+            secret: StoredSecret::Plain(wallet.secret_key),
+        };
+        batch.insert(key, serialize(&stored)?);
+    }
+    Ok(batch)
+}
+
+/// migrate_wallets_v3 drops the transaction index of a rescan, the ledger is rebuilt from the chain
+fn migrate_wallets_v3(tree: &sled::Tree) -> Result<sled::Batch> {
+    let mut batch = sled::Batch::default();
+    for item in tree.scan_prefix(WALLET_TX_PREFIX) {
+        batch.remove(item?.0);
+    }
+    batch.remove(WALLET_BEST_KEY);
+    Ok(batch)
+}
+
+/// migrate_wallets_v4 drops the master key an unlocked wallet left in the tree
+///
+/// sled doesn't overwrite the old value on disk, the data directory of a
+/// wallet that was ever unlocked should be treated as holding the master key.
+fn migrate_wallets_v4() -> sled::Batch {
+    let mut batch = sled::Batch::default();
+    batch.remove(LEGACY_UNLOCK_KEY);
+    batch
+}
+
+/// ImportLegacy moves the wallets and blocks of the databases used before DATA_DIR into store
+pub fn import_legacy(store: &Storage, wallets_dir: &Path, blocks_dir: &Path) -> Result<()> {
+    if wallets_dir.exists() {
+        import_legacy_wallets(store, wallets_dir)?;
+    }
+    if blocks_dir.exists() {
+        import_legacy_blocks(store, blocks_dir)?;
+    }
+    Ok(())
+}
+
+/// import_legacy_wallets migrates the wallets of dir and adds the ones store doesn't have yet
+///
+/// The old database is copied into a scratch tree and goes through the same
+/// migrations as an unversioned wallets tree, then the wallets are merged in
+/// one batch. An address store already has keeps the key store has.
+fn import_legacy_wallets(store: &Storage, dir: &Path) -> Result<()> {
+    upgrade_wallets(&store.wallets)?;
+    let staging = store.open_tree(WALLET_IMPORT_TREE)?;
+    staging.clear()?;
+    {
+        let old = sled::open(dir)?;
+        let mut batch = sled::Batch::default();
+        for item in old.iter() {
+            let (key, value) = item?;
+            batch.insert(key, value);
+        }
+        staging.apply_batch(batch)?;
+    }
+    upgrade_wallets(&staging)?;
+
+    let mut batch = sled::Batch::default();
+    let mut imported = 0;
+    for item in staging.iter() {
+        let (key, value) = item?;
+        if key.starts_with(b"$") || store.wallets.contains_key(&key)? {
+            continue;
+        }
+        batch.insert(key, value);
+        imported += 1;
+    }
+    store.wallets.apply_batch(batch)?;
+    store.wallets.flush()?;
+    store.drop_tree(WALLET_IMPORT_TREE)?;
+    if imported > 0 && store.wallets.contains_key(MASTER_KEY_KEY)? {
+        warn!(
+            "the {} wallets imported from {} are not encrypted",
+            imported,
+            dir.display()
+        );
+    }
+    mark_imported(dir)?;
+    println!("📥 从 {} 导入了 {} 个钱包", dir.display(), imported);
+    Ok(())
+}
+
+/// import_legacy_blocks copies the blocks of dir into an empty store
+///
+/// The blocks are committed without a chain schema version, so the next
+/// Blockchain::new runs migrate_chain_v0 on them and rebuilds the indexes.
+/// Blocks of a layout this binary can't read are left where they are.
+fn import_legacy_blocks(store: &Storage, dir: &Path) -> Result<()> {
+    if store.scan_prefix(Tree::Blocks, &[]).next().is_some() {
+        warn!(
+            "{} is ignored, {} already holds a chain; delete it",
+            dir.display(),
+            DATA_DIR
+        );
+        return Ok(());
+    }
+    let mut batch = Batch::new();
+    {
+        let old = sled::open(dir)?;
+        for item in old.iter() {
+            let (key, value) = item?;
+            if key.as_ref() == LAST_KEY.as_bytes() {
+                batch.insert(Tree::Metadata, LAST_KEY, value);
+                continue;
+            }
+            if let Err(e) = deserialize::<Block>(&value) {
+                warn!(
+                    "the blocks in {} were written in an older layout this binary can't read ({}); \
+                     sync or import the chain again and delete it",
+                    dir.display(),
+                    e
+                );
+                return Ok(());
+            }
+            batch.insert(Tree::Blocks, key, value);
+        }
+    }
+    batch.remove(Tree::Metadata, SCHEMA_VERSION_KEY);
+    batch.remove(Tree::Metadata, UTXO_BEST_KEY);
+    store.commit(batch)?;
+    store.flush()?;
+    mark_imported(dir)?;
+    println!("📥 从 {} 导入了区块", dir.display());
+    Ok(())
+}
+
+/// mark_imported renames an imported legacy database so it is not imported again
+fn mark_imported(dir: &Path) -> Result<()> {
+    std::fs::rename(dir, dir.with_extension("imported"))?;
+    Ok(())
+}
+
+fn check_supported(what: &str, version: u32, supported: u32) -> Result<()> {
+    if version > supported {
+        return Err(format_err!(
+            "ERROR: The {} data has schema version {}, this binary only supports up to version {}; \
+             upgrade the binary to open it",
+            what,
+            version,
+            supported
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_schema_versions() {
+        let store = MemoryStore::new();
+        Blockchain::new(&store).unwrap();
+        assert_eq!(chain_schema_version(&store).unwrap(), Some(CHAIN_SCHEMA_VERSION));
+
+        let mut batch = Batch::new();
+        set_chain_schema_version(&mut batch, CHAIN_SCHEMA_VERSION + 1).unwrap();
+        store.commit(batch).unwrap();
+        let err = Blockchain::new(&store).unwrap_err().to_string();
+        assert!(err.contains("upgrade the binary"), "{}", err);
+
+        let old = MemoryStore::new();
+        let mut batch = Batch::new();
+        batch.insert(Tree::Blocks, b"00ab", b"not a block");
+        batch.insert(Tree::Metadata, LAST_KEY, b"00ab");
+        old.commit(batch).unwrap();
+        let err = Blockchain::new(&old).unwrap_err().to_string();
+        assert!(err.contains("older layout"), "{}", err);
+        assert_eq!(chain_schema_version(&old).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn test_migrate_wallets() {
+        let dir = std::env::temp_dir().join(format!("wallets-{}", std::process::id()));
+        let mut keys = Vec::new();
+        {
+            let old = sled::open(&dir).unwrap();
+            for address in ["first", "second"] {
+                let (secret_key, public_key) = SignatureScheme::Ed25519.generate_keypair();
+                let legacy = LegacyWallet {
+                    public_key: public_key.clone(),
+                    secret_key,
+                };
+                old.insert(address, serialize(&legacy).unwrap()).unwrap();
+                keys.push((address.to_string(), public_key));
+            }
+            old.flush().unwrap();
+        }
+
+        let store = Storage::temporary().unwrap();
+        import_legacy(&store, &dir, &dir.with_extension("blocks")).unwrap();
+        let wallets = Wallets::new(&store).unwrap();
+        for (address, public_key) in &keys {
+            let wallet = wallets.get_wallet(address).unwrap();
+            assert_eq!(&wallet.public_key, public_key);
+            assert_eq!(wallet.scheme, SignatureScheme::Ed25519);
+        }
+        assert_eq!(wallets.get_all_addresses().len(), 2);
+        let version = store.wallets.get(WALLET_SCHEMA_KEY).unwrap().unwrap();
+        assert_eq!(deserialize::<u32>(&version).unwrap(), WALLET_SCHEMA_VERSION);
+        std::fs::remove_dir_all(dir.with_extension("imported")).unwrap();
+
+        store
+            .wallets
+            .insert(WALLET_SCHEMA_KEY, serialize(&(WALLET_SCHEMA_VERSION + 1)).unwrap())
+            .unwrap();
+        assert!(Wallets::new(&store).is_err());
+
+        // 有一个钱包读不了,这一步的迁移整个不生效,版本号也不变
+        let tree = store.open_tree("broken").unwrap();
+        let (secret_key, public_key) = SignatureScheme::Ed25519.generate_keypair();
+        let legacy = serialize(&LegacyWallet { public_key, secret_key }).unwrap();
+        tree.insert("good", legacy.clone()).unwrap();
+        tree.insert("unreadable", &b"x"[..]).unwrap();
+        assert!(upgrade_wallets(&tree).is_err());
+        assert_eq!(tree.get("good").unwrap().unwrap(), legacy);
+        assert!(!tree.contains_key(WALLET_SCHEMA_KEY).unwrap());
+
+        // 版本4解锁钱包时留下的主密钥在升级时删掉
+        let tree = store.open_tree("unlocked").unwrap();
+        tree.insert(WALLET_SCHEMA_KEY, serialize(&4u32).unwrap()).unwrap();
+        tree.insert(LEGACY_UNLOCK_KEY, &b"master key"[..]).unwrap();
+        upgrade_wallets(&tree).unwrap();
+        assert!(!tree.contains_key(LEGACY_UNLOCK_KEY).unwrap());
+    }
+
+    #[test]
+    fn test_import_legacy() {
+        let dir = std::env::temp_dir().join(format!("legacy-{}", std::process::id()));
+        let (wallets_dir, blocks_dir) = (dir.join("wallets"), dir.join("blocks"));
+        let (secret_key, public_key) = SignatureScheme::Ed25519.generate_keypair();
+        {
+            let old = sled::open(&wallets_dir).unwrap();
+            let legacy = LegacyWallet {
+                public_key: public_key.clone(),
+                secret_key,
+            };
+            old.insert("legacy", serialize(&legacy).unwrap()).unwrap();
+            old.flush().unwrap();
+            let old = sled::open(&blocks_dir).unwrap();
+            old.insert("00ab", "not a block").unwrap();
+            old.insert(LAST_KEY, "00ab").unwrap();
+            old.flush().unwrap();
+        }
+
+        let store = Storage::temporary().unwrap();
+        import_legacy(&store, &wallets_dir, &blocks_dir).unwrap();
+        let wallets = Wallets::new(&store).unwrap();
+        assert_eq!(wallets.get_wallet("legacy").unwrap().public_key, public_key);
+        assert!(!wallets_dir.exists());
+        assert!(dir.join("wallets.imported").exists());
+        // 旧格式的区块读不了,留在原地
+        assert!(blocks_dir.exists());
+        assert!(Blockchain::new(&store).unwrap().tip.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
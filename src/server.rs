@@ -2,14 +2,18 @@
 
 use super::*;
 use crate::block::*;
+use crate::mempool::*;
+use crate::storage::*;
 use crate::transaction::*;
 use crate::utxoset::*;
+use crate::wallets::*;
 use bincode::{deserialize, serialize};
 use failure::format_err;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
+use std::fmt;
 use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
+use std::net::{Shutdown, TcpListener, TcpStream};
 use std::sync::*;
 use std::thread;
 use std::time::Duration;
@@ -23,6 +27,11 @@ enum Message {
     GetBlock(GetBlocksmsg),
     Inv(Invmsg),
     Block(Blockmsg),
+    GetHeaders(GetHeadersmsg),
+    Headers(Headersmsg),
+    WalletPassphrase(WalletPassphrasemsg),
+    WalletLock,
+    SendFrom(SendFrommsg),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -36,6 +45,20 @@ struct GetBlocksmsg {
     addr_from: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GetHeadersmsg {
+    addr_from: String,
+}
+
+/// Headersmsg carries the best chain's headers, oldest first
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Headersmsg {
+    addr_from: String,
+    /// lowest height the sender still has the full block of
+    prune_height: i32,
+    headers: Vec<BlockHeader>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct GetDatamsg {
     addr_from: String,
@@ -56,32 +79,82 @@ struct Txmsg {
     transaction: Transaction,
 }
 
+/// WalletPassphrasemsg asks the node to unlock its wallet for timeout seconds
+#[derive(Serialize, Deserialize, Clone)]
+struct WalletPassphrasemsg {
+    passphrase: String,
+    timeout: u64,
+}
+
+impl fmt::Debug for WalletPassphrasemsg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // 密码不进日志
+        f.debug_struct("WalletPassphrasemsg")
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+/// SendFrommsg asks the node to pay amount from an address of its wallet
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SendFrommsg {
+    from: String,
+    to: String,
+    amount: i32,
+}
+
+/// WalletReply answers a wallet request, with the error message if it failed
+type WalletReply = std::result::Result<String, String>;
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 struct Versionmsg {
     addr_from: String,
     version: i32,
     best_height: i32,
+    /// lowest height the sender still has the full block of, 0 if it is not pruned
+    prune_height: i32,
 }
 
-pub struct Server {
+/// VersionPrefix is the start of a Versionmsg, the same in every protocol version
+#[derive(Serialize, Deserialize, Debug)]
+struct VersionPrefix {
+    addr_from: String,
+    version: i32,
+}
+
+pub struct Server<S: ChainStore = Storage> {
     node_address: String,
     mining_address: String,
-    inner: Arc<Mutex<ServerInner>>,
+    inner: Arc<Mutex<ServerInner<S>>>,
 }
 
-struct ServerInner {
+struct ServerInner<S: ChainStore> {
     known_nodes: HashSet<String>,
-    utxo: UTXOSet,
+    utxo: UTXOSet<S>,
     blocks_in_transit: Vec<String>,
-    mempool: HashMap<String, Transaction>,
+    mempool: Mempool,
+    /// wallets whose transaction ledger follows the blocks the node connects
+    wallets: Option<Wallets>,
+}
+
+impl<S: ChainStore> ServerInner<S> {
+    /// sync_ledger brings the wallet ledger to the tip, a failure only costs a rescan later
+    fn sync_ledger(&self) {
+        if let Some(wallets) = &self.wallets
+            && let Err(e) = wallets.sync(&self.utxo.blockchain)
+        {
+            warn!("cannot sync the wallet ledger: {}", e);
+        }
+    }
 }
 
 const KNOWN_NODE1: &str = "localhost:3000";
 const CMD_LEN: usize = 12;
-const VERSION: i32 = 1;
+/// VERSION is the protocol version, peers of another version are not talked to
+const VERSION: i32 = 2;
 
-impl Server {
-    pub fn new(port: &str, miner_address: &str, utxo: UTXOSet) -> Result<Server> {
+impl<S: ChainStore> Server<S> {
+    pub fn new(port: &str, miner_address: &str, utxo: UTXOSet<S>) -> Result<Server<S>> {
         let mut node_set = HashSet::new();
         node_set.insert(String::from(KNOWN_NODE1));
         Ok(Server {
@@ -91,11 +164,22 @@ impl Server {
                 known_nodes: node_set,
                 utxo,
                 blocks_in_transit: Vec::new(),
-                mempool: HashMap::new(),
+                mempool: Mempool::new(),
+                wallets: None,
             })),
         })
     }
 
+    /// SetWallets makes the node keep the transaction ledger of wallets at the tip
+    ///
+    /// A pruning node drops the transactions of old blocks as it connects new
+    /// ones, so the ledger follows every block before it is buried that deep.
+    pub fn set_wallets(&self, wallets: Wallets) {
+        let inner = &mut *self.inner.lock().unwrap();
+        inner.wallets = Some(wallets);
+        inner.sync_ledger();
+    }
+
     pub fn start_server(&self) -> Result<()> {
         let server1 = Server {
             node_address: self.node_address.clone(),
@@ -126,13 +210,17 @@ impl Server {
                 mining_address: self.mining_address.clone(),
                 inner: Arc::clone(&self.inner),
             };
-            thread::spawn(move || server1.handle_connection(stream));
+            thread::spawn(move || {
+                if let Err(e) = server1.handle_connection(stream) {
+                    warn!("{}", e);
+                }
+            });
         }
 
         Ok(())
     }
 
-    pub fn send_transaction(tx: &Transaction, utxoset: UTXOSet) -> Result<()> {
+    pub fn send_transaction(tx: &Transaction, utxoset: UTXOSet<S>) -> Result<()> {
         let server = Server::new("7000", "", utxoset)?;
         server.send_tx(KNOWN_NODE1, tx)?;
         Ok(())
@@ -170,60 +258,92 @@ impl Server {
     }
 
     fn get_mempool_tx(&self, addr: &str) -> Option<Transaction> {
-        match self.inner.lock().unwrap().mempool.get(addr) {
-            Some(tx) => Some(tx.clone()),
-            None => None,
-        }
+        self.inner.lock().unwrap().mempool.get(addr).cloned()
     }
 
-    fn get_mempool(&self) -> HashMap<String, Transaction> {
-        self.inner.lock().unwrap().mempool.clone()
+    fn mempool_is_empty(&self) -> bool {
+        self.inner.lock().unwrap().mempool.is_empty()
     }
 
-    fn insert_mempool(&self, tx: Transaction) {
-        self.inner.lock().unwrap().mempool.insert(tx.id.clone(), tx);
+    fn insert_mempool(&self, tx: Transaction) -> Result<()> {
+        let inner = &mut *self.inner.lock().unwrap();
+        inner.utxo.flush_if_pruned()?;
+        inner.mempool.add(tx, &inner.utxo)
     }
 
-    fn clear_mempool(&self) {
-        self.inner.lock().unwrap().mempool.clear()
+    fn mempool_block_template(&self) -> Vec<Transaction> {
+        self.inner.lock().unwrap().mempool.block_template()
+    }
+
+    fn remove_mempool_block(&self, block: &Block) {
+        self.inner.lock().unwrap().mempool.remove_for_block(block)
+    }
+
+    fn evict_mempool(&self, txs: &[Transaction]) {
+        let mempool = &mut self.inner.lock().unwrap().mempool;
+        for tx in txs {
+            mempool.evict(&tx.id);
+        }
     }
 
     fn get_best_height(&self) -> Result<i32> {
         self.inner.lock().unwrap().utxo.blockchain.get_best_height()
     }
 
-    fn get_block_hashs(&self) -> Vec<String> {
+    fn get_prune_height(&self) -> Result<i32> {
+        self.inner.lock().unwrap().utxo.blockchain.get_prune_height()
+    }
+
+    fn get_block_hashs(&self) -> Result<Vec<String>> {
         self.inner.lock().unwrap().utxo.blockchain.get_block_hashs()
     }
 
-    fn get_block(&self, block_hash: &str) -> Result<Block> {
-        self.inner
-            .lock()
-            .unwrap()
-            .utxo
-            .blockchain
-            .get_block(block_hash)
+    fn has_block(&self, block_hash: &str) -> Result<bool> {
+        self.inner.lock().unwrap().utxo.blockchain.has_block(block_hash)
+    }
+
+    /// get_headers returns the headers of the best chain, oldest first
+    ///
+    /// A node started from a UTXO snapshot only knows the headers from the snapshot's block on.
+    fn get_headers(&self) -> Result<Vec<BlockHeader>> {
+        let bc = &self.inner.lock().unwrap().utxo.blockchain;
+        let mut headers = Vec::new();
+        for height in 0..=bc.get_best_height()? {
+            if let Some(hash) = bc.get_block_hash(height)? {
+                headers.push(bc.get_header(&hash)?);
+            }
+        }
+        Ok(headers)
     }
 
-    fn verify_tx(&self, tx: &Transaction) -> Result<bool> {
+    fn get_block(&self, block_hash: &str) -> Result<Block> {
         self.inner
             .lock()
             .unwrap()
             .utxo
             .blockchain
-            .verify_transacton(tx)
+            .get_block(block_hash)
     }
 
     fn add_block(&self, block: Block) -> Result<()> {
-        self.inner.lock().unwrap().utxo.blockchain.add_block(block)
+        let inner = &mut *self.inner.lock().unwrap();
+        inner.utxo.add_block(block)?;
+        inner.sync_ledger();
+        Ok(())
     }
 
     fn mine_block(&self, txs: Vec<Transaction>) -> Result<Block> {
-        self.inner.lock().unwrap().utxo.blockchain.mine_block(txs)
+        let inner = &mut *self.inner.lock().unwrap();
+        let block = inner.utxo.mine_block(txs)?;
+        inner.sync_ledger();
+        Ok(block)
     }
 
-    fn utxo_reindex(&self) -> Result<()> {
-        self.inner.lock().unwrap().utxo.reindex()
+    fn utxo_repair(&self) -> Result<bool> {
+        let inner = &mut *self.inner.lock().unwrap();
+        let repaired = inner.utxo.repair()?;
+        inner.sync_ledger();
+        Ok(repaired)
     }
 
     /* -----------------------------------------------------*/
@@ -248,7 +368,7 @@ impl Server {
 
     fn request_blocks(&self) -> Result<()> {
         for node in self.get_known_nodes() {
-            self.send_get_blocks(&node)?
+            self.send_get_headers(&node)?
         }
         Ok(())
     }
@@ -284,12 +404,23 @@ impl Server {
         self.send_data(addr, &data)
     }
 
-    fn send_get_blocks(&self, addr: &str) -> Result<()> {
-        info!("send get blocks message to: {}", addr);
-        let data = GetBlocksmsg {
+    fn send_get_headers(&self, addr: &str) -> Result<()> {
+        info!("send get headers message to: {}", addr);
+        let data = GetHeadersmsg {
             addr_from: self.node_address.clone(),
         };
-        let data = serialize(&(cmd_to_bytes("getblocks"), data))?;
+        let data = serialize(&(cmd_to_bytes("getheaders"), data))?;
+        self.send_data(addr, &data)
+    }
+
+    fn send_headers(&self, addr: &str) -> Result<()> {
+        info!("send headers to: {}", addr);
+        let data = Headersmsg {
+            addr_from: self.node_address.clone(),
+            prune_height: self.get_prune_height()?,
+            headers: self.get_headers()?,
+        };
+        let data = serialize(&(cmd_to_bytes("headers"), data))?;
         self.send_data(addr, &data)
     }
 
@@ -323,6 +454,7 @@ impl Server {
             addr_from: self.node_address.clone(),
             best_height: self.get_best_height()?,
             version: VERSION,
+            prune_height: self.get_prune_height()?,
         };
         let data = serialize(&(cmd_to_bytes("version"), data))?;
         self.send_data(addr, &data)
@@ -332,7 +464,8 @@ impl Server {
         info!("receive version msg: {:#?}", msg);
         let my_best_height = self.get_best_height()?;
         if my_best_height < msg.best_height {
-            self.send_get_blocks(&msg.addr_from)?;
+            // 先要区块头:能看出对方裁剪掉了哪些区块,也能知道哪些区块是assumevalid区块的祖先
+            self.send_get_headers(&msg.addr_from)?;
         } else if my_best_height > msg.best_height {
             self.send_version(&msg.addr_from)?;
         }
@@ -360,7 +493,10 @@ impl Server {
             msg.addr_from,
             msg.block.get_hash()
         );
+        // 区块有效才把它确认和冲突的交易移出内存池
+        let block = msg.block.clone();
         self.add_block(msg.block)?;
+        self.remove_mempool_block(&block);
 
         let mut in_transit = self.get_in_transit();
         if in_transit.len() > 0 {
@@ -369,7 +505,7 @@ impl Server {
             in_transit.remove(0);
             self.replace_in_transit(in_transit);
         } else {
-            self.utxo_reindex()?;
+            self.utxo_repair()?;
         }
 
         Ok(())
@@ -378,16 +514,18 @@ impl Server {
     fn handle_inv(&self, msg: Invmsg) -> Result<()> {
         info!("receive inv msg: {:#?}", msg);
         if msg.kind == "block" {
-            let block_hash = &msg.items[0];
-            self.send_get_data(&msg.addr_from, "block", block_hash)?;
-
-            let mut new_in_transit = Vec::new();
-            for b in &msg.items {
-                if b != block_hash {
-                    new_in_transit.push(b.clone());
+            // 清单从链顶往回排,倒过来从最老的缺失区块要起,父区块总是先到
+            let mut wanted = Vec::new();
+            for block_hash in msg.items.iter().rev() {
+                if !self.has_block(block_hash)? {
+                    wanted.push(block_hash.clone());
                 }
             }
-            self.replace_in_transit(new_in_transit);
+            if wanted.is_empty() {
+                return Ok(());
+            }
+            self.send_get_data(&msg.addr_from, "block", &wanted.remove(0))?;
+            self.replace_in_transit(wanted);
         } else if msg.kind == "tx" {
             let txid = &msg.items[0];
             match self.get_mempool_tx(txid) {
@@ -404,11 +542,79 @@ impl Server {
 
     fn handle_get_blocks(&self, msg: GetBlocksmsg) -> Result<()> {
         info!("receive get blocks msg: {:#?}", msg);
-        let block_hashs = self.get_block_hashs();
+        let block_hashs = self.get_block_hashs()?;
         self.send_inv(&msg.addr_from, "block", block_hashs)?;
         Ok(())
     }
 
+    fn handle_get_headers(&self, msg: GetHeadersmsg) -> Result<()> {
+        info!("receive get headers msg: {:#?}", msg);
+        self.send_headers(&msg.addr_from)
+    }
+
+    /// handle_headers checks the headers chain and asks for the blocks the sender still has in full
+    fn handle_headers(&self, msg: Headersmsg) -> Result<()> {
+        info!(
+            "receive headers msg: {} headers from {}",
+            msg.headers.len(),
+            msg.addr_from
+        );
+        let mut prev: Option<&BlockHeader> = None;
+        for header in &msg.headers {
+            let linked = match prev {
+                Some(prev) => header.height == prev.height + 1 && header.prev_block_hash == prev.hash,
+                None => true,
+            };
+            if !linked || !header.check_proof_of_work()? {
+                return Err(format_err!(
+                    "ERROR: Bad header {} from {}",
+                    header.hash,
+                    msg.addr_from
+                ));
+            }
+            prev = Some(header);
+        }
+
+        self.inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .add_headers_chain(&msg.headers);
+        let mut wanted = self.blocks_after_known(&msg)?;
+        if wanted.is_empty() {
+            return Ok(());
+        }
+        self.send_get_data(&msg.addr_from, "block", &wanted.remove(0))?;
+        self.replace_in_transit(wanted);
+        Ok(())
+    }
+
+    /// blocks_after_known lists the blocks of a headers chain after the highest one we have
+    ///
+    /// The sender can't give us a block it pruned, so if we miss one below its
+    /// prune height the chain can't be connected and we have to sync elsewhere.
+    fn blocks_after_known(&self, msg: &Headersmsg) -> Result<Vec<String>> {
+        let mut start = 0;
+        for (i, header) in msg.headers.iter().enumerate().rev() {
+            if self.has_block(&header.hash)? {
+                start = i + 1;
+                break;
+            }
+        }
+        if let Some(first) = msg.headers.get(start)
+            && first.height < msg.prune_height
+        {
+            return Err(format_err!(
+                "ERROR: {} pruned block {} at height {} that we miss, sync from a node that is not pruned",
+                msg.addr_from,
+                first.hash,
+                first.height
+            ));
+        }
+        Ok(msg.headers[start..].iter().map(|h| h.hash.clone()).collect())
+    }
+
     fn handle_get_data(&self, msg: GetDatamsg) -> Result<()> {
         info!("receive get data msg: {:#?}", msg);
         if msg.kind == "block" {
@@ -423,57 +629,142 @@ impl Server {
 
     fn handle_tx(&self, msg: Txmsg) -> Result<()> {
         info!("receive tx msg: {} {}", msg.addr_from, &msg.transaction.id);
-        self.insert_mempool(msg.transaction.clone());
+        if let Err(e) = self.insert_mempool(msg.transaction.clone()) {
+            warn!("reject tx {}: {}", &msg.transaction.id, e);
+            return Ok(());
+        }
+        self.relay_tx(&msg.transaction, &msg.addr_from)
+    }
 
+    /// relay_tx passes on a transaction that entered the mempool
+    ///
+    /// The central node announces it to the other nodes and a miner mines the
+    /// mempool; a transaction of this node's wallet goes to the central node.
+    fn relay_tx(&self, tx: &Transaction, addr_from: &str) -> Result<()> {
         let known_nodes = self.get_known_nodes();
         if self.node_address == KNOWN_NODE1 {
             for node in known_nodes {
-                if node != self.node_address && node != msg.addr_from {
-                    self.send_inv(&node, "tx", vec![msg.transaction.id.clone()])?;
+                if node != self.node_address && node != addr_from {
+                    self.send_inv(&node, "tx", vec![tx.id.clone()])?;
                 }
             }
-        } else {
-            let mut mempool = self.get_mempool();
-            debug!("Current mempool: {:#?}", &mempool);
-            if mempool.len() >= 1 && !self.mining_address.is_empty() {
-                loop {
-                    let mut txs = Vec::new();
-
-                    for (_, tx) in &mempool {
-                        if self.verify_tx(tx)? {
-                            txs.push(tx.clone());
-                        }
-                    }
+        } else if !self.mining_address.is_empty() {
+            if self.mempool_is_empty() {
+                return Ok(());
+            }
+            // 按祖先费率打包,父交易总是排在子交易前面
+            let template = self.mempool_block_template();
+            debug!("Block template: {:#?}", &template);
+            if template.is_empty() {
+                return Ok(());
+            }
 
-                    if txs.is_empty() {
-                        return Ok(());
-                    }
+            let cbtx = Transaction::new_coinbase(self.mining_address.clone(), String::new())?;
+            let mut txs = vec![cbtx];
+            txs.extend(template.iter().cloned());
+
+            // 挖不出来的交易留在内存池里,之后每个区块模板都会再失败
+            let new_block = match self.mine_block(txs) {
+                Ok(block) => block,
+                Err(e) => {
+                    warn!("cannot mine the block template, evicting its transactions: {}", e);
+                    self.evict_mempool(&template);
+                    return Ok(());
+                }
+            };
+            self.remove_mempool_block(&new_block);
 
-                    let cbtx =
-                        Transaction::new_coinbase(self.mining_address.clone(), String::new())?;
-                    txs.push(cbtx);
+            for node in self.get_known_nodes() {
+                if node != self.node_address {
+                    self.send_inv(&node, "block", vec![new_block.get_hash()])?;
+                }
+            }
+        } else if addr_from == self.node_address {
+            self.send_tx(KNOWN_NODE1, tx)?;
+        }
 
-                    for tx in &txs {
-                        mempool.remove(&tx.id);
-                    }
+        Ok(())
+    }
 
-                    let new_block = self.mine_block(txs)?;
-                    self.utxo_reindex()?;
+    /// with_wallets runs f on the wallets of the node
+    fn with_wallets<T>(&self, f: impl FnOnce(&mut Wallets) -> Result<T>) -> Result<T> {
+        match &mut self.inner.lock().unwrap().wallets {
+            Some(wallets) => f(wallets),
+            None => Err(format_err!("ERROR: This node keeps no wallet")),
+        }
+    }
 
-                    for node in self.get_known_nodes() {
-                        if node != self.node_address {
-                            self.send_inv(&node, "block", vec![new_block.get_hash()])?;
-                        }
-                    }
+    /// handle_wallet_passphrase unlocks the wallet in the node's memory until the timeout
+    fn handle_wallet_passphrase(&self, msg: WalletPassphrasemsg) -> Result<String> {
+        let timeout = Duration::from_secs(msg.timeout);
+        self.with_wallets(|wallets| wallets.unlock_for(&msg.passphrase, timeout))?;
+        // 到时间把主密钥从内存里清掉;中间又解锁过的话,lock_if_expired不会提前锁定
+        let inner = Arc::clone(&self.inner);
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            if let Some(wallets) = &mut inner.lock().unwrap().wallets {
+                wallets.lock_if_expired();
+            }
+        });
+        info!("wallet unlocked for {} seconds", msg.timeout);
+        Ok(format!("wallet unlocked for {} seconds", msg.timeout))
+    }
 
-                    if mempool.len() == 0 {
-                        break;
-                    }
-                }
-                self.clear_mempool();
+    fn handle_wallet_lock(&self) -> Result<String> {
+        self.with_wallets(|wallets| wallets.lock())?;
+        info!("wallet locked");
+        Ok(String::from("wallet locked"))
+    }
+
+    /// handle_send_from signs a payment with the node's wallet and relays it like a received transaction
+    ///
+    /// It fails while the wallet is locked. The change goes to a new change address.
+    fn handle_send_from(&self, msg: SendFrommsg) -> Result<String> {
+        let tx = {
+            let inner = &mut *self.inner.lock().unwrap();
+            let wallets = match &mut inner.wallets {
+                Some(wallets) => wallets,
+                None => return Err(format_err!("ERROR: This node keeps no wallet")),
+            };
+            let wallet = wallets.get_wallet(&msg.from)?;
+            if !wallets.has_seed() {
+                return Err(format_err!(
+                    "ERROR: The wallet has no HD seed to derive a change address from, run createwallet once to create one and back up its mnemonic"
+                ));
             }
-        }
+            let change = wallets.create_change_address(wallet.scheme)?;
+            inner.utxo.flush_if_pruned()?;
+            let tx = Transaction::new_UTXO(&wallet, &msg.to, msg.amount, &change, &inner.utxo)?;
+            inner.mempool.add(tx.clone(), &inner.utxo)?;
+            if tx.vout.len() > 1 {
+                wallets.save_all()?;
+            }
+            tx
+        };
+        info!("send tx {} from the wallet", &tx.id);
+        self.relay_tx(&tx, &self.node_address)?;
+        Ok(tx.id)
+    }
 
+    /// reply_wallet answers a wallet request of the command line with the result of handle
+    ///
+    /// The requests carry the passphrase and spend the node's coins, so only
+    /// connections from this machine are served.
+    fn reply_wallet(
+        &self,
+        stream: &mut TcpStream,
+        handle: impl FnOnce(&Self) -> Result<String>,
+    ) -> Result<()> {
+        let result = if stream.peer_addr()?.ip().is_loopback() {
+            handle(self)
+        } else {
+            Err(format_err!("ERROR: Wallet requests are only taken from this machine"))
+        };
+        if let Err(e) = &result {
+            warn!("wallet request failed: {}", e);
+        }
+        let reply: WalletReply = result.map_err(|e| e.to_string());
+        stream.write_all(&serialize(&reply)?)?;
         Ok(())
     }
 
@@ -490,14 +781,63 @@ impl Server {
             Message::Inv(data) => self.handle_inv(data)?,
             Message::GetBlock(data) => self.handle_get_blocks(data)?,
             Message::GetData(data) => self.handle_get_data(data)?,
+            Message::GetHeaders(data) => self.handle_get_headers(data)?,
+            Message::Headers(data) => self.handle_headers(data)?,
             Message::Tx(data) => self.handle_tx(data)?,
             Message::Version(data) => self.handle_version(data)?,
+            Message::WalletPassphrase(data) => {
+                self.reply_wallet(&mut stream, |s| s.handle_wallet_passphrase(data))?
+            }
+            Message::WalletLock => self.reply_wallet(&mut stream, |s| s.handle_wallet_lock())?,
+            Message::SendFrom(data) => {
+                self.reply_wallet(&mut stream, |s| s.handle_send_from(data))?
+            }
         }
 
         Ok(())
     }
 }
 
+/// WalletPassphrase asks the node listening on port of this machine to unlock its wallet for timeout seconds
+pub fn wallet_passphrase(port: &str, passphrase: &str, timeout: u64) -> Result<String> {
+    let msg = WalletPassphrasemsg {
+        passphrase: passphrase.to_string(),
+        timeout,
+    };
+    request_wallet(port, "walletpass", &msg)
+}
+
+/// WalletLock asks the node listening on port of this machine to lock its wallet
+pub fn wallet_lock(port: &str) -> Result<String> {
+    request_wallet(port, "walletlock", &())
+}
+
+/// SendFromNode asks the node listening on port of this machine to pay with its wallet, returning the txid
+pub fn send_from_node(port: &str, from: &str, to: &str, amount: i32) -> Result<String> {
+    let msg = SendFrommsg {
+        from: from.to_string(),
+        to: to.to_string(),
+        amount,
+    };
+    request_wallet(port, "sendfrom", &msg)
+}
+
+/// request_wallet sends a wallet request and waits for the node's reply
+fn request_wallet<T: Serialize>(port: &str, cmd: &str, data: &T) -> Result<String> {
+    let addr = String::from("localhost:") + port;
+    let mut stream = match TcpStream::connect(&addr) {
+        Ok(s) => s,
+        Err(e) => return Err(format_err!("ERROR: No node is running at {}: {}", addr, e)),
+    };
+    stream.write_all(&serialize(&(cmd_to_bytes(cmd), data))?)?;
+    // 节点读到连接关闭才处理请求
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply)?;
+    let reply: WalletReply = deserialize(&reply)?;
+    reply.map_err(|e| format_err!("{}", e))
+}
+
 fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
     let mut data = [0; CMD_LEN];
     for (i, d) in cmd.as_bytes().iter().enumerate() {
@@ -529,13 +869,37 @@ fn bytes_to_cmd(bytes: &[u8]) -> Result<Message> {
     } else if cmd == "getblocks".as_bytes() {
         let data: GetBlocksmsg = deserialize(data)?;
         Ok(Message::GetBlock(data))
+    } else if cmd == "getheaders".as_bytes() {
+        let data: GetHeadersmsg = deserialize(data)?;
+        Ok(Message::GetHeaders(data))
+    } else if cmd == "headers".as_bytes() {
+        let data: Headersmsg = deserialize(data)?;
+        Ok(Message::Headers(data))
     } else if cmd == "getdata".as_bytes() {
         let data: GetDatamsg = deserialize(data)?;
         Ok(Message::GetData(data))
     } else if cmd == "tx".as_bytes() {
         let data: Txmsg = deserialize(data)?;
         Ok(Message::Tx(data))
+    } else if cmd == "walletpass".as_bytes() {
+        let data: WalletPassphrasemsg = deserialize(data)?;
+        Ok(Message::WalletPassphrase(data))
+    } else if cmd == "walletlock".as_bytes() {
+        Ok(Message::WalletLock)
+    } else if cmd == "sendfrom".as_bytes() {
+        let data: SendFrommsg = deserialize(data)?;
+        Ok(Message::SendFrom(data))
     } else if cmd == "version".as_bytes() {
+        // 先只读版本号,别的版本的消息格式可能不一样
+        let prefix: VersionPrefix = deserialize(data)?;
+        if prefix.version != VERSION {
+            return Err(format_err!(
+                "ERROR: {} speaks protocol version {}, this node speaks {}",
+                prefix.addr_from,
+                prefix.version,
+                VERSION
+            ));
+        }
         let data: Versionmsg = deserialize(data)?;
         Ok(Message::Version(data))
     } else {
@@ -547,20 +911,25 @@ fn bytes_to_cmd(bytes: &[u8]) -> Result<Message> {
 mod test {
     use super::*;
     use crate::blockchain::*;
-    use crate::wallets::*;
+    use crate::crypter::*;
+    use crate::keys::*;
+    use crate::testutil::*;
 
     #[test]
     fn test_cmd() {
-        let mut ws = Wallets::new().unwrap();
-        let wa1 = ws.create_wallet();
-        let bc = Blockchain::create_blockchain(wa1).unwrap();
-        let utxo_set = UTXOSet { blockchain: bc };
+        let mut ws = Wallets::new(&Storage::temporary().unwrap()).unwrap();
+        ws.create_seed().unwrap();
+        let wa1 = ws.create_wallet(SignatureScheme::Ed25519).unwrap();
+        let bc = Blockchain::create_blockchain(wa1, &MemoryStore::new()).unwrap();
+        let utxo_set = UTXOSet::new(bc).unwrap();
+        assert_eq!(utxo_set.best_block().unwrap(), Some(utxo_set.blockchain.tip.clone()));
         let server = Server::new("7878", "localhost:3001", utxo_set).unwrap();
 
         let vmsg = Versionmsg {
             addr_from: server.node_address.clone(),
             best_height: server.get_best_height().unwrap(),
             version: VERSION,
+            prune_height: server.get_prune_height().unwrap(),
         };
         let data = serialize(&(cmd_to_bytes("version"), vmsg.clone())).unwrap();
         if let Message::Version(v) = bytes_to_cmd(&data).unwrap() {
@@ -569,4 +938,149 @@ mod test {
             panic!("wrong!");
         }
     }
+
+    #[test]
+    fn test_version_mismatch() {
+        #[derive(Serialize)]
+        struct OldVersionmsg {
+            addr_from: String,
+            version: i32,
+            best_height: i32,
+        }
+        let old = OldVersionmsg {
+            addr_from: String::from("localhost:3001"),
+            version: 1,
+            best_height: 5,
+        };
+        let data = serialize(&(cmd_to_bytes("version"), old)).unwrap();
+        let err = bytes_to_cmd(&data).err().unwrap().to_string();
+        assert!(err.contains("protocol version 1"), "{}", err);
+    }
+
+    #[test]
+    fn test_headers_from_pruned_peer() {
+        let mut chain = TestChain::new();
+        let mut blocks = vec![chain.utxo.blockchain.get_block(&chain.utxo.blockchain.tip).unwrap()];
+        for _ in 0..12 {
+            blocks.push(chain.mine(vec![]));
+        }
+        chain.utxo.blockchain.set_prune_depth(MIN_PRUNE_DEPTH).unwrap();
+        chain.utxo.blockchain.prune().unwrap();
+        let bc = &chain.utxo.blockchain;
+        assert_eq!(bc.get_prune_height().unwrap(), 2);
+        assert!(bc.is_pruned(&blocks[1].get_hash()).unwrap());
+        assert!(bc.get_block(&blocks[1].get_hash()).is_err());
+        assert_eq!(bc.get_header(&blocks[1].get_hash()).unwrap().height, 1);
+        // 裁剪之后新区块照样验证和连接
+        blocks.push(chain.mine(vec![]));
+
+        let peer = Server::new("7879", "", chain.utxo).unwrap();
+        let headers = peer.get_headers().unwrap();
+        assert_eq!(headers.len(), blocks.len());
+        assert!(headers.iter().zip(&blocks).all(|(h, b)| h.hash == b.get_hash()));
+        let msg = Headersmsg {
+            addr_from: peer.node_address.clone(),
+            prune_height: peer.get_prune_height().unwrap(),
+            headers,
+        };
+        let data = serialize(&(cmd_to_bytes("headers"), msg.clone())).unwrap();
+        assert!(matches!(bytes_to_cmd(&data).unwrap(), Message::Headers(_)));
+
+        // 只有创世区块的全节点接不上对方裁剪掉的区块
+        let mut bc = Blockchain::new(&MemoryStore::new()).unwrap();
+        bc.add_block(blocks[0].clone()).unwrap();
+        let node = Server::new("7880", "", UTXOSet::new(bc).unwrap()).unwrap();
+        let err = node.blocks_after_known(&msg).unwrap_err().to_string();
+        assert!(err.contains("not pruned"), "{}", err);
+
+        // 缺的区块都没被裁剪就从已有的最高区块往后要
+        assert_eq!(msg.prune_height, 3);
+        node.add_block(blocks[1].clone()).unwrap();
+        assert!(node.blocks_after_known(&msg).is_err());
+        node.add_block(blocks[2].clone()).unwrap();
+        let wanted = node.blocks_after_known(&msg).unwrap();
+        assert_eq!(wanted.len(), blocks.len() - 3);
+        for hash in wanted {
+            let block = blocks.iter().find(|b| b.get_hash() == hash).unwrap();
+            node.add_block(block.clone()).unwrap();
+        }
+        assert_eq!(node.get_best_height().unwrap(), 13);
+        assert!(node.blocks_after_known(&msg).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_node_keeps_ledger() {
+        let TestChain { mut utxo, wallets, miner } = TestChain::new();
+        utxo.blockchain.set_prune_depth(MIN_PRUNE_DEPTH).unwrap();
+        let genesis = utxo.blockchain.get_block(&utxo.blockchain.tip).unwrap();
+        let node = Server::new("7881", "", utxo).unwrap();
+        node.set_wallets(wallets);
+
+        let coinbase = Transaction::new_coinbase(miner.clone(), String::new()).unwrap();
+        node.add_block(Block::new_block(vec![coinbase], genesis.get_hash(), 1).unwrap()).unwrap();
+        for _ in 0..11 {
+            let coinbase = Transaction::new_coinbase(miner.clone(), String::new()).unwrap();
+            node.mine_block(vec![coinbase]).unwrap();
+        }
+
+        // 早期的区块已经裁剪,账本里还有它们的交易
+        let inner = node.inner.lock().unwrap();
+        assert!(inner.utxo.blockchain.is_pruned(&genesis.get_hash()).unwrap());
+        let txs = inner.wallets.as_ref().unwrap().transactions().unwrap();
+        assert_eq!(txs.len(), 13);
+        assert_eq!(txs[0].block_hash, genesis.get_hash());
+        assert!(txs.iter().all(|wtx| wtx.direction() == Direction::Generated));
+    }
+
+    #[test]
+    fn test_wallet_requests() {
+        let TestChain { utxo, mut wallets, miner } = TestChain::new();
+        let to = wallets.create_wallet(SignatureScheme::Ed25519).unwrap();
+        let kdf = KdfParams {
+            m_cost: 64,
+            t_cost: 1,
+            p_cost: 1,
+        };
+        wallets.encrypt_with("secret", kdf).unwrap();
+        // 中心节点只把交易通告给别的节点,测试里没有别的节点
+        let node = Server::new("3000", "", utxo).unwrap();
+        node.set_wallets(wallets);
+        let send = || {
+            node.handle_send_from(SendFrommsg {
+                from: miner.clone(),
+                to: to.clone(),
+                amount: 3,
+            })
+        };
+        let unlock = |passphrase: &str, timeout| {
+            node.handle_wallet_passphrase(WalletPassphrasemsg {
+                passphrase: passphrase.to_string(),
+                timeout,
+            })
+        };
+
+        // 锁定时的错误经过连接传回命令行
+        let listener = TcpListener::bind("localhost:7882").unwrap();
+        let err = thread::scope(|scope| {
+            scope.spawn(|| node.handle_connection(listener.accept().unwrap().0).unwrap());
+            send_from_node("7882", &miner, &to, 3).unwrap_err().to_string()
+        });
+        assert!(err.contains("wallet is locked"), "{}", err);
+        assert!(node.mempool_is_empty());
+
+        assert!(unlock("wrong", 60).is_err());
+        assert!(unlock("secret", 0).is_err());
+        unlock("secret", 60).unwrap();
+        let txid = send().unwrap();
+        assert_eq!(node.get_mempool_tx(&txid).unwrap().vout[0].value, 3);
+        node.handle_wallet_lock().unwrap();
+        assert!(send().unwrap_err().to_string().contains("wallet is locked"));
+
+        // 到时间节点自己把钱包锁上
+        unlock("secret", 1).unwrap();
+        assert!(!node.with_wallets(|wallets| Ok(wallets.is_locked())).unwrap());
+        thread::sleep(Duration::from_millis(1500));
+        assert!(node.with_wallets(|wallets| Ok(wallets.is_locked())).unwrap());
+        assert!(send().unwrap_err().to_string().contains("wallet is locked"));
+    }
 }
@@ -0,0 +1,197 @@
+//! signature checks: caching, ed25519 batching and parallel verification
+
+use crate::keys::*;
+use bincode::serialize;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::sync::{Mutex, OnceLock};
+
+/// Maximum number of verified signatures remembered by the cache
+pub const SIG_CACHE_SIZE: usize = 100_000;
+/// Number of ed25519 signatures handed to one batch verification
+const ED25519_BATCH_SIZE: usize = 64;
+
+/// SigCheck is one input signature waiting to be verified
+#[derive(Debug, Clone)]
+pub struct SigCheck {
+    /// transaction and input the signature belongs to, for the error message
+    pub txid: String,
+    pub input: usize,
+    pub scheme: SignatureScheme,
+    pub message: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl SigCheck {
+    pub fn verify(&self) -> bool {
+        self.scheme
+            .verify(&self.message, &self.public_key, &self.signature)
+    }
+
+    /// cache_key identifies the check in the signature cache
+    fn cache_key(&self) -> [u8; 32] {
+        let data = serialize(&(
+            self.scheme,
+            &self.message,
+            &self.public_key,
+            &self.signature,
+        ))
+        .unwrap();
+        let mut hasher = Sha256::new();
+        hasher.input(&data);
+        let mut re: [u8; 32] = [0; 32];
+        hasher.result(&mut re);
+        re
+    }
+}
+
+fn sig_cache() -> &'static Mutex<HashSet<[u8; 32]>> {
+    static CACHE: OnceLock<Mutex<HashSet<[u8; 32]>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+/// VerifyBatch returns true if every check holds
+///
+/// Checks found in the signature cache are skipped, ed25519 checks are batch
+/// verified and the rest run on the rayon thread pool. A failed batch only
+/// tells that some signature is bad, so every check is then verified on its
+/// own; the result is the same however the checks were chunked. Successful
+/// checks are added to the cache so a transaction verified in the mempool is
+/// not verified again when its block arrives.
+pub fn verify_batch(checks: &[SigCheck]) -> bool {
+    let keyed: Vec<([u8; 32], &SigCheck)> = checks.par_iter().map(|c| (c.cache_key(), c)).collect();
+    let todo: Vec<([u8; 32], &SigCheck)> = {
+        let cache = sig_cache().lock().unwrap();
+        keyed
+            .into_iter()
+            .filter(|(key, _)| !cache.contains(key))
+            .collect()
+    };
+    if todo.is_empty() {
+        return true;
+    }
+    debug!("verify {} of {} signatures", todo.len(), checks.len());
+
+    let (ed25519, others): (Vec<&SigCheck>, Vec<&SigCheck>) = todo
+        .iter()
+        .map(|(_, c)| *c)
+        .partition(|c| c.scheme == SignatureScheme::Ed25519);
+
+    let ok = ed25519
+        .par_chunks(ED25519_BATCH_SIZE)
+        .all(verify_ed25519_batch)
+        && others.par_iter().all(|c| c.verify());
+    if !ok {
+        // 逐个重新验证,找出是哪个输入的签名不对
+        if let Some(bad) = todo.iter().map(|(_, c)| *c).find(|c| !c.verify()) {
+            warn!("invalid {} signature on input {} of {}", bad.scheme, bad.input, bad.txid);
+            return false;
+        }
+    }
+
+    let mut cache = sig_cache().lock().unwrap();
+    for (key, _) in todo {
+        if cache.len() >= SIG_CACHE_SIZE {
+            // 缓存满了就随便丢掉一个旧条目
+            let evict = *cache.iter().next().unwrap();
+            cache.remove(&evict);
+        }
+        cache.insert(key);
+    }
+    true
+}
+
+/// verify_ed25519_batch verifies a chunk of ed25519 checks in one batch equation
+///
+/// Keys and signatures are decoded like SigCheck::verify does, see ed25519_parse.
+fn verify_ed25519_batch(checks: &[&SigCheck]) -> bool {
+    if checks.len() == 1 {
+        return checks[0].verify();
+    }
+    let mut messages = Vec::new();
+    let mut signatures = Vec::new();
+    let mut public_keys = Vec::new();
+    for c in checks {
+        match ed25519_parse(&c.public_key, &c.signature) {
+            Some((pk, sig)) => {
+                messages.push(&c.message[..]);
+                signatures.push(sig);
+                public_keys.push(pk);
+            }
+            None => return false,
+        }
+    }
+    ed25519_dalek::verify_batch(&messages, &signatures, &public_keys).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check(scheme: SignatureScheme, message: &[u8]) -> SigCheck {
+        let (secret_key, public_key) = scheme.generate_keypair();
+        SigCheck {
+            txid: String::from("00"),
+            input: 0,
+            scheme,
+            message: message.to_vec(),
+            signature: scheme.sign_with(&secret_key, message).unwrap(),
+            public_key,
+        }
+    }
+
+    #[test]
+    fn test_verify_batch() {
+        let mut checks: Vec<SigCheck> = (0..5)
+            .map(|i| check(SignatureScheme::Ed25519, format!("msg {}", i).as_bytes()))
+            .collect();
+        checks.push(check(SignatureScheme::SchnorrSecp256k1, b"schnorr"));
+        assert!(verify_batch(&checks));
+        assert!(sig_cache().lock().unwrap().contains(&checks[0].cache_key()));
+
+        let mut bad = check(SignatureScheme::Ed25519, b"bad");
+        bad.message = b"tampered".to_vec();
+        checks.push(bad);
+        assert!(!verify_batch(&checks));
+    }
+
+    #[test]
+    fn test_ed25519_edge_cases() {
+        // S加上群的阶l,签名方程不变但S不是规范形式
+        let mut malleated = check(SignatureScheme::Ed25519, b"malleated");
+        let order = curve25519_dalek::constants::BASEPOINT_ORDER.to_bytes();
+        let mut carry = 0u16;
+        for (byte, o) in malleated.signature[32..].iter_mut().zip(order) {
+            let sum = *byte as u16 + o as u16 + carry;
+            *byte = sum as u8;
+            carry = sum >> 8;
+        }
+        // 单位点做公钥,R是单位点、S为0的签名对任何消息都成立
+        let mut identity = [0u8; 32];
+        identity[0] = 1;
+        let small_order = SigCheck {
+            txid: String::from("11"),
+            input: 1,
+            scheme: SignatureScheme::Ed25519,
+            message: b"any message".to_vec(),
+            public_key: identity.to_vec(),
+            signature: [identity, [0u8; 32]].concat(),
+        };
+
+        // 单独验证和放进一批验证的结果一样
+        for bad in [malleated, small_order] {
+            assert!(!bad.verify());
+            assert!(!verify_batch(std::slice::from_ref(&bad)));
+            let mut batch: Vec<SigCheck> = (0..4)
+                .map(|i| check(SignatureScheme::Ed25519, format!("edge {}", i).as_bytes()))
+                .collect();
+            batch.push(bad);
+            assert!(!verify_batch(&batch));
+            batch.pop();
+            assert!(verify_batch(&batch));
+        }
+    }
+}
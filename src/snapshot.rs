@@ -0,0 +1,344 @@
+//! UTXO set snapshots of the dumputxoset and loadutxoset commands
+//!
+//! A snapshot is the UTXO set at one block. It uses the bootstrap module's
+//! records after its own file header:
+//!
+//! ```text
+//! file header
+//!   4 bytes  magic "RBCU"
+//!   uint32   format version, little-endian (1)
+//! record     the header of the block, canonical encoding
+//! record     uint64 coin count, little-endian || 32 bytes UTXO set hash
+//! record     one per coin, in key order:
+//!   36 bytes   outpoint key, the raw txid and the big-endian vout
+//!   int64      value
+//!   bytes      public key hash
+//!   int32      height of the block that created the coin
+//!   uint8      1 for a coinbase output, 0 otherwise
+//! ```
+//!
+//! The UTXO set hash is the merkle root, built like a block's, over
+//! SHA-256(SHA-256(coin record)) of every coin in key order; an empty set
+//! hashes to 32 zero bytes. A node loads a snapshot only when the chain params
+//! trust that hash for that block, and then starts its chain from the block.
+
+use super::*;
+use crate::block::*;
+use crate::bootstrap::*;
+use crate::chainparams::*;
+use crate::encoding::*;
+use crate::storage::*;
+use crate::transaction::*;
+use crate::utxoset::*;
+use bincode::deserialize;
+use failure::format_err;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+/// Magic bytes at the start of a snapshot file
+const FILE_MAGIC: [u8; 4] = *b"RBCU";
+/// Current snapshot file format version
+const FORMAT_VERSION: u32 = 1;
+/// Number of coins written per batch while loading
+const LOAD_BATCH: usize = 10_000;
+
+/// UtxoStats describes the UTXO set at a block
+#[derive(Debug, Clone, PartialEq)]
+pub struct UtxoStats {
+    pub block_hash: String,
+    pub height: i32,
+    pub coins: u64,
+    pub hash: String,
+}
+
+/// GetUtxoStats counts and hashes the UTXO set, which must be at the chain tip
+pub fn get_utxo_stats<S: ChainStore>(utxo: &UTXOSet<S>) -> Result<UtxoStats> {
+    utxo.flush()?;
+    let bc = &utxo.blockchain;
+    if utxo.best_block()?.as_deref() != Some(bc.tip.as_str()) {
+        return Err(format_err!("ERROR: The UTXO set is not at the chain tip"));
+    }
+    let mut leaves = Vec::new();
+    for kv in bc.store.scan_prefix(Tree::Utxos, &[]) {
+        let (key, value) = kv?;
+        leaves.push(sha256d(&coin_record(&key, &deserialize(&value)?)?).to_vec());
+    }
+    Ok(UtxoStats {
+        block_hash: bc.tip.clone(),
+        height: bc.get_best_height()?,
+        coins: leaves.len() as u64,
+        hash: hex::encode(utxo_hash(leaves)),
+    })
+}
+
+/// DumpUtxoSet writes a snapshot of the UTXO set at the chain tip
+pub fn dump_utxo_set<S: ChainStore>(utxo: &UTXOSet<S>, path: &str) -> Result<UtxoStats> {
+    let stats = get_utxo_stats(utxo)?;
+    let bc = &utxo.blockchain;
+
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(&FILE_MAGIC)?;
+    w.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    write_record(&mut w, &encode(&bc.get_header(&stats.block_hash)?)?)?;
+    let mut meta = stats.coins.to_le_bytes().to_vec();
+    meta.extend_from_slice(&hex::decode(&stats.hash)?);
+    write_record(&mut w, &meta)?;
+    for kv in bc.store.scan_prefix(Tree::Utxos, &[]) {
+        let (key, value) = kv?;
+        write_record(&mut w, &coin_record(&key, &deserialize(&value)?)?)?;
+    }
+    w.flush()?;
+    Ok(stats)
+}
+
+/// LoadUtxoSet starts an empty chain from a snapshot
+///
+/// The snapshot's hash must be the one params trust for its block, unless
+/// force is set. The coins are written in batches and the chain tip is moved
+/// to the snapshot's block last, so an interrupted load leaves an empty chain
+/// whose leftover coins the next start clears.
+pub fn load_utxo_set<S: ChainStore>(
+    utxo: &mut UTXOSet<S>,
+    path: &str,
+    params: &ChainParams,
+    force: bool,
+) -> Result<UtxoStats> {
+    if !utxo.blockchain.tip.is_empty() {
+        return Err(format_err!(
+            "ERROR: A snapshot can only be loaded into an empty chain, this one is at height {}",
+            utxo.blockchain.get_best_height()?
+        ));
+    }
+
+    let mut file = File::open(path)?;
+    let mut head = [0; 8];
+    if file.read_exact(&mut head).is_err() || head[..4] != FILE_MAGIC {
+        return Err(format_err!("ERROR: {} is not a UTXO snapshot", path));
+    }
+    let version = u32::from_le_bytes([head[4], head[5], head[6], head[7]]);
+    if version != FORMAT_VERSION {
+        return Err(format_err!("ERROR: Unknown snapshot format version {}", version));
+    }
+    let mut r = BufReader::new(file);
+    let header: BlockHeader = decode(&next_record(&mut r)?)?;
+    if !header.check_proof_of_work()? {
+        return Err(format_err!("ERROR: Snapshot block {} has a bad proof of work", header.hash));
+    }
+    let meta = next_record(&mut r)?;
+    if meta.len() != 40 {
+        return Err(format_err!("ERROR: Bad snapshot metadata"));
+    }
+    let stats = UtxoStats {
+        block_hash: header.hash.clone(),
+        height: header.height,
+        coins: u64::from_le_bytes([
+            meta[0], meta[1], meta[2], meta[3], meta[4], meta[5], meta[6], meta[7],
+        ]),
+        hash: hex::encode(&meta[8..]),
+    };
+
+    match params.assume_utxo_at(&stats.block_hash) {
+        Some(trusted) if trusted.height != stats.height || trusted.utxo_hash != stats.hash => {
+            return Err(format_err!(
+                "ERROR: Snapshot hash {} differs from {} trusted by the chain params",
+                stats.hash,
+                trusted.utxo_hash
+            ));
+        }
+        Some(_) => {}
+        None if force => warn!("loading untrusted UTXO snapshot of block {}", stats.block_hash),
+        None => {
+            return Err(format_err!(
+                "ERROR: The chain params trust no UTXO hash for block {}",
+                stats.block_hash
+            ));
+        }
+    }
+
+    let store = utxo.blockchain.store.clone();
+    let mut batch = Batch::new();
+    batch.remove(Tree::Metadata, UTXO_BEST_KEY);
+    store.commit(batch)?;
+    store.clear(Tree::Utxos)?;
+    store.clear(Tree::Index)?;
+
+    let mut leaves = Vec::new();
+    let mut batch = Batch::new();
+    while let Some(record) = read_record(&mut r)? {
+        let (key, coin) = read_coin_record(&record)?;
+        leaves.push(sha256d(&record).to_vec());
+        insert_coin(&mut batch, &key, &coin)?;
+        if leaves.len() % LOAD_BATCH == 0 {
+            store.commit(std::mem::take(&mut batch))?;
+        }
+    }
+    store.commit(batch)?;
+
+    if leaves.len() as u64 != stats.coins || hex::encode(utxo_hash(leaves)) != stats.hash {
+        store.clear(Tree::Utxos)?;
+        store.clear(Tree::Index)?;
+        return Err(format_err!("ERROR: The snapshot's coins don't match its hash"));
+    }
+    let mut batch = Batch::new();
+    batch.insert(Tree::Metadata, UTXO_BEST_KEY, &stats.block_hash);
+    utxo.blockchain.start_from_snapshot(&header, batch)?;
+    Ok(stats)
+}
+
+fn next_record<R: Read>(r: &mut R) -> Result<Vec<u8>> {
+    match read_record(r)? {
+        Some(record) => Ok(record),
+        None => Err(format_err!("ERROR: Truncated snapshot")),
+    }
+}
+
+/// utxo_hash is the merkle root over the coin record hashes
+fn utxo_hash(leaves: Vec<Vec<u8>>) -> Vec<u8> {
+    if leaves.is_empty() {
+        return vec![0; 32];
+    }
+    merkle_root(leaves)
+}
+
+/// coin_record encodes a coin of the UTXO set for the snapshot
+fn coin_record(key: &[u8], coin: &Coin) -> Result<Vec<u8>> {
+    let mut w = key.to_vec();
+    coin.output.encode(&mut w)?;
+    write_i32(&mut w, coin.height);
+    w.push(coin.is_coinbase as u8);
+    Ok(w)
+}
+
+fn read_coin_record(data: &[u8]) -> Result<(Vec<u8>, Coin)> {
+    let mut r = Reader::new(data);
+    let key = r.read_slice(36)?.to_vec();
+    let output = TXOutput::decode(&mut r)?;
+    let height = r.read_i32()?;
+    let is_coinbase = match r.read_u8()? {
+        0 => false,
+        1 => true,
+        b => return Err(format_err!("ERROR: Bad coinbase flag {}", b)),
+    };
+    if !r.is_empty() {
+        return Err(format_err!("ERROR: Trailing bytes after coin record"));
+    }
+    Ok((
+        key,
+        Coin {
+            output,
+            height,
+            is_coinbase,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::blockchain::*;
+    use crate::testutil::*;
+
+    fn empty_chain() -> UTXOSet<MemoryStore> {
+        UTXOSet::new(Blockchain::new(&MemoryStore::new()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_coin_record() {
+        let coin = Coin {
+            output: TXOutput {
+                value: 7,
+                pub_key_hash: vec![1; 20],
+            },
+            height: 3,
+            is_coinbase: true,
+        };
+        let key = OutPoint::new(&"ab".repeat(32), 2).to_key().unwrap();
+        let record = coin_record(&key, &coin).unwrap();
+        let (read_key, read_coin) = read_coin_record(&record).unwrap();
+        assert_eq!(read_key, key);
+        assert_eq!(coin_record(&read_key, &read_coin).unwrap(), record);
+
+        assert_eq!(utxo_hash(Vec::new()), vec![0; 32]);
+        let one = utxo_hash(vec![sha256d(&record).to_vec()]);
+        let two = utxo_hash(vec![sha256d(&record).to_vec(), sha256d(b"other").to_vec()]);
+        assert_ne!(one, two);
+    }
+
+    #[test]
+    fn test_dump_load() {
+        let mut chain = TestChain::new();
+        let alice = chain.address();
+        let bob = chain.address();
+        let miner = chain.miner.clone();
+        let tx = chain.send(&miner, &alice, 3);
+        chain.mine(vec![tx]);
+        let tip = chain.mine(vec![]);
+        let stats = get_utxo_stats(&chain.utxo).unwrap();
+        assert_eq!((stats.block_hash.as_str(), stats.height), (tip.get_hash().as_str(), 2));
+        // 创世区块的coinbase花掉了,剩两个coinbase、alice的3和找零
+        assert_eq!(stats.coins, 4);
+
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("utxoset-{}.dat", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert_eq!(dump_utxo_set(&chain.utxo, path).unwrap(), stats);
+
+        // 链参数不信任这个哈希就不加载,链还是空的
+        let mut utxo = empty_chain();
+        let empty = get_utxo_stats(&utxo).unwrap();
+        assert_eq!((empty.coins, empty.hash), (0, "00".repeat(32)));
+        let err = load_utxo_set(&mut utxo, path, &ChainParams::default(), false).unwrap_err();
+        assert!(err.to_string().contains("trust no UTXO hash"), "{}", err);
+        let mut params = ChainParams::default();
+        params.assume_utxo.push(AssumeUtxo {
+            height: stats.height,
+            block_hash: stats.block_hash.clone(),
+            utxo_hash: "00".repeat(32),
+        });
+        let err = load_utxo_set(&mut utxo, path, &params, false).unwrap_err();
+        assert!(err.to_string().contains("differs from"), "{}", err);
+        assert!(utxo.blockchain.tip.is_empty());
+        params.assume_utxo[0].utxo_hash = stats.hash.clone();
+
+        // 改了一个币的金额,记录本身是好的,但和元数据里的哈希对不上,写进去的币要清掉
+        let data = std::fs::read(path).unwrap();
+        let mut r = &data[8..];
+        let mut tampered = data[..8].to_vec();
+        let mut n = 0;
+        while let Some(mut record) = read_record(&mut r).unwrap() {
+            n += 1;
+            if n == 3 {
+                let (key, mut coin) = read_coin_record(&record).unwrap();
+                coin.output.value += 1;
+                record = coin_record(&key, &coin).unwrap();
+            }
+            write_record(&mut tampered, &record).unwrap();
+        }
+        let part = dir.join(format!("utxoset-{}.part", std::process::id()));
+        let part = part.to_str().unwrap();
+        std::fs::write(part, &tampered).unwrap();
+        let err = load_utxo_set(&mut utxo, part, &params, false).unwrap_err();
+        assert!(err.to_string().contains("don't match its hash"), "{}", err);
+        assert!(utxo.blockchain.tip.is_empty());
+        assert_eq!(utxo.blockchain.store.scan_prefix(Tree::Utxos, &[]).count(), 0);
+        assert_eq!(utxo.blockchain.store.scan_prefix(Tree::Index, &[]).count(), 0);
+
+        assert_eq!(load_utxo_set(&mut utxo, path, &params, false).unwrap(), stats);
+        assert_eq!(get_utxo_stats(&utxo).unwrap(), stats);
+        assert_eq!(utxo.blockchain.tip, tip.get_hash());
+        let coins = utxo.find_UTXO(&pub_key_hash(&alice)).unwrap();
+        assert_eq!(coins.iter().map(|(_, coin)| coin.output.value).sum::<i32>(), 3);
+
+        // 快照之上接着加区块,花的是快照里的币
+        let tx = chain.send(&alice, &bob, 1);
+        let block = chain.mine(vec![tx]);
+        utxo.add_block(block.clone()).unwrap();
+        utxo.add_block(chain.mine(vec![])).unwrap();
+        assert_eq!(utxo.blockchain.get_best_height().unwrap(), 4);
+        assert_eq!(get_utxo_stats(&utxo).unwrap(), get_utxo_stats(&chain.utxo).unwrap());
+        assert_eq!(utxo.find_UTXO(&pub_key_hash(&bob)).unwrap().len(), 1);
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(part).unwrap();
+    }
+}
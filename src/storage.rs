@@ -0,0 +1,316 @@
+//! storage backends of the chain state
+//!
+//! Blockchain and UTXOSet only talk to a ChainStore: a few named trees of
+//! ordered byte keys plus atomic batches. `Storage` keeps them in sled on disk,
+//! `MemoryStore` keeps them in a map and is what the tests use.
+
+use super::*;
+use crate::schema::*;
+use failure::format_err;
+use sled::Transactional;
+use sled::transaction::TransactionError;
+use std::path::Path;
+#[cfg(test)]
+use std::collections::{BTreeMap, HashMap};
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+/// Directory of the node's database
+pub const DATA_DIR: &str = "data/db";
+
+/// Tree names one keyspace of a ChainStore
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Tree {
+    /// block hash -> bincode Block
+    Blocks,
+    /// outpoint key -> bincode Coin
+    Utxos,
+    /// address index of the UTXO set: len(pkh) || pkh || outpoint key -> ()
+    Index,
+    /// chain metadata such as the `LAST` block hash and the schema version
+    Metadata,
+    /// big-endian u32 height -> hash of the best chain's block at that height
+    Heights,
+    /// raw txid -> bincode TxLocation in the best chain
+    TxIndex,
+    /// block hash -> bincode list of the (outpoint key, Coin) the block spent
+    Undo,
+    /// block hash -> bincode BlockHeader of a block whose transactions were pruned
+    Headers,
+    /// block hash -> why the block is invalid, see Blockchain::invalidate_block
+    Invalid,
+}
+
+impl Tree {
+    pub const ALL: [Tree; 9] = [
+        Tree::Blocks,
+        Tree::Utxos,
+        Tree::Index,
+        Tree::Metadata,
+        Tree::Heights,
+        Tree::TxIndex,
+        Tree::Undo,
+        Tree::Headers,
+        Tree::Invalid,
+    ];
+
+    /// Name is the name of the sled tree holding this keyspace
+    pub fn name(self) -> &'static str {
+        match self {
+            Tree::Blocks => "blocks",
+            Tree::Utxos => "utxos",
+            Tree::Index => "index",
+            Tree::Metadata => "metadata",
+            Tree::Heights => "heights",
+            Tree::TxIndex => "txindex",
+            Tree::Undo => "undo",
+            Tree::Headers => "headers",
+            Tree::Invalid => "invalid",
+        }
+    }
+}
+
+/// KvIter iterates the (key, value) pairs of a tree in key order
+pub type KvIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// Batch collects writes that a ChainStore applies all together or not at all
+#[derive(Debug, Default)]
+pub struct Batch {
+    ops: Vec<(Tree, Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch::default()
+    }
+
+    pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, tree: Tree, key: K, value: V) {
+        self.ops
+            .push((tree, key.as_ref().to_vec(), Some(value.as_ref().to_vec())));
+    }
+
+    pub fn remove<K: AsRef<[u8]>>(&mut self, tree: Tree, key: K) {
+        self.ops.push((tree, key.as_ref().to_vec(), None));
+    }
+}
+
+/// ChainStore is the storage behind Blockchain and UTXOSet
+///
+/// Clones share the same underlying data.
+pub trait ChainStore: Clone + Send + Sync + 'static {
+    fn get(&self, tree: Tree, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    /// ScanPrefix iterates the entries whose key starts with prefix; an empty prefix walks the whole tree
+    fn scan_prefix(&self, tree: Tree, prefix: &[u8]) -> KvIter<'_>;
+    /// Commit applies every write of the batch atomically
+    fn commit(&self, batch: Batch) -> Result<()>;
+    fn clear(&self, tree: Tree) -> Result<()>;
+    fn flush(&self) -> Result<()>;
+
+    fn contains(&self, tree: Tree, key: &[u8]) -> Result<bool> {
+        Ok(self.get(tree, key)?.is_some())
+    }
+}
+
+/// Storage is one open sled database split into trees
+///
+/// Cloning a Storage is cheap and every clone shares the same database, so the
+/// database is opened once per process and handed to every component.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    db: sled::Db,
+    /// one sled tree per Tree, in the order of Tree::ALL
+    trees: Vec<sled::Tree>,
+    /// address -> bincode Wallet, plus the wallet schema version
+    pub wallets: sled::Tree,
+}
+
+impl Storage {
+    /// Open opens (or creates) the database at path
+    pub fn open(path: &str) -> Result<Storage> {
+        Storage::from_db(sled::open(path)?)
+    }
+
+    /// OpenDefault opens the database in DATA_DIR
+    ///
+    /// Wallets and blocks still in the separate databases used before DATA_DIR
+    /// are imported on the way, see schema::import_legacy.
+    pub fn open_default() -> Result<Storage> {
+        let storage = Storage::open(DATA_DIR)?;
+        import_legacy(
+            &storage,
+            Path::new(LEGACY_WALLETS_DIR),
+            Path::new(LEGACY_BLOCKS_DIR),
+        )?;
+        Ok(storage)
+    }
+
+    /// Temporary opens a database that is deleted when the last clone is dropped
+    #[cfg(test)]
+    pub fn temporary() -> Result<Storage> {
+        Storage::from_db(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn from_db(db: sled::Db) -> Result<Storage> {
+        let trees = Tree::ALL
+            .iter()
+            .map(|t| db.open_tree(t.name()))
+            .collect::<sled::Result<Vec<sled::Tree>>>()?;
+        Ok(Storage {
+            trees,
+            wallets: db.open_tree("wallets")?,
+            db,
+        })
+    }
+
+    fn tree(&self, tree: Tree) -> &sled::Tree {
+        &self.trees[tree as usize]
+    }
+
+    /// OpenTree opens a scratch tree outside of the ChainStore trees
+    pub fn open_tree(&self, name: &str) -> Result<sled::Tree> {
+        Ok(self.db.open_tree(name)?)
+    }
+
+    /// DropTree deletes a scratch tree opened by open_tree
+    pub fn drop_tree(&self, name: &str) -> Result<()> {
+        self.db.drop_tree(name)?;
+        Ok(())
+    }
+}
+
+impl ChainStore for Storage {
+    fn get(&self, tree: Tree, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.tree(tree).get(key)?.map(|v| v.to_vec()))
+    }
+
+    fn scan_prefix(&self, tree: Tree, prefix: &[u8]) -> KvIter<'_> {
+        Box::new(self.tree(tree).scan_prefix(prefix).map(|kv| {
+            let (k, v) = kv?;
+            Ok((k.to_vec(), v.to_vec()))
+        }))
+    }
+
+    fn commit(&self, batch: Batch) -> Result<()> {
+        let res: std::result::Result<(), TransactionError<()>> =
+            self.trees[..].transaction(|trees| {
+                for (tree, key, value) in &batch.ops {
+                    let t = &trees[*tree as usize];
+                    match value {
+                        Some(v) => t.insert(&key[..], &v[..])?,
+                        None => t.remove(&key[..])?,
+                    };
+                }
+                Ok(())
+            });
+        match res {
+            Ok(()) => Ok(()),
+            Err(TransactionError::Abort(())) => Err(format_err!("ERROR: Batch aborted")),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+
+    fn clear(&self, tree: Tree) -> Result<()> {
+        self.tree(tree).clear()?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+/// MemTree is one keyspace of a MemoryStore
+#[cfg(test)]
+type MemTree = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// MemoryStore keeps the chain state in memory, nothing survives the process
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    trees: Arc<Mutex<HashMap<Tree, MemTree>>>,
+}
+
+#[cfg(test)]
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+#[cfg(test)]
+impl ChainStore for MemoryStore {
+    fn get(&self, tree: Tree, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let trees = self.trees.lock().unwrap();
+        Ok(trees.get(&tree).and_then(|t| t.get(key).cloned()))
+    }
+
+    fn scan_prefix(&self, tree: Tree, prefix: &[u8]) -> KvIter<'_> {
+        // 持有锁的迭代器不好返回,直接拷贝一份匹配的条目
+        let trees = self.trees.lock().unwrap();
+        let items: Vec<Result<(Vec<u8>, Vec<u8>)>> = match trees.get(&tree) {
+            Some(t) => t
+                .range(prefix.to_vec()..)
+                .take_while(|(k, _)| k.starts_with(prefix))
+                .map(|(k, v)| Ok((k.clone(), v.clone())))
+                .collect(),
+            None => Vec::new(),
+        };
+        Box::new(items.into_iter())
+    }
+
+    fn commit(&self, batch: Batch) -> Result<()> {
+        let mut trees = self.trees.lock().unwrap();
+        for (tree, key, value) in batch.ops {
+            let t = trees.entry(tree).or_default();
+            match value {
+                Some(v) => t.insert(key, v),
+                None => t.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn clear(&self, tree: Tree) -> Result<()> {
+        self.trees.lock().unwrap().remove(&tree);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check_store<S: ChainStore>(store: S) {
+        let mut batch = Batch::new();
+        batch.insert(Tree::Index, b"ab1", b"1");
+        batch.insert(Tree::Index, b"ab2", b"2");
+        batch.insert(Tree::Index, b"b", b"3");
+        batch.insert(Tree::Metadata, b"ab1", b"meta");
+        batch.remove(Tree::Index, b"ab2");
+        store.commit(batch).unwrap();
+
+        let found: Vec<(Vec<u8>, Vec<u8>)> = store
+            .scan_prefix(Tree::Index, b"ab")
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(found, vec![(b"ab1".to_vec(), b"1".to_vec())]);
+        assert_eq!(store.scan_prefix(Tree::Index, b"").count(), 2);
+        assert_eq!(store.get(Tree::Metadata, b"ab1").unwrap(), Some(b"meta".to_vec()));
+        assert!(!store.contains(Tree::Blocks, b"ab1").unwrap());
+
+        store.clear(Tree::Index).unwrap();
+        assert_eq!(store.scan_prefix(Tree::Index, b"").count(), 0);
+        assert!(store.contains(Tree::Metadata, b"ab1").unwrap());
+    }
+
+    #[test]
+    fn test_stores_agree() {
+        check_store(MemoryStore::new());
+        check_store(Storage::temporary().unwrap());
+    }
+}
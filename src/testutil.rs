@@ -0,0 +1,93 @@
+//! helpers for tests that need a real chain
+//!
+//! Test builds mine with an easy TARGET_HEXS, so the blocks of a test chain
+//! take a few dozen hashes each.
+
+use crate::Result;
+use crate::block::*;
+use crate::blockchain::*;
+use crate::keys::*;
+use crate::storage::*;
+use crate::transaction::*;
+use crate::utxoset::*;
+use crate::wallets::*;
+use bincode::serialize;
+use std::collections::BTreeMap;
+
+/// TestChain is a chain in a MemoryStore with a wallet to mine and send coins
+pub struct TestChain {
+    pub utxo: UTXOSet<MemoryStore>,
+    pub wallets: Wallets,
+    /// address the genesis block and mined blocks pay
+    pub miner: String,
+}
+
+impl TestChain {
+    pub fn new() -> TestChain {
+        let mut wallets = Wallets::new(&Storage::temporary().unwrap()).unwrap();
+        wallets.create_seed().unwrap();
+        let miner = wallets.create_wallet(SignatureScheme::Ed25519).unwrap();
+        let bc = Blockchain::create_blockchain(miner.clone(), &MemoryStore::new()).unwrap();
+        TestChain {
+            utxo: UTXOSet::new(bc).unwrap(),
+            wallets,
+            miner,
+        }
+    }
+
+    /// Address derives a new receive address of the wallet
+    pub fn address(&mut self) -> String {
+        self.wallets.create_wallet(SignatureScheme::Ed25519).unwrap()
+    }
+
+    /// Mine connects a block of a coinbase paying the miner followed by txs
+    pub fn mine(&mut self, txs: Vec<Transaction>) -> Block {
+        let mut all = vec![Transaction::new_coinbase(self.miner.clone(), String::new()).unwrap()];
+        all.extend(txs);
+        self.utxo.mine_block(all).unwrap()
+    }
+
+    /// MineOn mines a block of a lone coinbase on top of prev without connecting it
+    pub fn mine_on(&self, prev: &Block) -> Block {
+        let coinbase = Transaction::new_coinbase(self.miner.clone(), String::new()).unwrap();
+        Block::new_block(vec![coinbase], prev.get_hash(), prev.get_height() + 1).unwrap()
+    }
+
+    /// Send signs a transaction paying amount from a wallet address, change goes to a new change address
+    pub fn send(&mut self, from: &str, to: &str, amount: i32) -> Transaction {
+        let wallet = self.wallets.get_wallet(from).unwrap();
+        let change = self.wallets.create_change_address(SignatureScheme::Ed25519).unwrap();
+        Transaction::new_UTXO(&wallet, to, amount, &change, &self.utxo).unwrap()
+    }
+
+    /// Balance sums the coins of address in the UTXO set
+    pub fn balance(&self, address: &str) -> i32 {
+        let coins = self.utxo.find_UTXO(&pub_key_hash(address)).unwrap();
+        coins.iter().map(|(_, coin)| coin.output.value).sum()
+    }
+}
+
+/// AssertUTXOSetMatches checks that the stored UTXO set equals the one find_UTXO rebuilds from the chain
+pub fn assert_utxo_set_matches(utxo: &UTXOSet<MemoryStore>) {
+    utxo.flush().unwrap();
+    let bc = &utxo.blockchain;
+    assert_eq!(utxo.best_block().unwrap().as_deref(), Some(bc.tip.as_str()));
+    let stored: BTreeMap<Vec<u8>, Vec<u8>> = bc
+        .store
+        .scan_prefix(Tree::Utxos, &[])
+        .collect::<Result<_>>()
+        .unwrap();
+    let rebuilt: BTreeMap<Vec<u8>, Vec<u8>> = bc
+        .find_UTXO()
+        .unwrap()
+        .iter()
+        .map(|(outpoint, coin)| (outpoint.to_key().unwrap(), serialize(coin).unwrap()))
+        .collect();
+    assert_eq!(stored, rebuilt);
+    assert_eq!(bc.store.scan_prefix(Tree::Index, &[]).count(), rebuilt.len());
+}
+
+/// PubKeyHash decodes the public key hash of an address
+pub fn pub_key_hash(address: &str) -> Vec<u8> {
+    bitcoincash_addr::Address::decode(address).unwrap().body
+}
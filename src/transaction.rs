@@ -1,22 +1,35 @@
 use super::*;
+use crate::encoding::*;
+use crate::keys::*;
+use crate::sigcheck::*;
+use crate::storage::*;
 use crate::utxoset::*;
 use crate::wallets::*;
-use bincode::serialize;
 use bitcoincash_addr::Address;
-use crypto::digest::Digest;
-use crypto::ed25519;
-use crypto::sha2::Sha256;
 use failure::format_err;
 use rand::Rng;  // 用于生成随机数
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt;
 pub const SUBSIDY: i32 = 10;
+/// Tag in front of the witness commitment at the end of the coinbase data
+pub const WITNESS_COMMITMENT_MAGIC: [u8; 4] = [0xaa, 0x21, 0xa9, 0xed];
 /// TXInput represents a transaction input
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXInput {
     pub txid: String,
     pub vout: i32,
+    pub scheme: SignatureScheme,
+    /// Data only carries the coinbase message, spending inputs leave it empty
+    pub data: Vec<u8>,
+}
+
+/// TXWitness holds the signature and public key that unlock one input
+///
+/// Witnesses are not covered by the txid, so a transaction's id is known
+/// before it is signed and can't be changed by re-encoding the signature.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TXWitness {
     pub signature: Vec<u8>,
     pub pub_key: Vec<u8>,
 }
@@ -29,23 +42,27 @@ pub struct TXOutput {
     pub pub_key_hash: Vec<u8>,
 }
 
-// TXOutputs collects TXOutput
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct TXOutputs {
-    pub outputs: Vec<TXOutput>,
-}
 /// Transaction represents a Bitcoin transaction
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
+    pub version: i32,
     pub id: String,
     pub vin: Vec<TXInput>,
     pub vout: Vec<TXOutput>,
+    /// Witness holds one entry per input, it is empty for coinbase transactions
+    pub witness: Vec<TXWitness>,
 }
 
 
 impl Transaction {
-    /// NewUTXOTransaction creates a new transaction
-    pub fn new_UTXO(wallet: &Wallet, to: &str, amount: i32, utxo: &UTXOSet) -> Result<Transaction> {
+    /// NewUTXOTransaction creates a new transaction, any change goes to the change address
+    pub fn new_UTXO<S: ChainStore>(
+        wallet: &Wallet,
+        to: &str,
+        amount: i32,
+        change: &str,
+        utxo: &UTXOSet<S>,
+    ) -> Result<Transaction> {
         info!(
             "new UTXO Transaction from: {} to: {}",
             wallet.get_address(),
@@ -71,8 +88,8 @@ impl Transaction {
                 let input = TXInput {
                     txid: tx.0.clone(),
                     vout: out,
-                    signature: Vec::new(),
-                    pub_key: wallet.public_key.clone(),
+                    scheme: wallet.scheme,
+                    data: Vec::new(),
                 };
                 vin.push(input);
             }
@@ -80,17 +97,19 @@ impl Transaction {
 
         let mut vout = vec![TXOutput::new(amount, to.to_string())?];
         if acc_v.0 > amount {
-            vout.push(TXOutput::new(acc_v.0 - amount, wallet.get_address())?)
+            vout.push(TXOutput::new(acc_v.0 - amount, change.to_string())?)
         }
 
         let mut tx = Transaction {
+            version: TX_VERSION,
             id: String::new(),
             vin,
             vout,
+            witness: Vec::new(),
         };
+        // txid不包含见证数据,签名前后都不会变
         tx.id = tx.hash()?;
-        utxo.blockchain
-            .sign_transacton(&mut tx, &wallet.secret_key)?;
+        utxo.blockchain.sign_transacton(&mut tx, wallet)?;
         Ok(tx)
     }
 
@@ -104,18 +123,20 @@ impl Transaction {
             rand.fill_bytes(&mut key);
             data = format!("Reward to '{}'", to);
         }
-        let mut pub_key = Vec::from(data.as_bytes());
-        pub_key.append(&mut Vec::from(key));
+        let mut coinbase_data = Vec::from(data.as_bytes());
+        coinbase_data.append(&mut Vec::from(key));
 
         let mut tx = Transaction {
+            version: TX_VERSION,
             id: String::new(),
             vin: vec![TXInput {
                 txid: String::new(),
                 vout: -1,
-                signature: Vec::new(),
-                pub_key,
+                scheme: SignatureScheme::default(),
+                data: coinbase_data,
             }],
             vout: vec![TXOutput::new(SUBSIDY, to)?],
+            witness: Vec::new(),
         };
         tx.id = tx.hash()?;
         Ok(tx)
@@ -134,104 +155,171 @@ impl Transaction {
         if self.is_coinbase() {
             return Ok(true);
         }
+        Ok(verify_batch(&self.signature_checks(&prev_TXs)?))
+    }
 
-        for vin in &self.vin {
-            if prev_TXs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct"));
-            }
+    /// SignatureChecks returns the signature check of every input without running it
+    pub fn signature_checks(&self, prev_TXs: &HashMap<String, Transaction>) -> Result<Vec<SigCheck>> {
+        if self.is_coinbase() {
+            return Ok(Vec::new());
+        }
+        if self.witness.len() != self.vin.len() {
+            return Err(format_err!(
+                "ERROR: Transaction {} has {} witnesses for {} inputs",
+                self.id,
+                self.witness.len(),
+                self.vin.len()
+            ));
         }
 
-        let mut tx_copy = self.trim_copy();
-
+        let mut checks = Vec::new();
         for in_id in 0..self.vin.len() {
-            let prev_Tx = prev_TXs.get(&self.vin[in_id].txid).unwrap();
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_Tx.vout[self.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[in_id].pub_key = Vec::new();
-
-            if !ed25519::verify(
-                &tx_copy.id.as_bytes(),
-                &self.vin[in_id].pub_key,
-                &self.vin[in_id].signature,
-            ) {
-                return Ok(false);
+            let prev_out = self.prev_output(in_id, prev_TXs)?;
+            let witness = &self.witness[in_id];
+            let mut pub_key_hash = witness.pub_key.clone();
+            hash_pub_key(&mut pub_key_hash);
+            if !prev_out.is_locked_with_key(&pub_key_hash) {
+                return Err(format_err!(
+                    "ERROR: Input {} of {} is not signed by the owner of the spent output",
+                    in_id,
+                    self.id
+                ));
             }
+
+            // 每个输入按自己声明的签名方案来验签
+            checks.push(SigCheck {
+                txid: self.id.clone(),
+                input: in_id,
+                scheme: self.vin[in_id].scheme,
+                message: self.sighash(in_id, prev_out)?,
+                public_key: witness.pub_key.clone(),
+                signature: witness.signature.clone(),
+            });
         }
 
-        Ok(true)
+        Ok(checks)
     }
 
+    /// Sign fills in the witness of every input, all of which must belong to signer
     pub fn sign(
         &mut self,
-        private_key: &[u8],
+        signer: &dyn Signer,
         prev_TXs: HashMap<String, Transaction>,
     ) -> Result<()> {
         if self.is_coinbase() {
             return Ok(());
         }
 
-        for vin in &self.vin {
-            if prev_TXs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct"));
+        let mut signer_hash = signer.public_key();
+        hash_pub_key(&mut signer_hash);
+
+        let mut witness = Vec::new();
+        for in_id in 0..self.vin.len() {
+            let prev_out = self.prev_output(in_id, &prev_TXs)?;
+            if self.vin[in_id].scheme != signer.scheme() || !prev_out.is_locked_with_key(&signer_hash) {
+                return Err(format_err!(
+                    "ERROR: Input {} expects a {} signature from another key, signer uses {}",
+                    in_id,
+                    self.vin[in_id].scheme,
+                    signer.scheme()
+                ));
             }
+            witness.push(TXWitness {
+                signature: signer.sign(&self.sighash(in_id, prev_out)?)?,
+                pub_key: signer.public_key(),
+            });
         }
+        self.witness = witness;
 
-        let mut tx_copy = self.trim_copy();
-
-        for in_id in 0..tx_copy.vin.len() {
-            let prev_Tx = prev_TXs.get(&tx_copy.vin[in_id].txid).unwrap();
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_Tx.vout[tx_copy.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[in_id].pub_key = Vec::new();
-            let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
-            self.vin[in_id].signature = signature.to_vec();
+        Ok(())
+    }
+
+    /// prev_output returns the output spent by input in_id
+    fn prev_output<'a>(
+        &self,
+        in_id: usize,
+        prev_TXs: &'a HashMap<String, Transaction>,
+    ) -> Result<&'a TXOutput> {
+        let vin = &self.vin[in_id];
+        match prev_TXs.get(&vin.txid) {
+            Some(prev_Tx) if !prev_Tx.id.is_empty() => {
+                prev_Tx.vout.get(vin.vout as usize).ok_or_else(|| {
+                    format_err!("ERROR: Previous output {}:{} does not exist", vin.txid, vin.vout)
+                })
+            }
+            _ => Err(format_err!("ERROR: Previous transaction is not correct")),
         }
+    }
 
-        Ok(())
+    /// Sighash returns the message signed by input in_id, see the encoding module
+    pub fn sighash(&self, in_id: usize, prev_out: &TXOutput) -> Result<Vec<u8>> {
+        let mut w = Vec::new();
+        self.encode_base(&mut w)?;
+        write_i32(&mut w, in_id as i32);
+        write_bytes(&mut w, &prev_out.pub_key_hash);
+        write_i64(&mut w, prev_out.value as i64);
+        Ok(sha256d(&w).to_vec())
     }
-    
+
     /// Hash returns the hash of the Transaction
+    ///
+    /// The hash is taken over the canonical encoding without witnesses, see the encoding module.
     pub fn hash(&self) -> Result<String> {
-        let mut copy = self.clone();
-        copy.id = String::new();
-        let data = serialize(&copy)?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        Ok(hasher.result_str())
+        let mut w = Vec::new();
+        self.encode_base(&mut w)?;
+        Ok(hex::encode(sha256d(&w)))
     }
 
-    /// TrimmedCopy creates a trimmed copy of Transaction to be used in signing
-    fn trim_copy(&self) -> Transaction {
-        let mut vin = Vec::new();
-        let mut vout = Vec::new();
+    /// WitnessHash returns the wtxid, which also commits to the witnesses
+    ///
+    /// The coinbase wtxid is all zeros, like in Bitcoin.
+    pub fn witness_hash(&self) -> Result<String> {
+        if self.is_coinbase() {
+            return Ok(hex::encode([0u8; 32]));
+        }
+        Ok(hex::encode(sha256d(&encode(self)?)))
+    }
 
-        for v in &self.vin {
-            vin.push(TXInput {
-                txid: v.txid.clone(),
-                vout: v.vout.clone(),
-                signature: Vec::new(),
-                pub_key: Vec::new(),
-            })
+    /// WitnessCommitment returns the commitment stored at the end of the coinbase data
+    pub fn witness_commitment(&self) -> Option<&[u8]> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        let data = &self.vin[0].data;
+        if data.len() >= 36 && data[data.len() - 36..data.len() - 32] == WITNESS_COMMITMENT_MAGIC {
+            Some(&data[data.len() - 32..])
+        } else {
+            None
         }
+    }
 
-        for v in &self.vout {
-            vout.push(TXOutput {
-                value: v.value,
-                pub_key_hash: v.pub_key_hash.clone(),
-            })
+    /// SetWitnessCommitment stores the witness merkle root in the coinbase and updates its id
+    pub fn set_witness_commitment(&mut self, root: &[u8]) -> Result<()> {
+        if !self.is_coinbase() {
+            return Err(format_err!("ERROR: Only coinbase transactions carry a witness commitment"));
+        }
+        if self.witness_commitment().is_some() {
+            let len = self.vin[0].data.len();
+            self.vin[0].data.truncate(len - 36);
         }
+        self.vin[0].data.extend_from_slice(&WITNESS_COMMITMENT_MAGIC);
+        self.vin[0].data.extend_from_slice(root);
+        self.id = self.hash()?;
+        Ok(())
+    }
 
-        Transaction {
-            id: self.id.clone(),
-            vin,
-            vout,
+    /// encode_base writes the transaction without its witnesses
+    fn encode_base(&self, w: &mut Vec<u8>) -> Result<()> {
+        write_i32(w, self.version);
+        write_varint(w, self.vin.len() as u64);
+        for vin in &self.vin {
+            vin.encode(w)?;
         }
+        write_varint(w, self.vout.len() as u64);
+        for vout in &self.vout {
+            vout.encode(w)?;
+        }
+        Ok(())
     }
     fn pub_key_hash_to_address(&self, pub_key_hash: &[u8]) -> String {
         use bitcoincash_addr::{Address, Scheme, HashType};
@@ -254,6 +342,93 @@ impl Transaction {
         })
     }
 }
+impl Encodable for TXInput {
+    fn encode(&self, w: &mut Vec<u8>) -> Result<()> {
+        write_hash(w, &self.txid)?;
+        write_i32(w, self.vout);
+        w.push(self.scheme.to_byte());
+        write_bytes(w, &self.data);
+        Ok(())
+    }
+}
+
+impl Decodable for TXInput {
+    fn decode(r: &mut Reader) -> Result<Self> {
+        Ok(TXInput {
+            txid: r.read_hash()?,
+            vout: r.read_i32()?,
+            scheme: SignatureScheme::from_byte(r.read_u8()?)?,
+            data: r.read_bytes()?,
+        })
+    }
+}
+
+impl Encodable for TXOutput {
+    fn encode(&self, w: &mut Vec<u8>) -> Result<()> {
+        write_i64(w, self.value as i64);
+        write_bytes(w, &self.pub_key_hash);
+        Ok(())
+    }
+}
+
+impl Decodable for TXOutput {
+    fn decode(r: &mut Reader) -> Result<Self> {
+        let value = r.read_i64()?;
+        if value < 0 || value > i32::MAX as i64 {
+            return Err(format_err!("ERROR: Output value {} out of range", value));
+        }
+        Ok(TXOutput {
+            value: value as i32,
+            pub_key_hash: r.read_bytes()?,
+        })
+    }
+}
+
+impl Encodable for Transaction {
+    fn encode(&self, w: &mut Vec<u8>) -> Result<()> {
+        self.encode_base(w)?;
+        write_varint(w, self.witness.len() as u64);
+        for witness in &self.witness {
+            write_bytes(w, &witness.signature);
+            write_bytes(w, &witness.pub_key);
+        }
+        Ok(())
+    }
+}
+
+impl Decodable for Transaction {
+    fn decode(r: &mut Reader) -> Result<Self> {
+        let version = r.read_i32()?;
+        if version != TX_VERSION {
+            return Err(format_err!("ERROR: Unknown transaction version {}", version));
+        }
+        let mut vin = Vec::new();
+        for _ in 0..r.read_varint()? {
+            vin.push(TXInput::decode(r)?);
+        }
+        let mut vout = Vec::new();
+        for _ in 0..r.read_varint()? {
+            vout.push(TXOutput::decode(r)?);
+        }
+        let mut witness = Vec::new();
+        for _ in 0..r.read_varint()? {
+            witness.push(TXWitness {
+                signature: r.read_bytes()?,
+                pub_key: r.read_bytes()?,
+            });
+        }
+        let mut tx = Transaction {
+            version,
+            id: String::new(),
+            vin,
+            vout,
+            witness,
+        };
+        tx.id = tx.hash()?;
+        Ok(tx)
+    }
+}
+
 // impl TXInput {
 //     /// UsesKey checks whether the address initiated the transaction
 //     pub fn uses_key(&self, pub_key_hash: &[u8]) -> bool {
@@ -312,7 +487,8 @@ impl fmt::Display for Transaction {
                     if input.txid.len() >= 16 { &input.txid[input.txid.len()-8..] } else { "" }
                 )?;
                 writeln!(f, "     📍 输出索引: {}", input.vout)?;
-                writeln!(f, "     🔐 签名长度: {} bytes", input.signature.len())?;
+                let sig_len = self.witness.get(i).map(|w| w.signature.len()).unwrap_or(0);
+                writeln!(f, "     🔐 签名长度: {} bytes ({})", sig_len, input.scheme)?;
             }
         }
         
@@ -1,17 +1,373 @@
 use super::*;
 use crate::block::*;
 use crate::blockchain::*;
+use crate::coinscache::*;
 use crate::transaction::*;
 use bincode::{deserialize, serialize};
-use sled;
-use std::collections::HashMap;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use crate::storage::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+
+/// OutPoint identifies one output of a transaction
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OutPoint {
+    pub txid: String,
+    pub vout: i32,
+}
+
+/// Coin is an unspent output together with where it was created
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Coin {
+    pub output: TXOutput,
+    pub height: i32,
+    pub is_coinbase: bool,
+}
+
+impl OutPoint {
+    pub fn new(txid: &str, vout: i32) -> OutPoint {
+        OutPoint {
+            txid: txid.to_string(),
+            vout,
+        }
+    }
+
+    /// ToKey encodes the outpoint as 32 raw txid bytes followed by the big-endian vout
+    pub fn to_key(&self) -> Result<Vec<u8>> {
+        let mut key = hex::decode(&self.txid)?;
+        key.extend_from_slice(&(self.vout as u32).to_be_bytes());
+        Ok(key)
+    }
+
+    pub fn from_key(key: &[u8]) -> Result<OutPoint> {
+        if key.len() != 36 {
+            return Err(format_err!("ERROR: Invalid outpoint key"));
+        }
+        let mut vout = [0; 4];
+        vout.copy_from_slice(&key[32..]);
+        Ok(OutPoint {
+            txid: hex::encode(&key[..32]),
+            vout: u32::from_be_bytes(vout) as i32,
+        })
+    }
+}
+
+/// address_prefix is the address index prefix of every outpoint locked to pub_key_hash
+fn address_prefix(pub_key_hash: &[u8]) -> Vec<u8> {
+    let mut prefix = vec![pub_key_hash.len() as u8];
+    prefix.extend_from_slice(pub_key_hash);
+    prefix
+}
+
+fn address_key(pub_key_hash: &[u8], outpoint_key: &[u8]) -> Vec<u8> {
+    let mut key = address_prefix(pub_key_hash);
+    key.extend_from_slice(outpoint_key);
+    key
+}
+
+/// InsertCoin adds to batch the writes storing coin and indexing it by address
+pub fn insert_coin(batch: &mut Batch, key: &[u8], coin: &Coin) -> Result<()> {
+    batch.insert(Tree::Index, address_key(&coin.output.pub_key_hash, key), []);
+    batch.insert(Tree::Utxos, key, serialize(coin)?);
+    Ok(())
+}
+
+/// RemoveCoin adds to batch the writes deleting coin and its address index entry
+pub fn remove_coin(batch: &mut Batch, key: &[u8], coin: &Coin) {
+    batch.remove(Tree::Utxos, key);
+    batch.remove(Tree::Index, address_key(&coin.output.pub_key_hash, key));
+}
+
+/// Key of the hash of the block the UTXO set reflects, in the metadata tree
+pub const UTXO_BEST_KEY: &str = "UTXO_BEST";
+
+/// Key of the progress of an interrupted reindex in the metadata tree
+pub const REINDEX_KEY: &str = "REINDEX";
+
+/// ReindexProgress is the chain a reindex works on and the next height it replays
+#[derive(Serialize, Deserialize, Debug)]
+struct ReindexProgress {
+    tip: String,
+    next_height: i32,
+}
 
 /// UTXOSet represents UTXO set
-pub struct UTXOSet {
-    pub blockchain: Blockchain,
+///
+/// Coins are keyed by outpoint in the `Utxos` tree of the ChainStore; the
+/// `Index` tree maps each public key hash to the outpoints it owns. The
+/// `UTXO_BEST` marker names the block the coins are up to date with; it is
+/// written in the same batch as the coins, so comparing it with the chain tip
+/// tells whether the UTXO set lags behind the block store.
+///
+/// Blocks change the coins in a CoinsCache first; it is written to the store
+/// when it runs out of room and when the UTXOSet is dropped.
+pub struct UTXOSet<S: ChainStore = Storage> {
+    pub blockchain: Blockchain<S>,
+    cache: Mutex<CoinsCache>,
 }
 
-impl UTXOSet {
+/// UndoCoins are the coins a block spent, restored when the block is disconnected
+type UndoCoins = Vec<(Vec<u8>, Coin)>;
+
+/// CoinChanges lists the outpoints a block spends and the coins it creates
+struct CoinChanges {
+    spent: Vec<Vec<u8>>,
+    created: Vec<(Vec<u8>, Coin)>,
+}
+
+impl CoinChanges {
+    fn new(block: &Block) -> Result<CoinChanges> {
+        let mut spent = Vec::new();
+        let mut created = Vec::new();
+        for tx in block.get_transaction() {
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    spent.push(OutPoint::new(&vin.txid, vin.vout).to_key()?);
+                }
+            }
+            for (vout, out) in tx.vout.iter().enumerate() {
+                let coin = Coin {
+                    output: out.clone(),
+                    height: block.get_height(),
+                    is_coinbase: tx.is_coinbase(),
+                };
+                created.push((OutPoint::new(&tx.id, vout as i32).to_key()?, coin));
+            }
+        }
+        Ok(CoinChanges { spent, created })
+    }
+
+    /// undo looks up the coins the block spends, failing if one of them is not unspent
+    fn undo<S: ChainStore>(&self, cache: &mut CoinsCache, store: &S) -> Result<UndoCoins> {
+        let created: HashSet<&Vec<u8>> = self.created.iter().map(|(key, _)| key).collect();
+        let mut seen = HashSet::new();
+        let mut undo = Vec::new();
+        for key in &self.spent {
+            if !seen.insert(key) {
+                return Err(format_err!(
+                    "ERROR: Block spends coin {:?} twice",
+                    OutPoint::from_key(key)?
+                ));
+            }
+            // 同一个区块里创建又花掉的输出不用落盘
+            if created.contains(key) {
+                continue;
+            }
+            match cache.get(store, key)? {
+                Some(coin) => undo.push((key.clone(), coin)),
+                None => {
+                    return Err(format_err!(
+                        "ERROR: Block spends missing coin {:?}",
+                        OutPoint::from_key(key)?
+                    ));
+                }
+            }
+        }
+        Ok(undo)
+    }
+
+    /// apply makes the changes in cache, once undo has checked them
+    fn apply(self, cache: &mut CoinsCache) -> Result<()> {
+        let mut created: HashMap<Vec<u8>, Coin> = self.created.into_iter().collect();
+        for key in &self.spent {
+            if created.remove(key).is_none() {
+                cache.spend(key)?;
+            }
+        }
+        for (key, coin) in created {
+            cache.add(key, coin);
+        }
+        Ok(())
+    }
+}
+
+impl<S: ChainStore> UTXOSet<S> {
+    /// NewUTXOSet opens the UTXO set of blockchain and repairs it if it lags behind the tip
+    pub fn new(blockchain: Blockchain<S>) -> Result<UTXOSet<S>> {
+        UTXOSet::with_dbcache(blockchain, DEFAULT_DBCACHE_MB)
+    }
+
+    /// WithDbcache is NewUTXOSet with a UTXO cache of dbcache MiB
+    pub fn with_dbcache(blockchain: Blockchain<S>, dbcache: usize) -> Result<UTXOSet<S>> {
+        let mut utxo = UTXOSet::unrepaired(blockchain, dbcache);
+        utxo.repair()?;
+        Ok(utxo)
+    }
+
+    /// Unrepaired opens the UTXO set of blockchain as it is in the store, with a UTXO cache of dbcache MiB
+    pub fn unrepaired(blockchain: Blockchain<S>, dbcache: usize) -> UTXOSet<S> {
+        UTXOSet {
+            blockchain,
+            cache: Mutex::new(CoinsCache::new(dbcache << 20)),
+        }
+    }
+
+    fn cache(&self) -> MutexGuard<'_, CoinsCache> {
+        self.cache.lock().unwrap()
+    }
+
+    /// CacheStats returns the statistics of the UTXO cache
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache().stats()
+    }
+
+    /// Flush writes the changes waiting in the UTXO cache to the store
+    pub fn flush(&self) -> Result<()> {
+        self.cache().flush(&self.blockchain.store, Batch::new())
+    }
+
+    /// flush_if_full flushes the UTXO cache when it uses more memory than allowed
+    fn flush_if_full(&self) -> Result<()> {
+        let mut cache = self.cache();
+        if cache.is_full() {
+            cache.flush(&self.blockchain.store, Batch::new())?;
+            let stats = cache.stats();
+            info!(
+                "UTXO cache flushed, {} hits, {} misses, {} flushes",
+                stats.hits, stats.misses, stats.flushes
+            );
+        }
+        Ok(())
+    }
+
+    /// FlushIfPruned flushes the UTXO cache of a pruned chain
+    ///
+    /// A pruned chain finds the previous outputs of transactions in the coin
+    /// store, see Blockchain::get_prev_TXs, so it must be up to date first.
+    pub fn flush_if_pruned(&self) -> Result<()> {
+        if self.blockchain.get_prune_height()? > 0 {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// GetCoin returns the unspent coin at outpoint, None once it is spent or if it never existed
+    pub fn get_coin(&self, outpoint: &OutPoint) -> Result<Option<Coin>> {
+        self.cache().get(&self.blockchain.store, &outpoint.to_key()?)
+    }
+
+    /// BestBlock returns the hash of the block the UTXO set is up to date with
+    pub fn best_block(&self) -> Result<Option<String>> {
+        if let Some(best) = self.cache().best() {
+            return Ok(Some(best.to_string()));
+        }
+        match self
+            .blockchain
+            .store
+            .get(Tree::Metadata, UTXO_BEST_KEY.as_bytes())?
+        {
+            Some(h) => Ok(Some(String::from_utf8(h)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Repair brings the UTXO set to the chain tip after an interrupted update
+    ///
+    /// An interrupted reindex is resumed. Otherwise blocks are disconnected with
+    /// their undo data back to the fork point and the tip's branch is connected
+    /// from there; if that is not possible the whole set is reindexed. It returns
+    /// whether anything had to be done.
+    pub fn repair(&mut self) -> Result<bool> {
+        if self.reindex_progress()?.is_some() {
+            warn!("a reindex was interrupted, resuming it");
+            self.reindex()?;
+            return Ok(true);
+        }
+        let best = self.best_block()?;
+        if best.as_deref() == Some(self.blockchain.tip.as_str()) {
+            return Ok(false);
+        }
+        warn!(
+            "UTXO set is at {:?} but the chain tip is {}, repairing",
+            best, self.blockchain.tip
+        );
+        if let Err(e) = self.catch_up() {
+            warn!("cannot replay blocks ({}), reindex the UTXO set", e);
+            self.reindex()?;
+        }
+        Ok(true)
+    }
+
+    /// catch_up moves the UTXO set from its best block to the chain tip block by block
+    fn catch_up(&mut self) -> Result<()> {
+        let tip = self.blockchain.tip.clone();
+        let (to_disconnect, to_connect) = self.fork_path(&tip)?;
+        for block in &to_disconnect {
+            self.disconnect_coins(block)?;
+        }
+        for block in &to_connect {
+            self.connect_coins(block, Batch::new())?;
+            self.flush_if_full()?;
+        }
+        self.flush()
+    }
+
+    /// fork_path returns the blocks to disconnect from the UTXO set's best block
+    /// back to the fork point, then the blocks to connect from there to target
+    fn fork_path(&self, target: &str) -> Result<(Vec<Block>, Vec<Block>)> {
+        let bc = &self.blockchain;
+        let best = match self.best_block()? {
+            Some(best) => best,
+            None => return Err(format_err!("ERROR: UTXO set has no best block")),
+        };
+        let mut old = bc.get_block(&best)?;
+        let mut new = bc.get_block(target)?;
+
+        // 两边都往回走到分叉点:旧分支的区块依次断开,新分支的区块记下来
+        let mut to_disconnect = Vec::new();
+        let mut to_connect = Vec::new();
+        while new.get_height() > old.get_height() {
+            let prev = bc.get_block(&new.get_prev_hash())?;
+            to_connect.push(new);
+            new = prev;
+        }
+        while old.get_hash() != new.get_hash() {
+            if old.get_height() == new.get_height() {
+                let prev = bc.get_block(&new.get_prev_hash())?;
+                to_connect.push(new);
+                new = prev;
+            }
+            let prev = bc.get_block(&old.get_prev_hash())?;
+            to_disconnect.push(old);
+            old = prev;
+        }
+        to_connect.reverse();
+        Ok((to_disconnect, to_connect))
+    }
+
+    /// reorganize moves the UTXO set to the branch of block, then makes block the tip
+    ///
+    /// The tip is only committed once every block of the branch connects. If one
+    /// doesn't, the coins go back to the old tip, that block and its descendants
+    /// are invalidated and its error is returned.
+    fn reorganize(&mut self, block: &Block) -> Result<()> {
+        let (to_disconnect, to_connect) = self.fork_path(&block.get_hash())?;
+        for old in &to_disconnect {
+            if let Err(e) = self.disconnect_coins(old) {
+                self.repair()?;
+                return Err(e);
+            }
+        }
+        for new in &to_connect {
+            if let Err(e) = self.connect_coins(new, Batch::new()) {
+                warn!(
+                    "block {} does not connect, staying on {}: {}",
+                    new.get_hash(),
+                    self.blockchain.tip,
+                    e
+                );
+                self.repair()?;
+                self.blockchain.invalidate_block(&new.get_hash())?;
+                return Err(e);
+            }
+            self.flush_if_full()?;
+        }
+        self.flush()?;
+        self.blockchain.set_best_chain(block)?;
+        self.blockchain.prune()
+    }
+
     /// FindUnspentTransactions returns a list of transactions containing unspent outputs
     pub fn find_spendable_outputs(
         &self,
@@ -21,44 +377,35 @@ impl UTXOSet {
         let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
         let mut accumulated = 0;
 
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter() {
-            let (k, v) = kv?;
-            let txid = String::from_utf8(k.to_vec())?;
-            let outs: TXOutputs = deserialize(&v.to_vec())?;
-
-            for out_idx in 0..outs.outputs.len() {
-                if outs.outputs[out_idx].is_locked_with_key(pub_key_hash) && accumulated < amount {
-                    accumulated += outs.outputs[out_idx].value;
-                    match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(out_idx as i32),
-                        None => {
-                            unspent_outputs.insert(txid.clone(), vec![out_idx as i32]);
-                        }
-                    }
-                }
+        for (outpoint, coin) in self.find_UTXO(pub_key_hash)? {
+            if accumulated >= amount {
+                break;
             }
+            accumulated += coin.output.value;
+            unspent_outputs
+                .entry(outpoint.txid)
+                .or_default()
+                .push(outpoint.vout);
         }
 
         Ok((accumulated, unspent_outputs))
     }
 
-    /// FindUTXO finds UTXO for a public key hash
-    pub fn find_UTXO(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
-        let mut utxos = TXOutputs {
-            outputs: Vec::new(),
-        };
-        let db = sled::open("data/utxos")?;
+    /// FindUTXO finds UTXO for a public key hash through the address index
+    pub fn find_UTXO(&self, pub_key_hash: &[u8]) -> Result<Vec<(OutPoint, Coin)>> {
+        self.flush()?;
+        let store = &self.blockchain.store;
+        let prefix = address_prefix(pub_key_hash);
+        let mut utxos = Vec::new();
 
-        for kv in db.iter() {
-            let (_, v) = kv?;
-            let outs: TXOutputs = deserialize(&v.to_vec())?;
-
-            for out in outs.outputs {
-                if out.is_locked_with_key(pub_key_hash) {
-                    utxos.outputs.push(out.clone())
-                }
-            }
+        for kv in store.scan_prefix(Tree::Index, &prefix) {
+            let (k, _) = kv?;
+            let outpoint_key = &k[prefix.len()..];
+            let coin = match store.get(Tree::Utxos, outpoint_key)? {
+                Some(v) => deserialize(&v)?,
+                None => return Err(format_err!("ERROR: Address index points to a missing coin")),
+            };
+            utxos.push((OutPoint::from_key(outpoint_key)?, coin));
         }
 
         Ok(utxos)
@@ -66,65 +413,455 @@ impl UTXOSet {
 
     /// CountTransactions returns the number of transactions in the UTXO set
     pub fn count_transactions(&self) -> Result<i32> {
+        self.flush()?;
         let mut counter = 0;
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter() {
-            kv?;
-            counter += 1;
+        let mut last_txid = Vec::new();
+        // key以txid开头并且有序,同一个交易的输出是连续的
+        for kv in self.blockchain.store.scan_prefix(Tree::Utxos, &[]) {
+            let (k, _) = kv?;
+            if k[..32] != last_txid[..] {
+                last_txid = k[..32].to_vec();
+                counter += 1;
+            }
         }
         Ok(counter)
     }
 
-    /// Reindex rebuilds the UTXO set
+    /// UsedPubKeyHashes returns the public key hashes that received coins on the best chain
+    ///
+    /// Pruned blocks can't be read, only their coins still unspent are seen.
+    pub fn used_pub_key_hashes(&self) -> Result<HashSet<Vec<u8>>> {
+        let mut used = HashSet::new();
+        for block in self.blockchain.iter() {
+            for tx in block?.get_transaction() {
+                for out in &tx.vout {
+                    used.insert(out.pub_key_hash.clone());
+                }
+            }
+        }
+        self.flush()?;
+        for kv in self.blockchain.store.scan_prefix(Tree::Utxos, &[]) {
+            let (_, v) = kv?;
+            let coin: Coin = deserialize(&v)?;
+            used.insert(coin.output.pub_key_hash);
+        }
+        Ok(used)
+    }
+
+    /// Reindex rebuilds the UTXO set and the chain indexes, see reindex_with
     pub fn reindex(&self) -> Result<()> {
-        std::fs::remove_dir_all("data/utxos").ok();
-        let db = sled::open("data/utxos")?;
+        self.reindex_with(&mut |_, _| {})
+    }
 
-        let utxos = self.blockchain.find_UTXO();
+    /// ReindexWith rebuilds the UTXO set and the chain indexes, reporting progress
+    ///
+    /// The height index is rebuilt first, then the blocks are replayed from the
+    /// genesis block in height order, one batch per block holding its coins,
+    /// undo data, txid index entries and the height to continue from. An
+    /// interrupted reindex resumes at that height. progress is called with the
+    /// number of blocks done and the number of blocks in the chain.
+    pub fn reindex_with(&self, progress: &mut dyn FnMut(i32, i32)) -> Result<()> {
+        let bc = &self.blockchain;
+        let store = &bc.store;
+        if bc.get_prune_height()? > 0 {
+            return Err(format_err!(
+                "ERROR: The chain is pruned below height {}, it can't be reindexed",
+                bc.get_prune_height()?
+            ));
+        }
 
-        for (txid, outs) in utxos {
-            db.insert(txid.as_bytes(), serialize(&outs)?)?;
+        let start = match self.reindex_progress()? {
+            Some(p) if p.tip == bc.tip => {
+                info!("resume reindex at height {}", p.next_height);
+                p.next_height
+            }
+            _ => {
+                // 先删掉标记,中途崩溃的话下次启动会重新来过
+                let mut batch = Batch::new();
+                batch.remove(Tree::Metadata, UTXO_BEST_KEY);
+                batch.remove(Tree::Metadata, REINDEX_KEY);
+                store.commit(batch)?;
+                self.cache().clear();
+                for tree in [Tree::Utxos, Tree::Index, Tree::Undo] {
+                    store.clear(tree)?;
+                }
+                bc.reindex_heights()?;
+                let mut batch = Batch::new();
+                let p = ReindexProgress {
+                    tip: bc.tip.clone(),
+                    next_height: 0,
+                };
+                batch.insert(Tree::Metadata, REINDEX_KEY, serialize(&p)?);
+                store.commit(batch)?;
+                0
+            }
+        };
+
+        let total = bc.get_best_height()? + 1;
+        for height in start..total {
+            let hash = match bc.get_block_hash(height)? {
+                Some(hash) => hash,
+                None => return Err(format_err!("ERROR: No block at height {}", height)),
+            };
+            let block = bc.get_block(&hash)?;
+            let mut batch = Batch::new();
+            bc.index_transactions(&block, &mut batch)?;
+            self.connect_coins(&block, batch)?;
+            // 进度和缓存里的币一起落盘,中断后从这里重放,之后的索引和撤销数据会被覆盖
+            let mut cache = self.cache();
+            if cache.is_full() || ((height + 1) as usize).is_multiple_of(REINDEX_BATCH) {
+                let p = ReindexProgress {
+                    tip: bc.tip.clone(),
+                    next_height: height + 1,
+                };
+                let mut batch = Batch::new();
+                batch.insert(Tree::Metadata, REINDEX_KEY, serialize(&p)?);
+                cache.flush(store, batch)?;
+                info!("reindexed {}/{} blocks", height + 1, total);
+            }
+            drop(cache);
+            progress(height + 1, total);
         }
 
+        let mut batch = Batch::new();
+        batch.remove(Tree::Metadata, REINDEX_KEY);
+        batch.insert(Tree::Metadata, UTXO_BEST_KEY, &bc.tip);
+        self.cache().flush(store, batch)?;
+
         Ok(())
     }
 
-    /// Update updates the UTXO set with transactions from the Block
+    /// reindex_progress returns where an interrupted reindex stopped
+    fn reindex_progress(&self) -> Result<Option<ReindexProgress>> {
+        match self.blockchain.store.get(Tree::Metadata, REINDEX_KEY.as_bytes())? {
+            Some(v) => Ok(Some(deserialize(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// MineBlock mines a block with the provided transactions and connects it
+    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
+        self.flush_if_pruned()?;
+        let block = self.blockchain.prepare_block(transactions)?;
+        self.connect_block(&block)?;
+        Ok(block)
+    }
+
+    /// AddBlock saves a received block
     ///
-    /// The Block is considered to be the tip of a blockchain
-    pub fn update(&self, block: &Block) -> Result<()> {
-        let db = sled::open("data/utxos")?;
+    /// A block extending the tip is connected in one batch. Any other block is
+    /// stored first and if its branch is higher the UTXO set reorganizes to it,
+    /// see reorganize. A block whose parent is unknown is rejected.
+    pub fn add_block(&mut self, block: Block) -> Result<()> {
+        if self.blockchain.has_block(&block.get_hash())? {
+            return Ok(());
+        }
+        if block.get_prev_hash() != self.blockchain.tip {
+            if self.blockchain.store_block(&block)? {
+                self.reorganize(&block)?;
+            }
+            return Ok(());
+        }
+        self.blockchain.check_block(&block)?;
+        self.connect_block(&block)
+    }
 
-        for tx in block.get_transaction() {
-            if !tx.is_coinbase() {
-                for vin in &tx.vin {
-                    let mut update_outputs = TXOutputs {
-                        outputs: Vec::new(),
-                    };
-                    let outs: TXOutputs = deserialize(&db.get(&vin.txid)?.unwrap().to_vec())?;
-                    for out_idx in 0..outs.outputs.len() {
-                        if out_idx != vin.vout as usize {
-                            update_outputs.outputs.push(outs.outputs[out_idx].clone());
-                        }
-                    }
-
-                    if update_outputs.outputs.is_empty() {
-                        db.remove(&vin.txid)?;
-                    } else {
-                        db.insert(vin.txid.as_bytes(), serialize(&update_outputs)?)?;
-                    }
-                }
+    /// InvalidateBlock marks a block invalid and rolls the UTXO set back to the new tip
+    pub fn invalidate_block(&mut self, block_hash: &str) -> Result<()> {
+        self.blockchain.invalidate_block(block_hash)?;
+        self.repair()?;
+        Ok(())
+    }
+
+    /// ReconsiderBlock clears the invalid marks of a block and moves the UTXO set to the best valid chain
+    pub fn reconsider_block(&mut self, block_hash: &str) -> Result<()> {
+        self.blockchain.reconsider_block(block_hash)?;
+        self.repair()?;
+        Ok(())
+    }
+
+    /// ConnectBlock saves a block on top of the tip and updates the UTXO set
+    ///
+    /// The block, the new tip and the undo data are committed in one batch;
+    /// the coin changes wait in the UTXO cache, and a crash before they are
+    /// flushed leaves a UTXO set that repair brings to the tip again.
+    fn connect_block(&mut self, block: &Block) -> Result<()> {
+        if block.get_prev_hash() != self.blockchain.tip {
+            return Err(format_err!(
+                "ERROR: Block {} does not extend the tip",
+                block.get_hash()
+            ));
+        }
+        let store = &self.blockchain.store;
+
+        let mut batch = Batch::new();
+        batch.insert(Tree::Blocks, block.get_hash(), serialize(block)?);
+        self.blockchain.index_best_chain(block, &mut batch)?;
+        self.connect_coins(block, batch)?;
+        store.flush()?;
+        self.flush_if_full()?;
+
+        self.blockchain.set_tip(block);
+        self.blockchain.prune()
+    }
+
+    /// connect_coins commits batch with the undo data of block, then makes its coin changes in the cache
+    fn connect_coins(&self, block: &Block, mut batch: Batch) -> Result<()> {
+        let changes = CoinChanges::new(block)?;
+        let mut cache = self.cache();
+        let undo = changes.undo(&mut cache, &self.blockchain.store)?;
+        batch.insert(Tree::Undo, block.get_hash(), serialize(&undo)?);
+        self.blockchain.store.commit(batch)?;
+        changes.apply(&mut cache)?;
+        cache.set_best(&block.get_hash());
+        Ok(())
+    }
+
+    /// SpentCoins returns the coins a connected block spent, from its undo data
+    pub fn spent_coins(&self, block_hash: &str) -> Result<HashMap<OutPoint, Coin>> {
+        let mut coins = HashMap::new();
+        if let Some(v) = self.blockchain.store.get(Tree::Undo, block_hash.as_bytes())? {
+            let undo: UndoCoins = deserialize(&v)?;
+            for (key, coin) in undo {
+                coins.insert(OutPoint::from_key(&key)?, coin);
             }
+        }
+        Ok(coins)
+    }
 
-            let mut new_outputs = TXOutputs {
-                outputs: Vec::new(),
-            };
-            for out in &tx.vout {
-                new_outputs.outputs.push(out.clone());
+    /// disconnect_coins reverts the coin changes of the UTXO set's best block with its undo data
+    fn disconnect_coins(&self, block: &Block) -> Result<()> {
+        self.flush()?;
+        let store = &self.blockchain.store;
+        let undo: UndoCoins = match store.get(Tree::Undo, block.get_hash().as_bytes())? {
+            Some(v) => deserialize(&v)?,
+            None => {
+                return Err(format_err!(
+                    "ERROR: No undo data for block {}",
+                    block.get_hash()
+                ));
             }
+        };
 
-            db.insert(tx.id.as_bytes(), serialize(&new_outputs)?)?;
+        let mut batch = Batch::new();
+        for (key, coin) in CoinChanges::new(block)?.created {
+            remove_coin(&mut batch, &key, &coin);
+        }
+        for (key, coin) in undo {
+            insert_coin(&mut batch, &key, &coin)?;
         }
+        batch.remove(Tree::Undo, block.get_hash());
+        batch.insert(Tree::Metadata, UTXO_BEST_KEY, block.get_prev_hash());
+        store.commit(batch)?;
+        self.cache().clear();
         Ok(())
     }
 }
+
+impl<S: ChainStore> Drop for UTXOSet<S> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("cannot flush the UTXO cache: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+    use crate::wallets::*;
+
+    #[test]
+    fn test_spend_second_output() {
+        let mut chain = TestChain::new();
+        let alice = chain.address();
+        let miner = chain.miner.clone();
+        let tx = chain.send(&miner, &alice, 3);
+        assert_eq!(tx.vout.len(), 2);
+        chain.mine(vec![tx.clone()]);
+        let change = address_from_pub_key_hash(&tx.vout[1].pub_key_hash).unwrap();
+
+        // 只花第二个输出,第一个输出还留在UTXO集合里
+        let bob = chain.address();
+        let spend = chain.send(&change, &bob, 5);
+        assert_eq!(spend.vin.len(), 1);
+        assert_eq!((spend.vin[0].txid.as_str(), spend.vin[0].vout), (tx.id.as_str(), 1));
+        chain.mine(vec![spend]);
+
+        let coins = chain.utxo.find_UTXO(&pub_key_hash(&alice)).unwrap();
+        assert_eq!(coins.len(), 1);
+        assert_eq!(coins[0].0, OutPoint::new(&tx.id, 0));
+        assert!(chain.utxo.find_UTXO(&pub_key_hash(&change)).unwrap().is_empty());
+        assert_eq!(chain.balance(&bob), 5);
+    }
+
+    #[test]
+    fn test_reopen_utxo_set() {
+        let mut chain = TestChain::new();
+        let alice = chain.address();
+        let miner = chain.miner.clone();
+        let tx = chain.send(&miner, &alice, 3);
+        chain.mine(vec![tx]);
+        chain.utxo.flush().unwrap();
+
+        let store = chain.utxo.blockchain.store.clone();
+        let utxo = UTXOSet::new(Blockchain::new(&store).unwrap()).unwrap();
+        assert_eq!(utxo.best_block().unwrap(), Some(chain.utxo.blockchain.tip.clone()));
+        let coins = utxo.find_UTXO(&pub_key_hash(&alice)).unwrap();
+        assert_eq!(coins.iter().map(|(_, c)| c.output.value).sum::<i32>(), 3);
+        let all = utxo.blockchain.find_UTXO().unwrap();
+        assert_eq!(all.values().map(|c| c.output.value).sum::<i32>(), 2 * SUBSIDY);
+    }
+
+    #[test]
+    fn test_repair_stale_utxo_set() {
+        let mut chain = TestChain::new();
+        let alice = chain.address();
+        let miner = chain.miner.clone();
+        let a1 = chain.mine(vec![]);
+        chain.utxo.flush().unwrap();
+        let store = chain.utxo.blockchain.store.clone();
+
+        // 区块和链顶提交了,币的变化还没写,像是在两者之间崩溃了
+        let tx = chain.send(&miner, &alice, 3);
+        let coinbase = Transaction::new_coinbase(miner.clone(), String::new()).unwrap();
+        let a2 = Block::new_block(vec![coinbase, tx], a1.get_hash(), 2).unwrap();
+        let mut bc = Blockchain::new(&store).unwrap();
+        bc.add_block(a2.clone()).unwrap();
+        let best = store.get(Tree::Metadata, UTXO_BEST_KEY.as_bytes()).unwrap();
+        assert_eq!(best, Some(a1.get_hash().into_bytes()));
+
+        let utxo = UTXOSet::new(Blockchain::new(&store).unwrap()).unwrap();
+        assert_utxo_set_matches(&utxo);
+        assert_eq!(utxo.find_UTXO(&pub_key_hash(&alice)).unwrap().len(), 1);
+        drop(utxo);
+
+        // 分叉:UTXO集合停在a2,链顶换成了从a1分出去的更长的分支
+        let b2 = chain.mine_on(&a1);
+        let b3 = chain.mine_on(&b2);
+        let mut bc = Blockchain::new(&store).unwrap();
+        bc.add_block(b2).unwrap();
+        bc.add_block(b3.clone()).unwrap();
+        assert_eq!(bc.tip, b3.get_hash());
+        let best = store.get(Tree::Metadata, UTXO_BEST_KEY.as_bytes()).unwrap();
+        assert_eq!(best, Some(a2.get_hash().into_bytes()));
+
+        let utxo = UTXOSet::new(Blockchain::new(&store).unwrap()).unwrap();
+        assert_utxo_set_matches(&utxo);
+        assert!(utxo.find_UTXO(&pub_key_hash(&alice)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_resume_reindex() {
+        let mut chain = TestChain::new();
+        let alice = chain.address();
+        let miner = chain.miner.clone();
+        for _ in 0..3 {
+            chain.mine(vec![]);
+        }
+        let tx = chain.send(&miner, &alice, 3);
+        chain.mine(vec![tx.clone()]);
+        chain.mine(vec![]);
+        chain.utxo.flush().unwrap();
+        let store = chain.utxo.blockchain.store.clone();
+
+        // 缓存为0,每个区块之后都保存进度;重放完第3个区块时进程被杀掉
+        let utxo = UTXOSet::unrepaired(Blockchain::new(&store).unwrap(), 0);
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            utxo.reindex_with(&mut |done, _| {
+                if done == 3 {
+                    panic!("interrupted");
+                }
+            })
+        }));
+        assert!(res.is_err());
+        std::mem::forget(utxo);
+
+        let mut utxo = UTXOSet::unrepaired(Blockchain::new(&store).unwrap(), 0);
+        assert_eq!(utxo.reindex_progress().unwrap().unwrap().next_height, 3);
+        assert_ne!(utxo.best_block().unwrap().as_deref(), Some(utxo.blockchain.tip.as_str()));
+        assert!(utxo.repair().unwrap());
+        assert!(utxo.reindex_progress().unwrap().is_none());
+        assert_utxo_set_matches(&utxo);
+        assert_eq!(utxo.find_UTXO(&pub_key_hash(&alice)).unwrap().len(), 1);
+        assert_eq!(utxo.blockchain.find_transacton(&tx.id).unwrap().id, tx.id);
+    }
+
+    #[test]
+    fn test_invalidate_reconsider() {
+        let mut chain = TestChain::new();
+        let alice = chain.address();
+        let miner = chain.miner.clone();
+        let tx = chain.send(&miner, &alice, 3);
+        let a1 = chain.mine(vec![tx.clone()]);
+        let a2 = chain.mine(vec![]);
+        let genesis = a1.get_prev_hash();
+
+        chain.utxo.invalidate_block(&a1.get_hash()).unwrap();
+        let bc = &chain.utxo.blockchain;
+        assert_eq!(bc.tip, genesis);
+        assert_eq!(bc.get_best_height().unwrap(), 0);
+        assert_eq!(bc.get_block_hash(1).unwrap(), None);
+        assert_eq!(bc.get_block_hash(2).unwrap(), None);
+        assert!(bc.get_transaction_location(&tx.id).unwrap().is_none());
+        assert!(bc.is_invalid(&a2.get_hash()).unwrap());
+        assert_utxo_set_matches(&chain.utxo);
+        assert!(chain.utxo.find_UTXO(&pub_key_hash(&alice)).unwrap().is_empty());
+        assert_eq!(chain.balance(&miner), SUBSIDY);
+
+        // 无效分支上的新区块存下来也不会跟过去
+        let a3 = chain.mine_on(&a2);
+        chain.utxo.add_block(a3.clone()).unwrap();
+        assert!(chain.utxo.blockchain.is_invalid(&a3.get_hash()).unwrap());
+        assert_eq!(chain.utxo.blockchain.tip, genesis);
+        let b1 = chain.mine_on(&chain.utxo.blockchain.get_block(&genesis).unwrap());
+        chain.utxo.add_block(b1.clone()).unwrap();
+        assert_eq!(chain.utxo.blockchain.tip, b1.get_hash());
+        assert_utxo_set_matches(&chain.utxo);
+
+        chain.utxo.reconsider_block(&a1.get_hash()).unwrap();
+        let bc = &chain.utxo.blockchain;
+        assert_eq!(bc.tip, a3.get_hash());
+        assert_eq!(bc.get_block_hash(1).unwrap(), Some(a1.get_hash()));
+        assert_eq!(bc.get_transaction_location(&tx.id).unwrap().unwrap().block_hash, a1.get_hash());
+        assert!(!bc.is_invalid(&a3.get_hash()).unwrap());
+        assert_utxo_set_matches(&chain.utxo);
+        assert_eq!(chain.balance(&alice), 3);
+    }
+
+    #[test]
+    fn test_reorg_to_bad_branch() {
+        let mut chain = TestChain::new();
+        let alice = chain.address();
+        let bob = chain.address();
+        let miner = chain.miner.clone();
+        let tx = chain.send(&miner, &alice, 3);
+        let a1 = chain.mine(vec![tx]);
+        let genesis = chain.utxo.blockchain.get_block(&a1.get_prev_hash()).unwrap();
+
+        // b2花的是a1里的币,签名没问题,在b分支上却没有这个币
+        let spend = chain.send(&alice, &bob, 1);
+        let b1 = chain.mine_on(&genesis);
+        let coinbase = Transaction::new_coinbase(miner.clone(), String::new()).unwrap();
+        let b2 = Block::new_block(vec![coinbase, spend], b1.get_hash(), 2).unwrap();
+        chain.utxo.add_block(b1.clone()).unwrap();
+        assert_eq!(chain.utxo.blockchain.tip, a1.get_hash());
+        let err = chain.utxo.add_block(b2.clone()).unwrap_err().to_string();
+        assert!(err.contains("missing coin"), "{}", err);
+
+        // 币回到a1上,b2被标成无效,跟在它后面的区块也不会被跟过去
+        let bc = &chain.utxo.blockchain;
+        assert_eq!(bc.tip, a1.get_hash());
+        assert_eq!(bc.get_block_hash(1).unwrap(), Some(a1.get_hash()));
+        assert!(bc.is_invalid(&b2.get_hash()).unwrap());
+        assert!(!bc.is_invalid(&b1.get_hash()).unwrap());
+        assert_eq!(chain.utxo.best_block().unwrap(), Some(a1.get_hash()));
+        assert_utxo_set_matches(&chain.utxo);
+        assert_eq!(chain.balance(&alice), 3);
+        let b3 = chain.mine_on(&b2);
+        chain.utxo.add_block(b3.clone()).unwrap();
+        assert_eq!(chain.utxo.blockchain.tip, a1.get_hash());
+        assert!(chain.utxo.blockchain.is_invalid(&b3.get_hash()).unwrap());
+    }
+}
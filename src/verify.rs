@@ -0,0 +1,297 @@
+//! chain integrity checks behind the verifychain command
+
+use super::*;
+use crate::storage::*;
+use crate::utxoset::*;
+use bincode::serialize;
+use crate::block::*;
+use crate::blockchain::*;
+use std::collections::{HashMap, HashSet};
+
+/// VerifyReport says what verify_chain looked at and the first inconsistency it found
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub blocks: usize,
+    pub transactions: usize,
+    pub utxos: usize,
+    /// blocks whose transactions were pruned, only their headers are checked
+    pub pruned: usize,
+    pub failure: Option<String>,
+}
+
+/// VerifyChain rechecks the newest depth blocks of the chain, all of them if depth is 0
+///
+/// - level 0: the block hash is the header hash and meets the target
+/// - level 1: txids and the witness commitment match the transactions
+/// - level 2: prev links, heights and the height and txid indexes
+/// - level 3: the transaction signatures and the undo data
+/// - level 4: a UTXO set rebuilt from the chain equals the stored one
+///
+/// Only the header checks apply to pruned blocks, and the UTXO set of a pruned
+/// chain can't be rebuilt so level 4 skips it.
+///
+/// It stops at the first inconsistency and records it in the report; an Err
+/// means the store itself could not be read.
+pub fn verify_chain<S: ChainStore>(
+    utxo: &UTXOSet<S>,
+    level: u32,
+    depth: usize,
+) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    if let Err(failure) = check_blocks(utxo, level, depth, &mut report)? {
+        report.failure = Some(failure);
+        return Ok(report);
+    }
+    if level >= 4
+        && utxo.blockchain.get_prune_height()? == 0
+        && let Err(failure) = check_utxos(utxo, &mut report)?
+    {
+        report.failure = Some(failure);
+    }
+    Ok(report)
+}
+
+/// check_blocks walks back from the tip; the inner Err is an inconsistency
+fn check_blocks<S: ChainStore>(
+    utxo: &UTXOSet<S>,
+    level: u32,
+    depth: usize,
+    report: &mut VerifyReport,
+) -> Result<std::result::Result<(), String>> {
+    let bc = &utxo.blockchain;
+    let mut hash = bc.tip.clone();
+    let mut height = bc.get_best_height()?;
+
+    while !hash.is_empty() && (depth == 0 || report.blocks < depth) {
+        // 从UTXO快照启动的节点没有快照区块之前的链
+        if height < bc.get_prune_height()? && !bc.has_block(&hash)? {
+            break;
+        }
+        if bc.is_pruned(&hash)? {
+            if let Err(failure) = check_header(utxo, &hash, height, level)? {
+                return Ok(Err(failure));
+            }
+            report.blocks += 1;
+            report.pruned += 1;
+            hash = bc.get_header(&hash)?.prev_block_hash;
+            height -= 1;
+            continue;
+        }
+        let block = match bc.get_block(&hash) {
+            Ok(block) => block,
+            Err(_) => return Ok(Err(format!("Block {} at height {} is missing", hash, height))),
+        };
+        let at = format!("Block {} at height {}", hash, height);
+
+        if block.get_hash() != hash || !block.check_proof_of_work()? {
+            return Ok(Err(format!("{}: bad proof of work", at)));
+        }
+        if level >= 1 && !block.check_merkle_roots()? {
+            return Ok(Err(format!("{}: merkle root mismatch", at)));
+        }
+        if level >= 2 {
+            if block.get_height() != height {
+                return Ok(Err(format!("{}: stored height is {}", at, block.get_height())));
+            }
+            if block.get_prev_hash().is_empty() != (height == 0) {
+                return Ok(Err(format!("{}: broken prev link", at)));
+            }
+            if bc.get_block_hash(height)?.as_deref() != Some(hash.as_str()) {
+                return Ok(Err(format!("{}: height index points elsewhere", at)));
+            }
+            for (pos, tx) in block.get_transaction().iter().enumerate() {
+                match bc.get_transaction_location(&tx.id)? {
+                    Some(loc) if loc.block_hash == hash && loc.position as usize == pos => {}
+                    _ => return Ok(Err(format!("{}: txid index is wrong for {}", at, tx.id))),
+                }
+            }
+        }
+        if level >= 3 {
+            let spent = utxo.spent_coins(&hash)?;
+            if let Err(failure) = check_undo(bc, &block, &spent)? {
+                return Ok(Err(format!("{}: {}", at, failure)));
+            }
+            match bc.verify_transactions_with(block.get_transaction(), &spent) {
+                Ok(true) => {}
+                Ok(false) => return Ok(Err(format!("{}: invalid signature", at))),
+                Err(e) => return Ok(Err(format!("{}: {}", at, e))),
+            }
+        }
+
+        report.blocks += 1;
+        report.transactions += block.get_transaction().len();
+        hash = block.get_prev_hash();
+        height -= 1;
+    }
+    Ok(Ok(()))
+}
+
+/// check_undo compares the coins in a block's undo data with the outputs its inputs spend
+fn check_undo<S: ChainStore>(
+    bc: &Blockchain<S>,
+    block: &Block,
+    spent: &HashMap<OutPoint, Coin>,
+) -> Result<std::result::Result<(), String>> {
+    let txids: HashSet<&str> = block.get_transaction().iter().map(|tx| tx.id.as_str()).collect();
+    for tx in block.get_transaction().iter().filter(|tx| !tx.is_coinbase()) {
+        for vin in tx.vin.iter().filter(|vin| !txids.contains(vin.txid.as_str())) {
+            let outpoint = OutPoint::new(&vin.txid, vin.vout);
+            let coin = match spent.get(&outpoint) {
+                Some(coin) => coin,
+                None => return Ok(Err(format!("undo data lacks {:?}", outpoint))),
+            };
+            // 前序交易被裁剪了就只剩撤销数据,没有东西可比
+            if let Some(loc) = bc.get_transaction_location(&vin.txid)?
+                && !bc.is_pruned(&loc.block_hash)?
+            {
+                let prev = bc.find_transacton(&vin.txid)?;
+                match prev.vout.get(vin.vout as usize) {
+                    Some(out) if serialize(out)? == serialize(&coin.output)? => {}
+                    _ => return Ok(Err(format!("undo data differs for {:?}", outpoint))),
+                }
+            }
+        }
+    }
+    Ok(Ok(()))
+}
+
+/// check_header runs the checks of levels 0 and 2 that only need the header of a pruned block
+fn check_header<S: ChainStore>(
+    utxo: &UTXOSet<S>,
+    hash: &str,
+    height: i32,
+    level: u32,
+) -> Result<std::result::Result<(), String>> {
+    let bc = &utxo.blockchain;
+    let header = bc.get_header(hash)?;
+    let at = format!("Pruned block {} at height {}", hash, height);
+
+    if header.hash != hash || !header.check_proof_of_work()? {
+        return Ok(Err(format!("{}: bad proof of work", at)));
+    }
+    if level >= 2 {
+        if header.height != height {
+            return Ok(Err(format!("{}: stored height is {}", at, header.height)));
+        }
+        if header.prev_block_hash.is_empty() != (height == 0) {
+            return Ok(Err(format!("{}: broken prev link", at)));
+        }
+        if bc.get_block_hash(height)?.as_deref() != Some(hash) {
+            return Ok(Err(format!("{}: height index points elsewhere", at)));
+        }
+    }
+    Ok(Ok(()))
+}
+
+/// check_utxos compares the stored UTXO set and address index with one rebuilt from the chain
+fn check_utxos<S: ChainStore>(
+    utxo: &UTXOSet<S>,
+    report: &mut VerifyReport,
+) -> Result<std::result::Result<(), String>> {
+    utxo.flush()?;
+    let bc = &utxo.blockchain;
+    if utxo.best_block()?.as_deref() != Some(bc.tip.as_str()) {
+        return Ok(Err(format!(
+            "UTXO set is at {:?}, the tip is {}",
+            utxo.best_block()?,
+            bc.tip
+        )));
+    }
+
+    let mut rebuilt = HashMap::new();
+    for (outpoint, coin) in bc.find_UTXO()? {
+        rebuilt.insert(outpoint.to_key()?, coin);
+    }
+    for kv in bc.store.scan_prefix(Tree::Utxos, &[]) {
+        let (key, value) = kv?;
+        let outpoint = OutPoint::from_key(&key)?;
+        match rebuilt.remove(&key) {
+            Some(coin) if serialize(&coin)? == value => {}
+            Some(_) => return Ok(Err(format!("UTXO {:?} differs from the chain", outpoint))),
+            None => return Ok(Err(format!("UTXO {:?} is not unspent in the chain", outpoint))),
+        }
+        report.utxos += 1;
+    }
+    if let Some((key, _)) = rebuilt.iter().next() {
+        return Ok(Err(format!(
+            "UTXO {:?} is missing from the UTXO set",
+            OutPoint::from_key(key)?
+        )));
+    }
+
+    let indexed = bc.store.scan_prefix(Tree::Index, &[]).count();
+    if indexed != report.utxos {
+        return Ok(Err(format!(
+            "Address index has {} entries for {} UTXOs",
+            indexed, report.utxos
+        )));
+    }
+    Ok(Ok(()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+    use bincode::deserialize;
+
+    /// first_failure returns the lowest level whose verify_chain reports a failure
+    fn first_failure(utxo: &UTXOSet<MemoryStore>) -> Option<(u32, String)> {
+        (0..=4).find_map(|level| {
+            let report = verify_chain(utxo, level, 0).unwrap();
+            report.failure.map(|f| (level, f))
+        })
+    }
+
+    #[test]
+    fn test_verify_corruption() {
+        let mut chain = TestChain::new();
+        let alice = chain.address();
+        let miner = chain.miner.clone();
+        let tx = chain.send(&miner, &alice, 3);
+        let block = chain.mine(vec![tx]);
+        chain.mine(vec![]);
+        chain.utxo.flush().unwrap();
+        let report = verify_chain(&chain.utxo, 4, 0).unwrap();
+        assert!(report.failure.is_none(), "{:?}", report.failure);
+        assert_eq!(report.blocks, 3);
+
+        let store = chain.utxo.blockchain.store.clone();
+        let write = |tree: Tree, key: &[u8], value: &[u8]| {
+            let mut batch = Batch::new();
+            batch.insert(tree, key, value);
+            store.commit(batch).unwrap();
+        };
+
+        // 撤销数据里被花掉的币换了主人,签名就对不上了
+        let hash = block.get_hash();
+        let undo = store.get(Tree::Undo, hash.as_bytes()).unwrap().unwrap();
+        let mut coins: Vec<(Vec<u8>, Coin)> = deserialize(&undo).unwrap();
+        coins[0].1.output.pub_key_hash = pub_key_hash(&alice);
+        write(Tree::Undo, hash.as_bytes(), &serialize(&coins).unwrap());
+        let (level, failure) = first_failure(&chain.utxo).unwrap();
+        assert_eq!(level, 3, "{}", failure);
+        assert!(failure.contains("undo data differs"), "{}", failure);
+        write(Tree::Undo, hash.as_bytes(), &undo);
+
+        // 高度索引指向了别的区块
+        let genesis = block.get_prev_hash();
+        write(Tree::Heights, &height_key(1), genesis.as_bytes());
+        let (level, failure) = first_failure(&chain.utxo).unwrap();
+        assert_eq!(level, 2, "{}", failure);
+        assert!(failure.contains("height index"), "{}", failure);
+        write(Tree::Heights, &height_key(1), hash.as_bytes());
+
+        // UTXO集合里的币和链上的不一样
+        let (key, value) = store.scan_prefix(Tree::Utxos, &[]).next().unwrap().unwrap();
+        let mut coin: Coin = deserialize(&value).unwrap();
+        coin.output.value += 1;
+        write(Tree::Utxos, &key, &serialize(&coin).unwrap());
+        let (level, failure) = first_failure(&chain.utxo).unwrap();
+        assert_eq!(level, 4, "{}", failure);
+        assert!(failure.contains("differs from the chain"), "{}", failure);
+        write(Tree::Utxos, &key, &value);
+
+        assert!(first_failure(&chain.utxo).is_none());
+    }
+}
@@ -1,36 +1,44 @@
 use super::*;
+use crate::block::*;
+use crate::blockchain::*;
+use crate::crypter::*;
+use crate::hdkey::*;
+use crate::keys::*;
+use crate::schema::*;
+use crate::storage::*;
+use crate::utxoset::*;
 use bincode::{deserialize, serialize};
+use bip39::Mnemonic;
 use bitcoincash_addr::*;
 use crypto::digest::Digest;
-use crypto::ed25519;
 use crypto::ripemd160::Ripemd160;
 use crypto::sha2::Sha256;
-use rand::Rng;
+use failure::format_err;
 use serde::{Deserialize, Serialize};
 use sled;
-use std::collections::HashMap;
- 
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+/// Key of the encrypted master key in the wallets tree
+pub const MASTER_KEY_KEY: &str = "$master";
+/// Key of the HD seed in the wallets tree
+pub const SEED_KEY: &str = "$seed";
+/// Key of the watch-only addresses in the wallets tree
+pub const WATCH_KEY: &str = "$watch";
+/// Prefix of the wallet transaction index in the wallets tree, followed by the txid
+pub const WALLET_TX_PREFIX: &str = "$tx:";
+/// Key of the hash of the block the wallet transaction index is up to date with
+pub const WALLET_BEST_KEY: &str = "$txbest";
+
 #[derive(Serialize,  Deserialize, Debug,Clone,PartialEq)]
 pub struct Wallet {
     pub public_key: Vec<u8>,
     pub secret_key: Vec<u8>,
+    pub scheme: SignatureScheme,
 }
 
 impl Wallet {
-    fn new() -> Self {
-        let mut key: [u8; 32] = [0; 32];
-        let mut rand = rand::OsRng::new().unwrap();//这是操作系统级别的随机数
-        rand.fill_bytes(&mut key);//填充这个随机数到key中
-        //然后用这个随机数生成公钥和私钥,ed25519是一种椭圆曲线算法
-        //keypair是生成公钥和私钥的函数
-        let (secret_key, public_key) = ed25519::keypair(&key);
-        let secret_key = secret_key.to_vec();
-        let public_key = public_key.to_vec();
-        Wallet {
-            secret_key,
-            public_key,
-        }
-    }
     pub fn get_address(&self) -> String {
         let mut pub_hash: Vec<u8> = self.public_key.clone();
         hash_pub_key(&mut pub_hash);    //对这个公钥进行两次哈希
@@ -44,6 +52,34 @@ impl Wallet {
     }
 }
 
+impl Signer for Wallet {
+    fn scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        self.public_key.clone()
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        self.scheme.sign_with(&self.secret_key, message)
+    }
+}
+
+/// AddressFromPubKeyHash encodes a public key hash as a wallet address
+pub fn address_from_pub_key_hash(pub_key_hash: &[u8]) -> Result<String> {
+    let address = Address {
+        body: pub_key_hash.to_vec(),
+        scheme: Scheme::Base58,
+        hash_type: HashType::Script,
+        ..Default::default()
+    };
+    match address.encode() {
+        Ok(address) => Ok(address),
+        Err(_) => Err(format_err!("ERROR: Bad public key hash {}", hex::encode(pub_key_hash))),
+    }
+}
+
 pub fn hash_pub_key(pubKey: &mut Vec<u8>) {
     let mut hasher1 = Sha256::new();
     hasher1.input(pubKey);//对这个公钥先进行哈希
@@ -54,33 +90,618 @@ pub fn hash_pub_key(pubKey: &mut Vec<u8>) {
     hasher2.result(pubKey);
 }
 
+/// StoredSecret is the secret key of a saved wallet, encrypted once the wallet is
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum StoredSecret {
+    Plain(Vec<u8>),
+    /// sealed with the master key, the public key is the associated data
+    Encrypted(Sealed),
+    /// derived from the HD seed, only the place on the derivation path is kept
+    Derived { chain: u32, index: u32 },
+}
+
+/// StoredWallet is a wallet as saved in the wallets tree
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StoredWallet {
+    pub public_key: Vec<u8>,
+    pub scheme: SignatureScheme,
+    pub secret: StoredSecret,
+}
+
+/// HdSeed is the seed of the derived wallets and the next index of every chain
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct HdSeed {
+    /// plain or sealed with the master key, SEED_KEY is the associated data
+    seed: StoredSecret,
+    next: HashMap<(SignatureScheme, u32), u32>,
+}
+
+/// WatchOnly is an address the wallet follows without holding its secret key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WatchOnly {
+    pub pub_key_hash: Vec<u8>,
+    /// only known when the address was imported with importpubkey
+    pub public_key: Option<Vec<u8>>,
+}
+
+/// WalletTxOut is a coin of a wallet address that a transaction creates or spends
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WalletTxOut {
+    pub outpoint: OutPoint,
+    pub address: String,
+    pub value: i32,
+}
+
+/// Direction is how a wallet transaction moves coins
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// a coinbase paying a wallet address
+    Generated,
+    Received,
+    Sent,
+    /// every output goes back to the wallet
+    SelfTransfer,
+}
+
+/// WalletTx is a transaction of the best chain that pays or spends a wallet address
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WalletTx {
+    pub txid: String,
+    pub block_hash: String,
+    pub height: i32,
+    /// position of the transaction in its block
+    pub index: usize,
+    /// timestamp of the block in milliseconds
+    pub time: u128,
+    pub is_coinbase: bool,
+    /// outputs of the transaction paying a wallet address
+    pub received: Vec<WalletTxOut>,
+    /// wallet coins the inputs of the transaction spend
+    pub spent: Vec<WalletTxOut>,
+    /// outputs of the transaction paying other addresses
+    pub paid: Vec<WalletTxOut>,
+    /// addresses of the inputs that don't spend wallet coins
+    pub senders: Vec<String>,
+    /// only known when every input spends a wallet coin
+    pub fee: Option<i32>,
+}
+
+impl WalletTx {
+    pub fn direction(&self) -> Direction {
+        if self.is_coinbase {
+            Direction::Generated
+        } else if self.spent.is_empty() {
+            Direction::Received
+        } else if self.paid.is_empty() {
+            Direction::SelfTransfer
+        } else {
+            Direction::Sent
+        }
+    }
+
+    /// Amount is what the transaction paid to the wallet, or what it paid to others as a negative amount
+    ///
+    /// The fee is not included; a transfer to the wallet itself has amount 0.
+    pub fn amount(&self) -> i32 {
+        match self.direction() {
+            Direction::Generated | Direction::Received => self.received.iter().map(|c| c.value).sum(),
+            Direction::Sent => -self.paid.iter().map(|c| c.value).sum::<i32>(),
+            Direction::SelfTransfer => 0,
+        }
+    }
+
+    /// Counterparties are the addresses paid by a sent transaction, or the senders of a received one
+    pub fn counterparties(&self) -> Vec<String> {
+        match self.direction() {
+            Direction::Sent => {
+                let mut addresses: Vec<String> = Vec::new();
+                for c in &self.paid {
+                    if !addresses.contains(&c.address) {
+                        addresses.push(c.address.clone());
+                    }
+                }
+                addresses
+            }
+            Direction::Received => self.senders.clone(),
+            Direction::Generated | Direction::SelfTransfer => Vec::new(),
+        }
+    }
+
+    /// Involves reports whether the transaction pays or spends a coin of address
+    pub fn involves(&self, address: &str) -> bool {
+        self.received.iter().chain(&self.spent).any(|c| c.address == address)
+    }
+
+    /// Confirmations counts the block of the transaction and the blocks after it up to best_height
+    pub fn confirmations(&self, best_height: i32) -> i32 {
+        best_height - self.height + 1
+    }
+
+    pub fn get_readable_time(&self) -> String {
+        let d = Duration::from_millis(self.time as u64);
+        let datetime = chrono::DateTime::<chrono::Utc>::from(UNIX_EPOCH + d);
+        datetime.format("%Y-%m-%d %H:%M:%S").to_string()
+    }
+}
+
 pub struct Wallets {
-    wallets: HashMap<String, Wallet>,
+    wallets: HashMap<String, StoredWallet>,
+    watch: HashMap<String, WatchOnly>,
+    /// None until the first wallet is created or the wallet is restored
+    seed: Option<HdSeed>,
+    /// None while the wallet is not encrypted
+    master: Option<MasterKey>,
+    /// the master key of an unlocked wallet, never written to the tree and wiped on drop
+    unlocked: Option<Zeroizing<Vec<u8>>>,
+    /// when the master key stops being used, None while it is kept until lock or drop
+    unlocked_until: Option<Instant>,
+    tree: sled::Tree,
 }
 
 impl Wallets {
-    pub fn new() -> Result<Wallets> {
+    pub fn new(store: &Storage) -> Result<Wallets> {
+        upgrade_wallets(&store.wallets)?;
         let mut wlt = Wallets {
-            wallets: HashMap::<String, Wallet>::new(),
+            wallets: HashMap::<String, StoredWallet>::new(),
+            watch: HashMap::new(),
+            seed: None,
+            master: None,
+            unlocked: None,
+            unlocked_until: None,
+            tree: store.wallets.clone(),
         };
-        let db = sled::open("data/wallets")?;
 
-        for item in db.into_iter() {    //打开数据库,遍历所有的钱包,加入内存中
+        for item in wlt.tree.iter() {    //遍历数据库中所有的钱包,加入内存中
             let i = item?;
+            // $开头的不是钱包,base58地址里没有$
+            if i.0.starts_with(b"$") {
+                continue;
+            }
             let address = String::from_utf8(i.0.to_vec())?;
             let wallet = deserialize(&i.1.to_vec())?;
             wlt.wallets.insert(address, wallet);    
         }
+        if let Some(v) = wlt.tree.get(WATCH_KEY)? {
+            wlt.watch = deserialize(&v)?;
+        }
+        if let Some(v) = wlt.tree.get(SEED_KEY)? {
+            wlt.seed = Some(deserialize(&v)?);
+        }
+        if let Some(v) = wlt.tree.get(MASTER_KEY_KEY)? {
+            wlt.master = Some(deserialize(&v)?);
+        }
         Ok(wlt)
     }
 
-    pub fn create_wallet(&mut self) -> String {
-        let wallet = Wallet::new();
-        let address = wallet.get_address();
-        self.wallets.insert(address.clone(), wallet);
-        info!("create wallet: {}", address);
+    pub fn has_seed(&self) -> bool {
+        self.seed.is_some()
+    }
+
+    /// CreateSeed draws the HD seed new wallets are derived from
+    ///
+    /// The returned mnemonic is the only backup of the seed, it is not stored.
+    pub fn create_seed(&mut self) -> Result<Mnemonic> {
+        if self.has_seed() {
+            return Err(format_err!("ERROR: The wallet already has an HD seed"));
+        }
+        let mnemonic = new_mnemonic()?;
+        self.set_seed(&mnemonic.to_seed(""))?;
+        Ok(mnemonic)
+    }
+
+    /// CreateWallet derives the next receive address of scheme from the HD seed
+    ///
+    /// An encrypted wallet has to be unlocked for it.
+    pub fn create_wallet(&mut self, scheme: SignatureScheme) -> Result<String> {
+        self.derive_address(scheme, RECEIVE_CHAIN)
+    }
+
+    /// CreateChangeAddress derives the next change address of scheme from the HD seed
+    ///
+    /// Change addresses are internal, they are not handed out to receive coins.
+    pub fn create_change_address(&mut self, scheme: SignatureScheme) -> Result<String> {
+        self.derive_address(scheme, CHANGE_CHAIN)
+    }
+
+    /// IsChange reports whether the address is a change address of the HD seed
+    pub fn is_change(&self, address: &str) -> bool {
+        matches!(
+            self.wallets.get(address),
+            Some(StoredWallet {
+                secret: StoredSecret::Derived {
+                    chain: CHANGE_CHAIN,
+                    ..
+                },
+                ..
+            })
+        )
+    }
+
+    /// RestoreWallet sets the HD seed from a mnemonic and derives the addresses already used
+    ///
+    /// Each chain is searched until GAP_LIMIT addresses in a row are not in
+    /// used, the public key hashes seen on the chain.
+    pub fn restore(&mut self, mnemonic: &Mnemonic, used: &HashSet<Vec<u8>>) -> Result<Vec<String>> {
+        if self.has_seed() {
+            return Err(format_err!("ERROR: The wallet already has an HD seed"));
+        }
+        let seed = mnemonic.to_seed("");
+        self.set_seed(&seed)?;
+
+        let mut restored = Vec::new();
+        for scheme in SignatureScheme::ALL {
+            for chain in [RECEIVE_CHAIN, CHANGE_CHAIN] {
+                let mut keys = Vec::new();
+                let mut found = 0;
+                let mut index = 0;
+                while index < found + GAP_LIMIT {
+                    let (_, public_key) = derive_keypair(&seed, scheme, chain, index)?;
+                    let mut pub_key_hash = public_key.clone();
+                    hash_pub_key(&mut pub_key_hash);
+                    if used.contains(&pub_key_hash) {
+                        found = index + 1;
+                    }
+                    keys.push(public_key);
+                    index += 1;
+                }
+                keys.truncate(found as usize);
+                for (index, public_key) in keys.into_iter().enumerate() {
+                    let index = index as u32;
+                    restored.push(self.add_derived(scheme, chain, index, public_key));
+                }
+            }
+        }
+        Ok(restored)
+    }
+
+    /// DerivationPathOf returns the derivation path of a derived wallet
+    pub fn derivation_path_of(&self, address: &str) -> Option<[u32; 5]> {
+        match self.wallets.get(address) {
+            Some(StoredWallet {
+                scheme,
+                secret: StoredSecret::Derived { chain, index },
+                ..
+            }) => Some(derivation_path(*scheme, *chain, *index)),
+            _ => None,
+        }
+    }
+
+    /// derive_address derives the next address of chain and adds its wallet
+    fn derive_address(&mut self, scheme: SignatureScheme, chain: u32) -> Result<String> {
+        let seed = self.seed_bytes()?;
+        let index = match &self.seed {
+            Some(hd) => hd.next.get(&(scheme, chain)).copied().unwrap_or(0),
+            None => 0,
+        };
+        let (_, public_key) = derive_keypair(&seed, scheme, chain, index)?;
+        let address = self.add_derived(scheme, chain, index, public_key);
+        info!("create {} wallet: {}", scheme, address);
+        Ok(address)
+    }
+
+    /// add_derived adds the wallet at index of chain and moves the chain's next index past it
+    fn add_derived(&mut self, scheme: SignatureScheme, chain: u32, index: u32, public_key: Vec<u8>) -> String {
+        let stored = StoredWallet {
+            public_key,
+            scheme,
+            secret: StoredSecret::Derived { chain, index },
+        };
+        let address = Wallet {
+            public_key: stored.public_key.clone(),
+            secret_key: Vec::new(),
+            scheme,
+        }
+        .get_address();
+        self.wallets.insert(address.clone(), stored);
+        if let Some(hd) = &mut self.seed {
+            let next = hd.next.entry((scheme, chain)).or_insert(0);
+            *next = (*next).max(index + 1);
+        }
         address
     }
+
+    fn set_seed(&mut self, seed: &[u8]) -> Result<()> {
+        let seed = match self.master {
+            Some(_) => StoredSecret::Encrypted(seal(self.master_key()?, seed, SEED_KEY.as_bytes())?),
+            None => StoredSecret::Plain(seed.to_vec()),
+        };
+        self.seed = Some(HdSeed {
+            seed,
+            next: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    /// seed_bytes returns the HD seed, decrypted if the wallet is encrypted
+    fn seed_bytes(&self) -> Result<Vec<u8>> {
+        match self.seed.as_ref().map(|hd| &hd.seed) {
+            Some(StoredSecret::Plain(seed)) => Ok(seed.clone()),
+            Some(StoredSecret::Encrypted(sealed)) => open(self.master_key()?, sealed, SEED_KEY.as_bytes()),
+            _ => Err(format_err!(
+                "ERROR: The wallet has no HD seed, create one with createwallet or restorewallet"
+            )),
+        }
+    }
+
+    /// ImportAddress adds a watch-only address and returns it in base58
+    ///
+    /// The coins of the address are followed but can't be spent.
+    pub fn import_address(&mut self, address: &str) -> Result<String> {
+        let pub_key_hash = match Address::decode(address) {
+            Ok(decoded) => decoded.body,
+            Err(_) => return Err(format_err!("ERROR: Bad address {}", address)),
+        };
+        // 统一成base58地址,和钱包里的地址一样
+        let address = address_from_pub_key_hash(&pub_key_hash)?;
+        self.add_watch_only(address.clone(), pub_key_hash, None)?;
+        Ok(address)
+    }
+
+    /// ImportPubKey adds the address of a public key as a watch-only address and returns it
+    pub fn import_pub_key(&mut self, public_key: &[u8]) -> Result<String> {
+        // ed25519和schnorr是32字节,ecdsa是压缩的33字节
+        if public_key.len() != 32 && public_key.len() != 33 {
+            return Err(format_err!("ERROR: Bad public key length {}", public_key.len()));
+        }
+        let mut pub_key_hash = public_key.to_vec();
+        hash_pub_key(&mut pub_key_hash);
+        let address = address_from_pub_key_hash(&pub_key_hash)?;
+        self.add_watch_only(address.clone(), pub_key_hash, Some(public_key.to_vec()))?;
+        Ok(address)
+    }
+
+    fn add_watch_only(&mut self, address: String, pub_key_hash: Vec<u8>, public_key: Option<Vec<u8>>) -> Result<()> {
+        if self.wallets.contains_key(&address) {
+            return Err(format_err!("ERROR: The wallet already has the key of {}", address));
+        }
+        if self.watch.contains_key(&address) {
+            return Err(format_err!("ERROR: Address {} is already watched", address));
+        }
+        info!("import watch-only address: {}", address);
+        self.watch.insert(
+            address,
+            WatchOnly {
+                pub_key_hash,
+                public_key,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn is_watch_only(&self, address: &str) -> bool {
+        self.watch.contains_key(address)
+    }
+
+    /// GetWatchOnlyAddresses returns the imported addresses the wallet has no key for
+    pub fn get_watch_only_addresses(&self) -> Vec<String> {
+        self.watch.keys().cloned().collect()
+    }
+
+    /// Rescan rebuilds the wallet transaction index from the best chain of bc
+    ///
+    /// Blocks are connected again by height from the oldest one, so every
+    /// spend of a wallet coin finds the output it spends in the index. A
+    /// pruned chain is only scanned from the prune height. Returns the number
+    /// of wallet transactions found.
+    pub fn rescan<S: ChainStore>(&self, bc: &Blockchain<S>) -> Result<usize> {
+        let prune_height = bc.get_prune_height()?;
+        if prune_height > 0 {
+            warn!("rescan starts at the prune height {}", prune_height);
+        }
+
+        let mut batch = sled::Batch::default();
+        for item in self.tree.scan_prefix(WALLET_TX_PREFIX) {
+            batch.remove(item?.0);
+        }
+        batch.insert(WALLET_BEST_KEY, bc.tip.as_bytes());
+        self.tree.apply_batch(batch)?;
+        let addresses = self.pub_key_hashes();
+        let mut found = 0;
+        let best_height = bc.get_best_height()?;
+        // 按高度一个一个读区块,不把整条链读进内存
+        for height in prune_height..=best_height {
+            let hash = match bc.get_block_hash(height)? {
+                Some(hash) => hash,
+                None => return Err(format_err!("ERROR: No block at height {} of the best chain", height)),
+            };
+            found += self.connect_block(&bc.get_block(&hash)?, &addresses)?;
+        }
+        self.tree.flush()?;
+        info!("rescan found {} wallet transactions in {} blocks", found, best_height - prune_height + 1);
+        Ok(found)
+    }
+
+    /// Sync brings the wallet transaction index to the chain tip
+    ///
+    /// Like the UTXO set, the index remembers the block it is up to date with.
+    /// Blocks are disconnected back to the fork point with the tip's branch and
+    /// the branch is connected from there; when that is not possible, e.g.
+    /// because the blocks were pruned, the whole chain is rescanned.
+    ///
+    /// The wallet commands sync when they open the ledger, the chain can't
+    /// change under them while they hold the store. A node syncs after every
+    /// block it connects, see Server::set_wallets, since pruning would
+    /// otherwise drop blocks the ledger has not seen and lose their history.
+    pub fn sync<S: ChainStore>(&self, bc: &Blockchain<S>) -> Result<()> {
+        let best = match self.tree.get(WALLET_BEST_KEY)? {
+            // 上次同步时还没有区块链,直接从头扫描
+            Some(best) if !best.is_empty() || bc.tip.is_empty() => String::from_utf8(best.to_vec())?,
+            _ => {
+                self.rescan(bc)?;
+                return Ok(());
+            }
+        };
+        if best == bc.tip {
+            return Ok(());
+        }
+        if let Err(e) = self.catch_up(bc, &best) {
+            warn!("cannot follow the chain from {} ({}), rescanning the wallet", best, e);
+            self.rescan(bc)?;
+        }
+        Ok(())
+    }
+
+    /// catch_up moves the index from best to the chain tip block by block
+    fn catch_up<S: ChainStore>(&self, bc: &Blockchain<S>, best: &str) -> Result<()> {
+        if best.is_empty() || bc.tip.is_empty() {
+            return Err(format_err!("ERROR: No common block with the chain"));
+        }
+        let mut old = bc.get_block(best)?;
+        let mut new = bc.get_block(&bc.tip)?;
+
+        // 和UTXO集合一样,两边都往回走到分叉点
+        let mut to_connect = Vec::new();
+        while new.get_height() > old.get_height() {
+            let prev = bc.get_block(&new.get_prev_hash())?;
+            to_connect.push(new);
+            new = prev;
+        }
+        while old.get_hash() != new.get_hash() {
+            if old.get_height() == new.get_height() {
+                let prev = bc.get_block(&new.get_prev_hash())?;
+                to_connect.push(new);
+                new = prev;
+            }
+            self.disconnect_block(&old)?;
+            old = bc.get_block(&old.get_prev_hash())?;
+        }
+
+        let addresses = self.pub_key_hashes();
+        for block in to_connect.iter().rev() {
+            self.connect_block(block, &addresses)?;
+        }
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    /// connect_block adds the wallet transactions of block to the index and returns their number
+    fn connect_block(&self, block: &Block, addresses: &HashMap<Vec<u8>, String>) -> Result<usize> {
+        // 同一个区块里前面交易的输出也可能被后面的交易花掉
+        let mut connected: HashMap<String, WalletTx> = HashMap::new();
+        let mut batch = sled::Batch::default();
+        for (index, tx) in block.get_transaction().iter().enumerate() {
+            let mut spent = Vec::new();
+            let mut senders = Vec::new();
+            if !tx.is_coinbase() {
+                for (vin, witness) in tx.vin.iter().zip(&tx.witness) {
+                    let prev = match connected.get(&vin.txid) {
+                        Some(prev) => Some(prev.clone()),
+                        None => self.get_transaction(&vin.txid)?,
+                    };
+                    let coin = prev.and_then(|prev| {
+                        prev.received.into_iter().find(|c| c.outpoint.vout == vin.vout)
+                    });
+                    match coin {
+                        Some(coin) => spent.push(coin),
+                        None => {
+                            let mut pub_key_hash = witness.pub_key.clone();
+                            hash_pub_key(&mut pub_key_hash);
+                            let sender = address_from_pub_key_hash(&pub_key_hash)?;
+                            if !senders.contains(&sender) {
+                                senders.push(sender);
+                            }
+                        }
+                    }
+                }
+            }
+            let mut received = Vec::new();
+            let mut paid = Vec::new();
+            for (vout, out) in tx.vout.iter().enumerate() {
+                let outpoint = OutPoint::new(&tx.id, vout as i32);
+                match addresses.get(&out.pub_key_hash) {
+                    Some(address) => received.push(WalletTxOut {
+                        outpoint,
+                        address: address.clone(),
+                        value: out.value,
+                    }),
+                    None => paid.push(WalletTxOut {
+                        outpoint,
+                        address: address_from_pub_key_hash(&out.pub_key_hash)?,
+                        value: out.value,
+                    }),
+                }
+            }
+            if received.is_empty() && spent.is_empty() {
+                continue;
+            }
+            // 所有输入都是钱包的币才知道手续费
+            let fee = if !tx.is_coinbase() && spent.len() == tx.vin.len() {
+                let inputs: i32 = spent.iter().map(|c| c.value).sum();
+                let outputs: i32 = tx.vout.iter().map(|out| out.value).sum();
+                Some(inputs - outputs)
+            } else {
+                None
+            };
+            let wtx = WalletTx {
+                txid: tx.id.clone(),
+                block_hash: block.get_hash(),
+                height: block.get_height(),
+                index,
+                time: block.timestamp,
+                is_coinbase: tx.is_coinbase(),
+                received,
+                spent,
+                paid,
+                senders,
+                fee,
+            };
+            batch.insert(wallet_tx_key(&tx.id).as_bytes(), serialize(&wtx)?);
+            connected.insert(tx.id.clone(), wtx);
+        }
+        batch.insert(WALLET_BEST_KEY, block.get_hash().as_bytes());
+        self.tree.apply_batch(batch)?;
+        Ok(connected.len())
+    }
+
+    /// disconnect_block removes the wallet transactions of block from the index
+    fn disconnect_block(&self, block: &Block) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for tx in block.get_transaction() {
+            // 同一笔交易可能已经被新分支上的区块重新记下了
+            if let Some(wtx) = self.get_transaction(&tx.id)?
+                && wtx.block_hash == block.get_hash()
+            {
+                batch.remove(wallet_tx_key(&tx.id).as_bytes());
+            }
+        }
+        batch.insert(WALLET_BEST_KEY, block.get_prev_hash().as_bytes());
+        self.tree.apply_batch(batch)?;
+        Ok(())
+    }
+
+    /// pub_key_hashes maps the public key hash of every wallet and watch-only address to the address
+    fn pub_key_hashes(&self) -> HashMap<Vec<u8>, String> {
+        let mut addresses = HashMap::new();
+        for (address, wallet) in &self.wallets {
+            let mut pub_key_hash = wallet.public_key.clone();
+            hash_pub_key(&mut pub_key_hash);
+            addresses.insert(pub_key_hash, address.clone());
+        }
+        for (address, watch) in &self.watch {
+            addresses.insert(watch.pub_key_hash.clone(), address.clone());
+        }
+        addresses
+    }
+
+    /// Transactions returns the wallet transaction index, oldest first
+    pub fn transactions(&self) -> Result<Vec<WalletTx>> {
+        let mut txs = Vec::new();
+        for item in self.tree.scan_prefix(WALLET_TX_PREFIX) {
+            let wtx: WalletTx = deserialize(&item?.1)?;
+            txs.push(wtx);
+        }
+        txs.sort_by_key(|wtx| (wtx.height, wtx.index));
+        Ok(txs)
+    }
+
+    /// GetTransaction returns the index entry of a wallet transaction
+    pub fn get_transaction(&self, txid: &str) -> Result<Option<WalletTx>> {
+        match self.tree.get(wallet_tx_key(txid))? {
+            Some(v) => Ok(Some(deserialize(&v)?)),
+            None => Ok(None),
+        }
+    }
+
     //拼接一个vec返回哈希表中的所有钱包地址(没有钱包)
     pub fn get_all_addresses(&self) -> Vec<String> {
         let mut addresses = Vec::<String>::new();
@@ -89,21 +710,543 @@ impl Wallets {
         }
         addresses
     }
-    //返回单个钱包
-    pub fn get_wallet(&self, address: &str) -> Option<&Wallet> {
-        self.wallets.get(address)
+    /// GetAddresses returns the addresses the wallet holds keys for, the change addresses only if with_change
+    pub fn get_addresses(&self, with_change: bool) -> Vec<String> {
+        let mut addresses = self.get_all_addresses();
+        if !with_change {
+            addresses.retain(|a| !self.is_change(a));
+        }
+        addresses
+    }
+
+    /// GetWallet returns the key pair of an address, decrypting it if the wallet is encrypted
+    pub fn get_wallet(&self, address: &str) -> Result<Wallet> {
+        let stored = match self.wallets.get(address) {
+            Some(stored) => stored,
+            None if self.is_watch_only(address) => {
+                return Err(format_err!("ERROR: Address {} is watch-only, the wallet has no key for it", address));
+            }
+            None => return Err(format_err!("ERROR: No wallet for address {}", address)),
+        };
+        let secret_key = match &stored.secret {
+            StoredSecret::Plain(secret_key) => secret_key.clone(),
+            StoredSecret::Encrypted(sealed) => open(self.master_key()?, sealed, &stored.public_key)?,
+            StoredSecret::Derived { chain, index } => {
+                let (secret_key, public_key) =
+                    derive_keypair(&self.seed_bytes()?, stored.scheme, *chain, *index)?;
+                if public_key != stored.public_key {
+                    return Err(format_err!("ERROR: Wallet {} does not match the HD seed", address));
+                }
+                secret_key
+            }
+        };
+        Ok(Wallet {
+            public_key: stored.public_key.clone(),
+            secret_key,
+            scheme: stored.scheme,
+        })
     }
     //把内存中的钱包保存到数据库中
     pub fn save_all(&self) -> Result<()> {
-        let db = sled::open("data/wallets")?;
-
         for (address, wallet) in &self.wallets {
             let data = serialize(wallet)?;
-            db.insert(address, data)?;
+            self.tree.insert(address, data)?;
+        }
+        if let Some(hd) = &self.seed {
+            self.tree.insert(SEED_KEY, serialize(hd)?)?;
+        }
+        if !self.watch.is_empty() {
+            self.tree.insert(WATCH_KEY, serialize(&self.watch)?)?;
         }
 
-        db.flush()?;
-        drop(db);
+        self.tree.flush()?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    pub fn is_encrypted(&self) -> bool {
+        self.master.is_some()
+    }
+
+    /// EncryptWallet encrypts every secret key with a new master key protected by passphrase
+    ///
+    /// The wallet is locked afterwards. Secret keys written before may still
+    /// be found in old copies of the data directory.
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<()> {
+        self.encrypt_with(passphrase, KdfParams::default())
+    }
+
+    /// EncryptWith is encrypt with the key derivation cost of kdf
+    pub fn encrypt_with(&mut self, passphrase: &str, kdf: KdfParams) -> Result<()> {
+        if self.is_encrypted() {
+            return Err(format_err!("ERROR: The wallet is already encrypted, use changepassphrase"));
+        }
+        check_passphrase(passphrase)?;
+        let (master, key) = MasterKey::new(passphrase, kdf)?;
+        let key = Zeroizing::new(key);
+
+        let mut batch = sled::Batch::default();
+        for (address, wallet) in self.wallets.iter_mut() {
+            if let StoredSecret::Plain(secret_key) = &wallet.secret {
+                wallet.secret = StoredSecret::Encrypted(seal(&key, secret_key, &wallet.public_key)?);
+            }
+            batch.insert(address.as_bytes(), serialize(wallet)?);
+        }
+        if let Some(hd) = &mut self.seed {
+            if let StoredSecret::Plain(seed) = &hd.seed {
+                hd.seed = StoredSecret::Encrypted(seal(&key, seed, SEED_KEY.as_bytes())?);
+            }
+            batch.insert(SEED_KEY, serialize(hd)?);
+        }
+        batch.insert(MASTER_KEY_KEY, serialize(&master)?);
+        self.tree.apply_batch(batch)?;
+        self.tree.flush()?;
+        self.master = Some(master);
+        self.unlocked = None;
+        self.unlocked_until = None;
+        Ok(())
+    }
+
+    /// Unlock decrypts the master key so the secret keys can be used
+    ///
+    /// The key stays in this Wallets only and is wiped when it is dropped,
+    /// every command that needs a secret key asks for the passphrase again.
+    pub fn unlock(&mut self, passphrase: &str) -> Result<()> {
+        let master = match &self.master {
+            Some(master) => master,
+            None => return Err(format_err!("ERROR: The wallet is not encrypted")),
+        };
+        self.unlocked = Some(Zeroizing::new(master.unlock(passphrase)?));
+        self.unlocked_until = None;
+        Ok(())
+    }
+
+    /// UnlockFor unlocks the wallet for timeout, see walletpassphrase
+    ///
+    /// The master key is not used after the timeout; lock_if_expired wipes it.
+    /// Unlocking again before that sets a new timeout.
+    pub fn unlock_for(&mut self, passphrase: &str, timeout: Duration) -> Result<()> {
+        if timeout.is_zero() {
+            return Err(format_err!("ERROR: The timeout must be at least one second"));
+        }
+        let until = match Instant::now().checked_add(timeout) {
+            Some(until) => until,
+            None => return Err(format_err!("ERROR: The timeout is too long")),
+        };
+        self.unlock(passphrase)?;
+        self.unlocked_until = Some(until);
+        Ok(())
+    }
+
+    /// Lock wipes the master key of an unlocked wallet
+    pub fn lock(&mut self) -> Result<()> {
+        if !self.is_encrypted() {
+            return Err(format_err!("ERROR: The wallet is not encrypted"));
+        }
+        self.unlocked = None;
+        self.unlocked_until = None;
+        Ok(())
+    }
+
+    /// LockIfExpired locks a wallet whose unlock timeout has passed
+    pub fn lock_if_expired(&mut self) {
+        if self.unlocked.is_some() && self.is_locked() {
+            self.unlocked = None;
+            self.unlocked_until = None;
+        }
+    }
+
+    /// IsLocked reports whether the secret keys of an encrypted wallet can't be used
+    pub fn is_locked(&self) -> bool {
+        self.is_encrypted() && self.master_key().is_err()
+    }
+
+    /// ChangePassphrase seals every secret key and the HD seed again under a new master key
+    ///
+    /// A fresh master key is protected by new, so a master key that leaked
+    /// under the old passphrase can't open the secrets written afterwards.
+    /// The wallet is locked afterwards.
+    pub fn change_passphrase(&mut self, old: &str, new: &str) -> Result<()> {
+        let master = match &self.master {
+            Some(master) => master,
+            None => return Err(format_err!("ERROR: The wallet is not encrypted")),
+        };
+        check_passphrase(new)?;
+        let old_key = Zeroizing::new(master.unlock(old)?);
+        let (master, key) = MasterKey::new(new, master.kdf())?;
+        let key = Zeroizing::new(key);
+
+        // 全部重新加密成功后再一起写入,中途出错不会留下用两个主密钥加密的钱包
+        let mut wallets = self.wallets.clone();
+        let mut batch = sled::Batch::default();
+        for (address, wallet) in wallets.iter_mut() {
+            if let StoredSecret::Encrypted(sealed) = &wallet.secret {
+                let secret_key = Zeroizing::new(open(&old_key, sealed, &wallet.public_key)?);
+                wallet.secret = StoredSecret::Encrypted(seal(&key, &secret_key, &wallet.public_key)?);
+                batch.insert(address.as_bytes(), serialize(wallet)?);
+            }
+        }
+        let mut seed = self.seed.clone();
+        if let Some(hd) = &mut seed
+            && let StoredSecret::Encrypted(sealed) = &hd.seed
+        {
+            let plain = Zeroizing::new(open(&old_key, sealed, SEED_KEY.as_bytes())?);
+            hd.seed = StoredSecret::Encrypted(seal(&key, &plain, SEED_KEY.as_bytes())?);
+            batch.insert(SEED_KEY, serialize(hd)?);
+        }
+        batch.insert(MASTER_KEY_KEY, serialize(&master)?);
+        self.tree.apply_batch(batch)?;
+        self.tree.flush()?;
+        self.wallets = wallets;
+        self.seed = seed;
+        self.master = Some(master);
+        self.unlocked = None;
+        self.unlocked_until = None;
+        Ok(())
+    }
+
+    /// master_key returns the master key of an unlocked wallet
+    fn master_key(&self) -> Result<&[u8]> {
+        match (&self.unlocked, self.unlocked_until) {
+            (Some(_), Some(until)) if Instant::now() >= until => {}
+            (Some(key), _) => return Ok(key),
+            (None, _) => {}
+        }
+        Err(format_err!(
+            "ERROR: The wallet is locked, unlock it with walletpassphrase <timeout> first"
+        ))
+    }
+}
+
+fn wallet_tx_key(txid: &str) -> String {
+    format!("{}{}", WALLET_TX_PREFIX, txid)
+}
+
+fn check_passphrase(passphrase: &str) -> Result<()> {
+    if passphrase.is_empty() {
+        return Err(format_err!("ERROR: The passphrase can't be empty"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::*;
+    use crate::transaction::SUBSIDY;
+
+    #[test]
+    fn test_encrypt_wallet() {
+        let store = Storage::temporary().unwrap();
+        let mut ws = Wallets::new(&store).unwrap();
+        ws.create_seed().unwrap();
+        let address = ws.create_wallet(SignatureScheme::Ed25519).unwrap();
+        // HD种子之前的钱包,私钥单独加密
+        let (secret_key, public_key) = SignatureScheme::Ed25519.generate_keypair();
+        let legacy = Wallet { public_key, secret_key, scheme: SignatureScheme::Ed25519 };
+        ws.wallets.insert(
+            legacy.get_address(),
+            StoredWallet {
+                public_key: legacy.public_key.clone(),
+                scheme: legacy.scheme,
+                secret: StoredSecret::Plain(legacy.secret_key.clone()),
+            },
+        );
+        ws.save_all().unwrap();
+        let wallet = ws.get_wallet(&address).unwrap();
+
+        let kdf = KdfParams {
+            m_cost: 64,
+            t_cost: 1,
+            p_cost: 1,
+        };
+        ws.encrypt_with("secret", kdf).unwrap();
+        assert!(ws.encrypt_with("secret", kdf).is_err());
+        let stored = store.wallets.get(&address).unwrap().unwrap();
+        assert!(!stored.windows(wallet.secret_key.len()).any(|w| w == &wallet.secret_key[..]));
+
+        // 重新打开后仍然是锁定的,主密钥不会写进钱包数据
+        let mut ws = Wallets::new(&store).unwrap();
+        let err = ws.get_wallet(&address).unwrap_err().to_string();
+        assert!(err.contains("locked"), "{}", err);
+        assert!(ws.create_wallet(SignatureScheme::Ed25519).is_err());
+        assert!(ws.unlock("wrong").is_err());
+
+        ws.unlock("secret").unwrap();
+        assert_eq!(ws.get_wallet(&address).unwrap(), wallet);
+        let second = ws.create_wallet(SignatureScheme::EcdsaSecp256k1).unwrap();
+        ws.save_all().unwrap();
+        assert!(Wallets::new(&store).unwrap().get_wallet(&address).is_err());
+        let sealed_before = store.wallets.get(SEED_KEY).unwrap().unwrap();
+
+        // 换密码时种子和私钥都用新的主密钥重新加密
+        assert!(ws.change_passphrase("wrong", "new secret").is_err());
+        ws.change_passphrase("secret", "new secret").unwrap();
+        assert!(ws.get_wallet(&second).is_err());
+        assert_ne!(store.wallets.get(SEED_KEY).unwrap().unwrap(), sealed_before);
+        let mut ws = Wallets::new(&store).unwrap();
+        assert!(ws.unlock("secret").is_err());
+        ws.unlock("new secret").unwrap();
+        assert_eq!(ws.get_wallet(&address).unwrap(), wallet);
+        assert_eq!(ws.get_wallet(&second).unwrap().scheme, SignatureScheme::EcdsaSecp256k1);
+        assert_eq!(ws.get_wallet(&legacy.get_address()).unwrap(), legacy);
+
+        // walletpassphrase的解锁到时间就不能再用,之后主密钥被清掉
+        ws.lock().unwrap();
+        assert!(ws.is_locked());
+        assert!(ws.unlock_for("new secret", Duration::ZERO).is_err());
+        assert!(ws.unlock_for("secret", Duration::from_secs(60)).is_err());
+        ws.unlock_for("new secret", Duration::from_millis(200)).unwrap();
+        assert!(!ws.is_locked());
+        assert_eq!(ws.get_wallet(&address).unwrap(), wallet);
+        std::thread::sleep(Duration::from_millis(250));
+        assert!(ws.is_locked());
+        let err = ws.get_wallet(&address).unwrap_err().to_string();
+        assert!(err.contains("wallet is locked"), "{}", err);
+        ws.lock_if_expired();
+        assert!(ws.unlocked.is_none());
+    }
+
+    #[test]
+    fn test_watch_only() {
+        let store = Storage::temporary().unwrap();
+        let mut ws = Wallets::new(&store).unwrap();
+        ws.create_seed().unwrap();
+        let own = ws.create_wallet(SignatureScheme::Ed25519).unwrap();
+        assert!(ws.import_address(&own).is_err());
+        assert!(ws.import_address("not an address").is_err());
+
+        let mut seed = [7u8; 32];
+        let (_, public_key) = SignatureScheme::EcdsaSecp256k1.keypair_from_seed(&seed).unwrap();
+        let by_key = ws.import_pub_key(&public_key).unwrap();
+        assert!(ws.import_pub_key(&public_key).is_err());
+        assert!(ws.import_pub_key(&public_key[1..5]).is_err());
+        seed[0] = 8;
+        let (_, public_key) = SignatureScheme::Ed25519.keypair_from_seed(&seed).unwrap();
+        let mut pub_key_hash = public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        let by_address = ws.import_address(&address_from_pub_key_hash(&pub_key_hash).unwrap()).unwrap();
+        ws.save_all().unwrap();
+
+        let ws = Wallets::new(&store).unwrap();
+        let mut watched = ws.get_watch_only_addresses();
+        watched.sort();
+        let mut expected = vec![by_key.clone(), by_address];
+        expected.sort();
+        assert_eq!(watched, expected);
+        assert_eq!(ws.get_all_addresses(), vec![own]);
+        let err = ws.get_wallet(&by_key).unwrap_err().to_string();
+        assert!(err.contains("watch-only"), "{}", err);
+
+        // 重新扫描会清掉旧的索引
+        store.wallets.insert(wallet_tx_key("stale"), vec![0u8]).unwrap();
+        let chain = MemoryStore::new();
+        assert_eq!(ws.rescan(&Blockchain::new(&chain).unwrap()).unwrap(), 0);
+        assert!(ws.transactions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_watch_only_ledger() {
+        let mut chain = TestChain::new();
+        let miner = chain.miner.clone();
+        let watched = chain.address();
+        let other = chain.address();
+        let mut watcher = Wallets::new(&Storage::temporary().unwrap()).unwrap();
+        watcher.import_address(&watched).unwrap();
+        watcher.save_all().unwrap();
+
+        let receive = chain.send(&miner, &watched, 4);
+        chain.mine(vec![receive.clone()]);
+        watcher.sync(&chain.utxo.blockchain).unwrap();
+        let spend = chain.send(&watched, &other, 1);
+        chain.mine(vec![spend.clone()]);
+        // 接着上次的区块同步,和从头重新扫描得到的记录一样
+        watcher.sync(&chain.utxo.blockchain).unwrap();
+        let synced = watcher.transactions().unwrap();
+        assert_eq!(watcher.rescan(&chain.utxo.blockchain).unwrap(), 2);
+        assert_eq!(watcher.transactions().unwrap(), synced);
+
+        assert_eq!(synced.len(), 2);
+        let (received, sent) = (&synced[0], &synced[1]);
+        assert_eq!(received.txid, receive.id);
+        assert_eq!(received.direction(), Direction::Received);
+        assert_eq!(received.amount(), 4);
+        assert_eq!(received.senders, vec![miner]);
+        assert_eq!(received.fee, None);
+
+        // 花掉的正是之前收到的那个币
+        assert_eq!(sent.txid, spend.id);
+        assert_eq!(sent.direction(), Direction::Sent);
+        assert_eq!(sent.spent, received.received);
+        assert_eq!(sent.spent[0].address, watched);
+        assert_eq!(sent.amount(), -4);
+        assert_eq!(sent.fee, Some(0));
+        assert!(sent.paid.iter().any(|out| out.address == other && out.value == 1));
+    }
+
+    #[test]
+    fn test_ledger_follows_chain() {
+        let mut chain = TestChain::new();
+        let miner = chain.miner.clone();
+        let mut outside = Wallets::new(&Storage::temporary().unwrap()).unwrap();
+        outside.create_seed().unwrap();
+        let bob = outside.create_wallet(SignatureScheme::Ed25519).unwrap();
+        chain.wallets.sync(&chain.utxo.blockchain).unwrap();
+        assert_eq!(chain.wallets.transactions().unwrap()[0].direction(), Direction::Generated);
+
+        let paid = chain.send(&miner, &bob, 3);
+        let a1 = chain.mine(vec![paid.clone()]);
+        let to_self = chain.address();
+        let moved = chain.send(&miner, &to_self, 2);
+        let a2 = chain.mine(vec![moved.clone()]);
+        chain.wallets.sync(&chain.utxo.blockchain).unwrap();
+        let wtx = chain.wallets.get_transaction(&paid.id).unwrap().unwrap();
+        assert_eq!((wtx.direction(), wtx.amount(), wtx.fee), (Direction::Sent, -3, Some(0)));
+        assert_eq!(wtx.counterparties(), vec![bob.clone()]);
+        assert_eq!(wtx.block_hash, a1.get_hash());
+        let wtx = chain.wallets.get_transaction(&moved.id).unwrap().unwrap();
+        assert_eq!((wtx.direction(), wtx.amount(), wtx.fee), (Direction::SelfTransfer, 0, Some(0)));
+        let coinbase = &a2.get_transaction()[0];
+        let wtx = chain.wallets.get_transaction(&coinbase.id).unwrap().unwrap();
+        assert_eq!((wtx.direction(), wtx.amount(), wtx.fee), (Direction::Generated, SUBSIDY, None));
+
+        // 分叉切换后被换掉的区块的交易从账本里删掉
+        let b2 = chain.mine_on(&a1);
+        let b3 = chain.mine_on(&b2);
+        chain.utxo.add_block(b2.clone()).unwrap();
+        chain.utxo.add_block(b3.clone()).unwrap();
+        chain.wallets.sync(&chain.utxo.blockchain).unwrap();
+        assert!(chain.wallets.get_transaction(&moved.id).unwrap().is_none());
+        assert!(chain.wallets.get_transaction(&coinbase.id).unwrap().is_none());
+        assert!(chain.wallets.get_transaction(&paid.id).unwrap().is_some());
+        assert!(chain.wallets.get_transaction(&b3.get_transaction()[0].id).unwrap().is_some());
+        assert_eq!(chain.wallets.transactions().unwrap().len(), 5);
+        assert_eq!(chain.wallets.tree.get(WALLET_BEST_KEY).unwrap().unwrap(), b3.get_hash().as_bytes());
+
+        // 账本停在已经裁剪掉的区块上,只能从裁剪高度重新扫描
+        for _ in 0..12 {
+            chain.mine(vec![]);
+        }
+        chain.utxo.flush().unwrap();
+        chain.utxo.blockchain.set_prune_depth(MIN_PRUNE_DEPTH).unwrap();
+        chain.utxo.blockchain.prune().unwrap();
+        let bc = &chain.utxo.blockchain;
+        assert!(bc.is_pruned(&b3.get_hash()).unwrap());
+        chain.wallets.sync(bc).unwrap();
+        let txs = chain.wallets.transactions().unwrap();
+        let prune_height = bc.get_prune_height().unwrap();
+        assert_eq!(txs.len() as i32, bc.get_best_height().unwrap() - prune_height + 1);
+        assert_eq!(txs[0].height, prune_height);
+        assert!(chain.wallets.get_transaction(&paid.id).unwrap().is_none());
+        assert_eq!(chain.wallets.tree.get(WALLET_BEST_KEY).unwrap().unwrap(), bc.tip.as_bytes());
+    }
+
+    #[test]
+    fn test_wallet_tx() {
+        let coin = |address: &str, value: i32| WalletTxOut {
+            outpoint: OutPoint::new("00", 0),
+            address: address.to_string(),
+            value,
+        };
+        let mut wtx = WalletTx {
+            txid: "00".to_string(),
+            block_hash: "11".to_string(),
+            height: 3,
+            index: 1,
+            time: 0,
+            is_coinbase: false,
+            received: vec![coin("change", 6)],
+            spent: vec![coin("mine", 10)],
+            paid: vec![coin("bob", 3), coin("bob", 1)],
+            senders: Vec::new(),
+            fee: Some(0),
+        };
+        assert_eq!(wtx.direction(), Direction::Sent);
+        assert_eq!(wtx.amount(), -4);
+        assert_eq!(wtx.counterparties(), vec!["bob".to_string()]);
+        assert!(wtx.involves("mine") && wtx.involves("change") && !wtx.involves("bob"));
+        assert_eq!(wtx.confirmations(3), 1);
+
+        wtx.paid.clear();
+        assert_eq!(wtx.direction(), Direction::SelfTransfer);
+        assert_eq!(wtx.amount(), 0);
+        wtx.spent.clear();
+        wtx.senders = vec!["alice".to_string()];
+        assert_eq!(wtx.direction(), Direction::Received);
+        assert_eq!(wtx.amount(), 6);
+        assert_eq!(wtx.counterparties(), vec!["alice".to_string()]);
+        wtx.is_coinbase = true;
+        assert_eq!(wtx.direction(), Direction::Generated);
+        assert!(wtx.counterparties().is_empty());
+    }
+
+    #[test]
+    fn test_change_address() {
+        let mut chain = TestChain::new();
+        let miner = chain.miner.clone();
+        let to = chain.address();
+        let tx = chain.send(&miner, &to, 3);
+        let change: Vec<String> =
+            chain.wallets.get_all_addresses().into_iter().filter(|a| chain.wallets.is_change(a)).collect();
+        assert_eq!(change.len(), 1);
+        let change = &change[0];
+        assert_ne!(change, &miner);
+        assert_eq!(tx.vout.len(), 2);
+        assert!(tx.vout[0].is_locked_with_key(&pub_key_hash(&to)));
+        assert!(tx.vout[1].is_locked_with_key(&pub_key_hash(change)));
+        assert_eq!(tx.vout[1].value, SUBSIDY - 3);
+
+        chain.mine(vec![tx]);
+        assert_eq!(chain.balance(change), SUBSIDY - 3);
+        assert_eq!(chain.balance(&to), 3);
+        assert_eq!(chain.balance(&miner), SUBSIDY);
+
+        // listaddresses只在--change时列出找零地址
+        let shown = chain.wallets.get_addresses(false);
+        assert!(shown.contains(&miner) && shown.contains(&to) && !shown.contains(change));
+        assert!(chain.wallets.get_addresses(true).contains(change));
+
+        // 没有HD种子就派生不了找零地址
+        let mut ws = Wallets::new(&Storage::temporary().unwrap()).unwrap();
+        let err = ws.create_change_address(SignatureScheme::Ed25519).unwrap_err().to_string();
+        assert!(err.contains("no HD seed"), "{}", err);
+    }
+
+    #[test]
+    fn test_restore_wallet() {
+        let store = Storage::temporary().unwrap();
+        let mut ws = Wallets::new(&store).unwrap();
+        let mnemonic = ws.create_seed().unwrap();
+        assert!(ws.create_seed().is_err());
+        let first: Vec<String> = (0..3)
+            .map(|_| ws.create_wallet(SignatureScheme::Ed25519).unwrap())
+            .collect();
+        let change = ws.create_change_address(SignatureScheme::SchnorrSecp256k1).unwrap();
+        ws.save_all().unwrap();
+        assert!(ws.is_change(&change));
+        assert!(!ws.is_change(&first[0]));
+        assert!(!store.wallets.get(SEED_KEY).unwrap().unwrap().is_empty());
+
+        let pub_key_hash = |address: &str| {
+            let mut hash = ws.get_wallet(address).unwrap().public_key;
+            hash_pub_key(&mut hash);
+            hash
+        };
+        // 中间的地址没用过也要恢复出来
+        let used: HashSet<Vec<u8>> = [pub_key_hash(&first[2]), pub_key_hash(&change)].into();
+
+        let other = Storage::temporary().unwrap();
+        let mut restored = Wallets::new(&other).unwrap();
+        let mut addresses = restored.restore(&mnemonic, &used).unwrap();
+        addresses.sort();
+        let mut expected = vec![first[0].clone(), first[1].clone(), first[2].clone(), change.clone()];
+        expected.sort();
+        assert_eq!(addresses, expected);
+        assert_eq!(
+            restored.get_wallet(&first[1]).unwrap(),
+            ws.get_wallet(&first[1]).unwrap()
+        );
+        let next = restored.create_wallet(SignatureScheme::Ed25519).unwrap();
+        assert_eq!(next, ws.create_wallet(SignatureScheme::Ed25519).unwrap());
+        assert!(restored.restore(&mnemonic, &used).is_err());
+    }
+}